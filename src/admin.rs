@@ -0,0 +1,171 @@
+//! Operator commands, send as `{"type": "admin", "token": "<ADMIN_TOKEN>", "request": {"action": ...}}`
+
+use crate::fault::FaultSettings;
+use crate::store::SessionSnapshot;
+use crate::{DemoInfo, EncryptionMode, Marker};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// Get the flight recorder contents, for one session or all of them
+    DumpRecorder { session: Option<String> },
+    /// Get the state of a session, the owner token is only included if requested
+    ExportSession {
+        session: String,
+        #[serde(default)]
+        include_token: bool,
+    },
+    /// Re-create an exported session, ready for the owner to reclaim it
+    ImportSession { session: Box<ExportedSession> },
+    /// Get the sessions and peers that waited the longest for their locks
+    LockContention {
+        #[serde(default = "default_contention_limit")]
+        limit: usize,
+    },
+    /// Write all logs for a session to a separate file, for `duration` seconds, or stop doing so
+    CaptureLogs {
+        session: String,
+        enabled: bool,
+        #[serde(default = "default_capture_duration")]
+        duration: u64,
+    },
+    /// Get the names of all archived sessions
+    ListArchive,
+    /// Get the final state of an archived session
+    GetArchive { session: String },
+    /// Get the statistics of a running session
+    SessionStats { session: String },
+    /// Change the injected faults for a peer, or the defaults if no peer is given
+    SetFaults {
+        peer: Option<String>,
+        faults: FaultSettings,
+    },
+    /// Repeat the owner commands of the source session in the target session, or stop doing so
+    Mirror {
+        source: String,
+        target: String,
+        enabled: bool,
+    },
+    /// Stop injecting faults for a peer, or disable the default faults if no peer is given
+    ClearFaults { peer: Option<String> },
+}
+
+fn default_contention_limit() -> usize {
+    10
+}
+
+fn default_capture_duration() -> u64 {
+    600
+}
+
+/// The state of a session moved between servers
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExportedSession {
+    pub name: String,
+    /// A new token is generated on import if the token wasn't exported
+    pub owner_token: Option<String>,
+    pub tick: u64,
+    pub playing: bool,
+    pub scheduled_start: Option<u64>,
+    #[serde(default)]
+    pub last_seek: Option<u64>,
+    #[serde(default)]
+    pub public: bool,
+    /// Whether ticks that go backwards are ignored
+    #[serde(default)]
+    pub monotonic: bool,
+    /// Whether the next demo of the queue is played once the current demo ended
+    #[serde(default)]
+    pub auto_advance: bool,
+    /// Seconds the session is kept after the owner left, instead of the configured timeout
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Maximum number of clients chosen by the owner
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    /// Whether the owner commands are logged
+    #[serde(default)]
+    pub log: bool,
+    /// The entity of the player the owner is spectating
+    #[serde(default)]
+    pub pov: Option<u32>,
+    /// The first and last tick of the region that is repeated
+    #[serde(default)]
+    pub loop_region: Option<(u64, u64)>,
+    /// Whether the owner enabled the voice relay
+    #[serde(default)]
+    pub voice: bool,
+    /// Which messages are encrypted
+    #[serde(default)]
+    pub encryption: EncryptionMode,
+    #[serde(default)]
+    pub demo: Option<DemoInfo>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub markers: Vec<Marker>,
+    /// Demos to play after the current one
+    #[serde(default)]
+    pub queue: Vec<DemoInfo>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+impl ExportedSession {
+    pub fn new(snapshot: SessionSnapshot, include_token: bool) -> Self {
+        ExportedSession {
+            name: snapshot.name,
+            owner_token: include_token.then_some(snapshot.owner_token),
+            tick: snapshot.tick,
+            playing: snapshot.playing,
+            scheduled_start: snapshot.scheduled_start,
+            last_seek: snapshot.last_seek,
+            public: snapshot.public,
+            monotonic: snapshot.monotonic,
+            auto_advance: snapshot.auto_advance,
+            timeout: snapshot.timeout,
+            max_clients: snapshot.max_clients,
+            log: snapshot.log,
+            pov: snapshot.pov,
+            loop_region: snapshot.loop_region,
+            voice: snapshot.voice,
+            encryption: snapshot.encryption,
+            demo: snapshot.demo,
+            password: snapshot.password,
+            markers: snapshot.markers,
+            queue: snapshot.queue,
+            title: snapshot.title,
+            description: snapshot.description,
+        }
+    }
+
+    pub fn into_snapshot(self, owner_token: String) -> SessionSnapshot {
+        SessionSnapshot {
+            name: self.name,
+            owner_token,
+            tick: self.tick,
+            playing: self.playing,
+            scheduled_start: self.scheduled_start,
+            last_seek: self.last_seek,
+            public: self.public,
+            monotonic: self.monotonic,
+            auto_advance: self.auto_advance,
+            timeout: self.timeout,
+            max_clients: self.max_clients,
+            log: self.log,
+            pov: self.pov,
+            loop_region: self.loop_region,
+            voice: self.voice,
+            encryption: self.encryption,
+            demo: self.demo,
+            password: self.password,
+            markers: self.markers,
+            queue: self.queue,
+            title: self.title,
+            description: self.description,
+        }
+    }
+}
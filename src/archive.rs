@@ -0,0 +1,66 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// The final state of a removed session
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ArchivedSession {
+    pub name: String,
+    pub tick: u64,
+    pub playing: bool,
+    /// Unix timestamps in milliseconds
+    pub created_at: u64,
+    pub removed_at: u64,
+    /// Number of times a client joined the session
+    pub joins: u64,
+    /// Highest number of clients connected at the same time
+    pub peak_clients: usize,
+    /// Number of messages send to the clients of the session
+    #[serde(default)]
+    pub broadcasts: u64,
+    pub reactions: u64,
+}
+
+/// Removed sessions, kept in memory for a while for support and statistics
+pub struct Archive {
+    retention: Duration,
+    sessions: DashMap<String, ArchivedSession>,
+}
+
+impl Archive {
+    /// Keep sessions for `retention`, zero disables the archive
+    pub fn new(retention: Duration) -> Self {
+        Archive {
+            retention,
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub fn insert(&self, session: ArchivedSession) {
+        if !self.retention.is_zero() {
+            self.sessions.insert(session.name.clone(), session);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<ArchivedSession> {
+        self.sessions.get(name).map(|session| session.clone())
+    }
+
+    /// Names of all archived sessions
+    pub fn list(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .sessions
+            .iter()
+            .map(|session| session.key().clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Permanently delete the sessions archived longer than the retention period, `now` in unix milliseconds
+    pub fn expire(&self, now: u64) {
+        let retention = self.retention.as_millis() as u64;
+        self.sessions
+            .retain(|_, session| session.removed_at + retention > now);
+    }
+}
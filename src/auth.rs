@@ -0,0 +1,130 @@
+use crate::peer::query_param;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::http::HeaderMap;
+
+/// The parts of the websocket handshake available for authentication
+pub struct Handshake<'a> {
+    pub headers: &'a HeaderMap,
+    pub query: Option<&'a str>,
+}
+
+impl Handshake<'_> {
+    /// Get the credential send by the client, either as bearer token or `key` query parameter
+    pub fn credential(&self) -> Option<&str> {
+        self.headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| query_param(self.query, "key"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    Anonymous,
+    User(String),
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identity::Anonymous => write!(f, "anonymous"),
+            Identity::User(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    MissingCredentials,
+    InvalidCredentials,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingCredentials => write!(f, "authentication required"),
+            AuthError::InvalidCredentials => write!(f, "invalid credentials"),
+        }
+    }
+}
+
+/// Decides who can connect to the server and who can create sessions
+pub trait AuthProvider: Send + Sync {
+    /// Authenticate a connection during the websocket handshake
+    fn authenticate(&self, handshake: &Handshake) -> Result<Identity, AuthError>;
+
+    /// Check if an authenticated peer is allowed to create (or take ownership of) a session
+    fn authorize_create(&self, _identity: &Identity, _session: &str) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+/// Allow everyone
+pub struct NoAuth;
+
+impl AuthProvider for NoAuth {
+    fn authenticate(&self, _handshake: &Handshake) -> Result<Identity, AuthError> {
+        Ok(Identity::Anonymous)
+    }
+}
+
+/// Authenticate using a fixed set of keys, optionally allowing anonymous viewers
+pub struct StaticKeyAuth {
+    /// Map of key to user name
+    keys: HashMap<String, String>,
+    allow_anonymous: bool,
+}
+
+impl StaticKeyAuth {
+    pub fn new(keys: HashMap<String, String>, allow_anonymous: bool) -> Self {
+        StaticKeyAuth {
+            keys,
+            allow_anonymous,
+        }
+    }
+}
+
+impl AuthProvider for StaticKeyAuth {
+    fn authenticate(&self, handshake: &Handshake) -> Result<Identity, AuthError> {
+        match handshake.credential() {
+            Some(key) => match self.keys.get(key) {
+                Some(name) => Ok(Identity::User(name.clone())),
+                None => Err(AuthError::InvalidCredentials),
+            },
+            None if self.allow_anonymous => Ok(Identity::Anonymous),
+            None => Err(AuthError::MissingCredentials),
+        }
+    }
+
+    fn authorize_create(&self, identity: &Identity, _session: &str) -> Result<(), AuthError> {
+        match identity {
+            Identity::User(_) => Ok(()),
+            Identity::Anonymous => Err(AuthError::MissingCredentials),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum AuthConfig {
+    #[default]
+    None,
+    Static {
+        keys: HashMap<String, String>,
+        allow_anonymous: bool,
+    },
+}
+
+impl AuthConfig {
+    pub fn provider(&self) -> Box<dyn AuthProvider> {
+        match self {
+            AuthConfig::None => Box::new(NoAuth),
+            AuthConfig::Static {
+                keys,
+                allow_anonymous,
+            } => Box::new(StaticKeyAuth::new(keys.clone(), *allow_anonymous)),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+//! Track frames dropped because a peer doesn't read its messages fast enough
+
+use dashmap::DashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Time over which the drop rate of a session is averaged, at most one alert is raised per window
+pub const DROP_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct DropWindow {
+    start: Instant,
+    dropped: u64,
+    alerted: bool,
+}
+
+/// Dropped frames per session
+pub struct DropTracker {
+    /// Dropped frames per second after which an alert is raised, zero disables alerts
+    alert_rate: f64,
+    sessions: DashMap<String, DropWindow>,
+}
+
+impl DropTracker {
+    pub fn new(alert_rate: f64) -> Self {
+        DropTracker {
+            alert_rate,
+            sessions: DashMap::new(),
+        }
+    }
+
+    /// Record a dropped frame, returns the number of frames dropped in the current window
+    /// if the drop rate crossed the threshold and no alert was raised for this window yet
+    pub fn record(&self, session: &str, now: Instant) -> Option<u64> {
+        if self.alert_rate <= 0.0 {
+            return None;
+        }
+        let mut window = self
+            .sessions
+            .entry(session.to_string())
+            .or_insert(DropWindow {
+                start: now,
+                dropped: 0,
+                alerted: false,
+            });
+        if now.duration_since(window.start) > DROP_WINDOW {
+            *window = DropWindow {
+                start: now,
+                dropped: 0,
+                alerted: false,
+            };
+        }
+        window.dropped += 1;
+
+        let threshold = self.alert_rate * DROP_WINDOW.as_secs_f64();
+        if !window.alerted && window.dropped as f64 >= threshold {
+            window.alerted = true;
+            Some(window.dropped)
+        } else {
+            None
+        }
+    }
+
+    pub fn remove(&self, session: &str) {
+        self.sessions.remove(session);
+    }
+}
@@ -0,0 +1,102 @@
+//! Batched messages, a json array of commands in a single text frame
+//!
+//! While the commands of a batch are handled, broadcasts to clients that negotiated the `batch` capability
+//! are collected and send as a single array per client once the whole batch is handled.
+
+use crate::egress::Priority;
+use crate::peer::PeerId;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Maximum number of commands in a single batch
+pub const MAX_BATCH_SIZE: usize = 32;
+
+#[derive(Default)]
+struct Pending {
+    /// Messages grouped by recipient and session, in the order the recipients were first seen
+    order: Vec<(PeerId, String)>,
+    messages: HashMap<(PeerId, String), (Priority, Vec<String>)>,
+}
+
+thread_local! {
+    // batches are handled synchronously, so they never move between threads
+    static PENDING: RefCell<Option<Pending>> = const { RefCell::new(None) };
+}
+
+/// Whether a text frame contains a batch instead of a single command
+pub fn is_batch(message: &str) -> bool {
+    message.trim_start().starts_with('[')
+}
+
+/// Whether broadcasts are currently being collected
+pub fn collecting() -> bool {
+    PENDING.with(|pending| pending.borrow().is_some())
+}
+
+/// Start collecting broadcasts until the returned guard is finished or dropped
+pub fn collect() -> Collecting {
+    PENDING.with(|pending| *pending.borrow_mut() = Some(Pending::default()));
+    Collecting
+}
+
+/// Add a broadcast to the batch send to a client, the batch is send with the highest priority of its messages
+pub fn defer(peer: &PeerId, session: &str, priority: Priority, text: &str) {
+    PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let Some(pending) = pending.as_mut() else {
+            return;
+        };
+        let key = (*peer, session.to_string());
+        let (batch_priority, messages) = pending.messages.entry(key.clone()).or_insert_with(|| {
+            pending.order.push(key);
+            (priority, Vec::new())
+        });
+        *batch_priority = (*batch_priority).max(priority);
+        messages.push(text.to_string());
+    })
+}
+
+pub struct Collecting;
+
+/// A frame containing the broadcasts of a batch for a single client
+pub struct BatchedFrame {
+    pub peer: PeerId,
+    pub session: String,
+    pub priority: Priority,
+    pub text: String,
+}
+
+impl Collecting {
+    /// Stop collecting and join the collected broadcasts into a frame per client
+    pub fn finish(self) -> Vec<BatchedFrame> {
+        let Some(mut pending) = PENDING.with(|pending| pending.borrow_mut().take()) else {
+            return Vec::new();
+        };
+        pending
+            .order
+            .into_iter()
+            .filter_map(|key| {
+                let (priority, messages) = pending.messages.remove(&key)?;
+                let (peer, session) = key;
+                // a single message is send as is
+                let text = match <[String; 1]>::try_from(messages) {
+                    Ok([message]) => message,
+                    Err(messages) => format!("[{}]", messages.join(",")),
+                };
+                Some(BatchedFrame {
+                    peer,
+                    session,
+                    priority,
+                    text,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Drop for Collecting {
+    fn drop(&mut self) {
+        // don't leave the thread collecting when handling the batch panicked
+        PENDING.with(|pending| pending.borrow_mut().take());
+    }
+}
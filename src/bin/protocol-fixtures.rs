@@ -0,0 +1,37 @@
+//! Check the json fixtures of the wire protocol, or rewrite them after an intentional protocol change
+//!
+//! usage: protocol-fixtures [check|update] [fixture dir]
+
+use main_error::MainResult;
+use std::path::PathBuf;
+use sync::fixtures::{check, update};
+
+fn main() -> MainResult {
+    let mut args = std::env::args().skip(1);
+    let mode = args.next().unwrap_or_else(|| "check".into());
+    let dir = PathBuf::from(args.next().unwrap_or_else(|| "protocol".into()));
+
+    match mode.as_str() {
+        "check" => {
+            let mismatches = check(&dir)?;
+            for mismatch in &mismatches {
+                eprintln!("{mismatch}");
+            }
+            if !mismatches.is_empty() {
+                return Err(format!(
+                    "{} fixtures don't match, run `protocol-fixtures update` if the protocol change is intentional",
+                    mismatches.len()
+                )
+                .into());
+            }
+            println!("all fixtures match");
+        }
+        "update" => {
+            for path in update(&dir)? {
+                println!("updated {}", path.display());
+            }
+        }
+        _ => return Err("usage: protocol-fixtures [check|update] [fixture dir]".into()),
+    }
+    Ok(())
+}
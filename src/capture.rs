@@ -0,0 +1,205 @@
+//! Write the debug logs of single sessions to separate files, without enabling debug logging globally
+//!
+//! The [`CaptureLayer`] has to be added to the tracing subscriber and the [`LogCapture`] passed to the
+//! [`ServerBuilder`](crate::ServerBuilder) so captures can be started with an admin command. The layer is filtered
+//! by a [`CaptureFilter`] that only lets spans and events through while a capture is running.
+
+use crate::unix_millis;
+use std::collections::HashMap;
+use std::fmt::{Debug, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::Attributes;
+use tracing::subscriber::Interest;
+use tracing::{Event, Id, Metadata, Subscriber};
+use tracing_subscriber::filter::Filtered;
+use tracing_subscriber::layer::{Context, Filter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+struct Capture {
+    file: Mutex<File>,
+    until: Instant,
+}
+
+/// The sessions for which logs are currently captured
+#[derive(Default)]
+pub struct LogCapture {
+    active: AtomicBool,
+    sessions: RwLock<HashMap<String, Capture>>,
+}
+
+impl LogCapture {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    pub fn layer<S: Subscriber + for<'a> LookupSpan<'a>>(
+        self: &Arc<Self>,
+    ) -> Filtered<CaptureLayer, CaptureFilter, S> {
+        CaptureLayer {
+            capture: self.clone(),
+        }
+        .with_filter(CaptureFilter {
+            capture: self.clone(),
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Append all log events for the session to the file at `path` until `until`
+    pub fn start(&self, session: &str, path: &Path, until: Instant) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.insert(
+            session.to_string(),
+            Capture {
+                file: Mutex::new(file),
+                until,
+            },
+        );
+        self.active.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stop capturing logs for a session, returns whether logs were being captured
+    pub fn stop(&self, session: &str) -> bool {
+        let mut sessions = self.sessions.write().unwrap();
+        let stopped = sessions.remove(session).is_some();
+        self.active.store(!sessions.is_empty(), Ordering::Relaxed);
+        stopped
+    }
+
+    /// Stop the captures that ran out
+    pub fn expire(&self, now: Instant) {
+        if !self.is_active() {
+            return;
+        }
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, capture| capture.until > now);
+        self.active.store(!sessions.is_empty(), Ordering::Relaxed);
+    }
+
+    fn write(&self, session: &str, line: &str) {
+        let sessions = self.sessions.read().unwrap();
+        if let Some(capture) = sessions.get(session) {
+            // logging can't fail, the capture is best effort
+            let _ = capture.file.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+}
+
+/// The session of a span and its formatted fields, stored in the span extensions
+struct SpanFields {
+    session: Option<String>,
+    fields: String,
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    session: Option<String>,
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "session" {
+            self.session = Some(value.to_string());
+        }
+        self.record_debug(field, &value)
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "session" && self.session.is_none() {
+            self.session = Some(format!("{value:?}"));
+        }
+        if field.name() == "message" && self.message.is_empty() {
+            let _ = write!(self.message, "{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
+
+/// Only enables spans and events for the [`CaptureLayer`] while a capture is running
+pub struct CaptureFilter {
+    capture: Arc<LogCapture>,
+}
+
+impl<S> Filter<S> for CaptureFilter {
+    fn enabled(&self, _metadata: &Metadata<'_>, _ctx: &Context<'_, S>) -> bool {
+        self.capture.is_active()
+    }
+
+    fn callsite_enabled(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // captures are started and stopped at runtime, so the interest can't be cached
+        Interest::sometimes()
+    }
+}
+
+/// Tracing layer that writes the events of captured sessions, at any level, to their capture files
+///
+/// Events belong to a session if they, or one of their spans, have a `session` field. Spans that were created before
+/// the capture started aren't known to the layer
+pub struct CaptureLayer {
+    capture: Arc<LogCapture>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for CaptureLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.capture.is_active() {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanFields {
+            session: visitor.session,
+            fields: visitor.fields,
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !self.capture.is_active() {
+            return;
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let mut span_session = None;
+        let mut span_fields = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    if fields.session.is_some() {
+                        span_session.clone_from(&fields.session);
+                    }
+                    span_fields.push_str(&fields.fields);
+                }
+            }
+        }
+        let Some(session) = visitor.session.or(span_session) else {
+            return;
+        };
+
+        let metadata = event.metadata();
+        let line = format!(
+            "{} {} {}: {}{span_fields}{}\n",
+            unix_millis(),
+            metadata.level(),
+            metadata.target(),
+            visitor.message,
+            visitor.fields
+        );
+        self.capture.write(&session, &line);
+    }
+}
@@ -0,0 +1,765 @@
+use crate::auth::AuthConfig;
+use crate::fault::{FaultConfig, FaultSettings};
+use crate::listener::{parse_proxy, ListenAddress, ListenerConfig, TcpOptions};
+use crate::peer::PING_INTERVAL;
+use crate::ratelimit::{CreatePolicy, FloodPolicy, RateLimit, RateLimits};
+use crate::store::{StoreConfig, StoreKeys};
+use crate::telemetry::MetricsConfig;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    /// Time without a presence ping after which a client is reported as idle
+    pub presence_timeout: Duration,
+    /// Time a session is kept after the owner left
+    pub session_timeout: Duration,
+    /// Time without messages or answered pings after which a peer is disconnected, zero disables the timeout
+    pub idle_timeout: Duration,
+    /// Longest timeout an owner can request for a session
+    pub max_session_timeout: Duration,
+    pub rate_limits: RateLimits,
+    pub flood_policy: FloodPolicy,
+    /// Limit on the number of sessions created from a single ip
+    pub create_policy: CreatePolicy,
+    /// Time the previous owner has to contest a session takeover, zero disables contesting
+    pub takeover_grace: Duration,
+    /// Time the old owner token stays valid after a rotation
+    pub token_rotation_grace: Duration,
+    pub auth: AuthConfig,
+    pub store: StoreConfig,
+    /// Keys to encrypt the owner tokens and passwords of stored sessions, required for stores that write to disk
+    pub store_keys: Option<StoreKeys>,
+    pub plugins: PluginConfig,
+    pub metrics: MetricsConfig,
+    /// Maximum size of the payload of extension messages in bytes
+    pub ext_max_payload: usize,
+    /// Whether clients can send extension messages, instead of only the owner
+    pub ext_from_clients: bool,
+    /// Maximum payload size of a relayed binary frame
+    pub relay_max_frame: usize,
+    /// Bytes per second that can be relayed in a session
+    pub relay_rate: RateLimit,
+    /// Bytes per second that can be send over the voice relay in a session
+    pub voice_rate: RateLimit,
+    /// Bytes per second that can be send to a single peer, unlimited if not set
+    pub egress_peer_rate: Option<RateLimit>,
+    /// Bytes per second that can be send to all members of a session together, unlimited if not set
+    pub egress_session_rate: Option<RateLimit>,
+    /// Number of messages kept in the flight recorder per session, zero disables recording
+    pub recorder_size: usize,
+    /// Directory the flight recorder is written to on SIGUSR1
+    pub recorder_dir: PathBuf,
+    /// Token for admin commands, admin commands are disabled without one
+    pub admin_token: Option<String>,
+    /// Inject faults into connections, for testing clients, disabled when `None`
+    pub faults: Option<FaultConfig>,
+    /// Dropped messages per second in a session after which a warning is raised, zero disables warnings
+    pub drop_alert_rate: f64,
+    /// Create sessions that don't exist when they are joined, with the joining peer as owner
+    pub auto_create: bool,
+    /// Maximum number of sessions created by a single user or ip, zero for no limit
+    pub max_owner_sessions: usize,
+    /// Maximum number of clients in a single session, zero for no limit
+    pub max_session_clients: usize,
+    /// Number of owner commands kept in the log of a session that enabled it, zero disables the log
+    pub max_command_log: usize,
+    /// Remove the longest abandoned session of an owner at the limit instead of refusing new sessions
+    pub evict_idle_sessions: bool,
+    /// Time during which joins for the old name of a renamed session are redirected to the new name
+    pub rename_redirect: Duration,
+    /// Time removed sessions are kept in the archive, zero disables the archive
+    pub archive_retention: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct PluginConfig {
+    /// Directory to load wasm plugins from, requires the `plugins` feature
+    pub dir: Option<PathBuf>,
+    /// Fuel available to a plugin for handling a single event
+    pub fuel: u64,
+    /// Maximum memory size of a plugin in bytes
+    pub memory_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listeners: vec![ListenerConfig::new(
+                "default",
+                ListenAddress::Tcp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 80))),
+            )],
+            presence_timeout: Duration::from_secs(60),
+            session_timeout: Duration::from_secs(15 * 60),
+            idle_timeout: Duration::ZERO,
+            max_session_timeout: Duration::from_secs(24 * 60 * 60),
+            rate_limits: RateLimits::default(),
+            flood_policy: FloodPolicy::default(),
+            create_policy: CreatePolicy::default(),
+            takeover_grace: Duration::from_secs(30),
+            token_rotation_grace: Duration::from_secs(60),
+            auth: AuthConfig::None,
+            store: StoreConfig::Memory,
+            store_keys: None,
+            plugins: PluginConfig {
+                dir: None,
+                fuel: 1_000_000,
+                memory_limit: 16 * 1024 * 1024,
+            },
+            metrics: MetricsConfig::None,
+            ext_max_payload: 4096,
+            ext_from_clients: false,
+            relay_max_frame: 64 * 1024,
+            relay_rate: RateLimit::new(256.0 * 1024.0, 1024.0 * 1024.0),
+            voice_rate: RateLimit::new(8.0 * 1024.0, 16.0 * 1024.0),
+            egress_peer_rate: None,
+            egress_session_rate: None,
+            recorder_size: 256,
+            recorder_dir: std::env::temp_dir(),
+            admin_token: None,
+            faults: None,
+            drop_alert_rate: 1.0,
+            auto_create: false,
+            max_owner_sessions: 0,
+            max_session_clients: 0,
+            max_command_log: 10_000,
+            evict_idle_sessions: false,
+            rename_redirect: Duration::from_secs(300),
+            archive_retention: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from the environment, reporting all invalid settings at once
+    pub fn from_env() -> Result<Self, ValidationError> {
+        let mut errors = ConfigErrors::default();
+        let defaults = Config::default();
+        let flood = defaults.flood_policy;
+        let create = defaults.create_policy;
+        let mut rate_limits = defaults.rate_limits;
+        for (kind, limit) in errors.check(rate_limits_from_env("RATE_LIMIT_")) {
+            rate_limits.set(&kind, limit);
+        }
+        let config = Config {
+            listeners: errors.check(listeners_from_env(&rate_limits)),
+            presence_timeout: errors
+                .check(env_duration("PRESENCE_TIMEOUT", defaults.presence_timeout)),
+            session_timeout: errors
+                .check(env_duration("SESSION_TIMEOUT", defaults.session_timeout)),
+            idle_timeout: errors.check(env_duration("IDLE_TIMEOUT", defaults.idle_timeout)),
+            max_session_timeout: errors.check(env_duration(
+                "MAX_SESSION_TIMEOUT",
+                defaults.max_session_timeout,
+            )),
+            rate_limits,
+            flood_policy: FloodPolicy {
+                max_violations: errors
+                    .check(env_parse("FLOOD_MAX_VIOLATIONS", flood.max_violations)),
+                max_decode_errors: errors.check(env_parse(
+                    "FLOOD_MAX_DECODE_ERRORS",
+                    flood.max_decode_errors,
+                )),
+                window: errors.check(env_duration("FLOOD_WINDOW", flood.window)),
+                penalty: errors.check(env_duration("FLOOD_PENALTY", flood.penalty)),
+            },
+            create_policy: CreatePolicy {
+                max_sessions: errors.check(env_parse("IP_CREATE_LIMIT", create.max_sessions)),
+                window: errors.check(env_duration("IP_CREATE_WINDOW", create.window)),
+                cooldown: errors.check(env_duration("IP_CREATE_COOLDOWN", create.cooldown)),
+            },
+            takeover_grace: errors.check(env_duration("TAKEOVER_GRACE", defaults.takeover_grace)),
+            token_rotation_grace: errors.check(env_duration(
+                "TOKEN_ROTATION_GRACE",
+                defaults.token_rotation_grace,
+            )),
+            auth: errors.check(auth_from_env()),
+            store: errors.check(store_from_env()),
+            store_keys: errors.check(env_optional("STORE_KEYS", defaults.store_keys)),
+            plugins: PluginConfig {
+                dir: std::env::var("PLUGIN_DIR").ok().map(PathBuf::from),
+                fuel: errors.check(env_parse("PLUGIN_FUEL", defaults.plugins.fuel)),
+                memory_limit: errors
+                    .check(env_parse("PLUGIN_MEMORY", defaults.plugins.memory_limit)),
+            },
+            metrics: errors.check(metrics_from_env()),
+            ext_max_payload: errors.check(env_parse("EXT_MAX_PAYLOAD", defaults.ext_max_payload)),
+            ext_from_clients: errors
+                .check(env_parse("EXT_FROM_CLIENTS", defaults.ext_from_clients)),
+            relay_max_frame: errors.check(env_parse("RELAY_MAX_FRAME", defaults.relay_max_frame)),
+            relay_rate: errors.check_or(
+                env_parse("RELAY_RATE", defaults.relay_rate),
+                defaults.relay_rate,
+            ),
+            voice_rate: errors.check_or(
+                env_parse("VOICE_RATE", defaults.voice_rate),
+                defaults.voice_rate,
+            ),
+            egress_peer_rate: errors
+                .check(env_optional("EGRESS_PEER_RATE", defaults.egress_peer_rate)),
+            egress_session_rate: errors.check(env_optional(
+                "EGRESS_SESSION_RATE",
+                defaults.egress_session_rate,
+            )),
+            recorder_size: errors.check(env_parse("RECORDER_SIZE", defaults.recorder_size)),
+            recorder_dir: errors.check(env_parse("RECORDER_DIR", defaults.recorder_dir)),
+            admin_token: std::env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            faults: errors.check(faults_from_env()),
+            drop_alert_rate: errors.check(env_parse("DROP_ALERT_RATE", defaults.drop_alert_rate)),
+            auto_create: errors.check(env_parse("AUTO_CREATE", defaults.auto_create)),
+            max_owner_sessions: errors
+                .check(env_parse("MAX_OWNER_SESSIONS", defaults.max_owner_sessions)),
+            max_session_clients: errors.check(env_parse(
+                "MAX_SESSION_CLIENTS",
+                defaults.max_session_clients,
+            )),
+            max_command_log: errors.check(env_parse("MAX_COMMAND_LOG", defaults.max_command_log)),
+            evict_idle_sessions: errors.check(env_parse(
+                "EVICT_IDLE_SESSIONS",
+                defaults.evict_idle_sessions,
+            )),
+            rename_redirect: errors
+                .check(env_duration("RENAME_REDIRECT", defaults.rename_redirect)),
+            archive_retention: errors.check(env_duration(
+                "ARCHIVE_RETENTION",
+                defaults.archive_retention,
+            )),
+        };
+        errors.into_result(config)
+    }
+}
+
+impl Config {
+    /// Check the configuration for problems that would only show up after starting, like
+    /// overlapping listeners or missing directories, reporting all problems at once
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut problems = Vec::new();
+
+        if self.listeners.is_empty() {
+            problems.push("no listeners configured, LISTENERS is empty".to_string());
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            for other in &self.listeners[..i] {
+                if listener.name.eq_ignore_ascii_case(&other.name) {
+                    problems.push(format!("listener {:?} is configured twice", listener.name));
+                } else if listener.address.overlaps(&other.address) {
+                    problems.push(format!(
+                        "listeners {:?} ({}) and {:?} ({}) overlap",
+                        other.name, other.address, listener.name, listener.address
+                    ));
+                }
+            }
+            #[cfg(unix)]
+            if let ListenAddress::Unix(path) = &listener.address {
+                let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+                if let Some(problem) = check_dir(dir.unwrap_or(".".as_ref())) {
+                    problems.push(format!(
+                        "socket of listener {:?} can't be created: {problem}",
+                        listener.name
+                    ));
+                }
+            }
+            let tcp = &listener.tcp;
+            if tcp.keepalive.is_none()
+                && (tcp.keepalive_interval.is_some() || tcp.keepalive_retries.is_some())
+            {
+                problems.push(format!(
+                    "keepalive interval and retries of listener {:?} have no effect without TCP_KEEPALIVE",
+                    listener.name
+                ));
+            }
+        }
+
+        if self.session_timeout > self.max_session_timeout {
+            problems.push("SESSION_TIMEOUT is longer than MAX_SESSION_TIMEOUT".to_string());
+        }
+        if !self.idle_timeout.is_zero() && self.idle_timeout <= PING_INTERVAL {
+            problems.push(format!(
+                "IDLE_TIMEOUT has to be longer than the ping interval of {}s",
+                PING_INTERVAL.as_secs()
+            ));
+        }
+        if self.recorder_size > 0 {
+            if let Some(problem) = check_dir(&self.recorder_dir) {
+                problems.push(format!("RECORDER_DIR: {problem}"));
+            }
+        }
+        match &self.store {
+            StoreConfig::Memory => {}
+            StoreConfig::File(dir) => {
+                if dir.exists() && !dir.is_dir() {
+                    problems.push(format!("STORE_PATH: {} is not a directory", dir.display()));
+                }
+            }
+            #[cfg(feature = "sqlite")]
+            StoreConfig::Sqlite(path) => {
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    if let Some(problem) = check_dir(dir) {
+                        problems.push(format!("STORE_PATH: {problem}"));
+                    }
+                }
+            }
+            #[cfg(feature = "redis")]
+            StoreConfig::Redis(url) => {
+                if let Err(error) = redis::Client::open(url.as_str()) {
+                    problems.push(format!("STORE_PATH: invalid redis url: {error}"));
+                }
+            }
+        }
+        if let Some(dir) = &self.plugins.dir {
+            if !dir.is_dir() {
+                problems.push(format!("PLUGIN_DIR: {} is not a directory", dir.display()));
+            }
+        }
+        if let Some(faults) = &self.faults {
+            let defaults = &faults.defaults;
+            for (key, rate) in [
+                ("FAULT_DROP_RATE", defaults.drop_rate),
+                ("FAULT_DISCONNECT_RATE", defaults.disconnect_rate),
+            ] {
+                if !(0.0..=1.0).contains(&rate) {
+                    problems.push(format!(
+                        "{key}: {rate} is not a probability between 0 and 1"
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { problems })
+        }
+    }
+}
+
+/// Check that files can be created in a directory
+fn check_dir(dir: &Path) -> Option<String> {
+    match std::fs::metadata(dir) {
+        Ok(metadata) if !metadata.is_dir() => Some(format!("{} is not a directory", dir.display())),
+        Ok(metadata) if metadata.permissions().readonly() => {
+            Some(format!("{} is not writable", dir.display()))
+        }
+        Ok(_) => None,
+        Err(error) => Some(format!("{}: {error}", dir.display())),
+    }
+}
+
+fn faults_from_env() -> Result<Option<FaultConfig>, ConfigError> {
+    if !env_parse("FAULT_INJECTION", false)? {
+        return Ok(None);
+    }
+    Ok(Some(FaultConfig {
+        defaults: FaultSettings {
+            latency: env_parse("FAULT_LATENCY", 0)?,
+            jitter: env_parse("FAULT_JITTER", 0)?,
+            drop_rate: env_parse("FAULT_DROP_RATE", 0.0)?,
+            disconnect_rate: env_parse("FAULT_DISCONNECT_RATE", 0.0)?,
+        },
+        seed: std::env::var("FAULT_SEED")
+            .ok()
+            .map(|seed| {
+                seed.parse().map_err(|_| ConfigError {
+                    key: "FAULT_SEED".into(),
+                    value: seed,
+                })
+            })
+            .transpose()?,
+    }))
+}
+
+fn metrics_from_env() -> Result<MetricsConfig, ConfigError> {
+    match std::env::var("METRICS").as_deref() {
+        Err(_) | Ok("none") => Ok(MetricsConfig::None),
+        #[cfg(feature = "prometheus")]
+        Ok("prometheus") => Ok(MetricsConfig::Prometheus(env_parse(
+            "METRICS_ADDRESS",
+            std::net::SocketAddr::from(([0, 0, 0, 0], 9000)),
+        )?)),
+        Ok(value) => Err(ConfigError {
+            key: "METRICS".into(),
+            value: value.into(),
+        }),
+    }
+}
+
+fn store_from_env() -> Result<StoreConfig, ConfigError> {
+    let path = || -> Result<PathBuf, ConfigError> {
+        match std::env::var("STORE_PATH") {
+            Ok(path) => Ok(path.into()),
+            Err(_) => Err(ConfigError {
+                key: "STORE_PATH".into(),
+                value: String::new(),
+            }),
+        }
+    };
+    match std::env::var("STORE").as_deref() {
+        Err(_) | Ok("memory") => Ok(StoreConfig::Memory),
+        Ok("file") => Ok(StoreConfig::File(path()?)),
+        #[cfg(feature = "sqlite")]
+        Ok("sqlite") => Ok(StoreConfig::Sqlite(path()?)),
+        #[cfg(feature = "redis")]
+        Ok("redis") => Ok(StoreConfig::Redis(path()?.to_string_lossy().into_owned())),
+        Ok(value) => Err(ConfigError {
+            key: "STORE".into(),
+            value: value.into(),
+        }),
+    }
+}
+
+fn auth_from_env() -> Result<AuthConfig, ConfigError> {
+    match std::env::var("AUTH").as_deref() {
+        Err(_) | Ok("none") => Ok(AuthConfig::None),
+        Ok("static") => {
+            let keys = std::env::var("AUTH_KEYS").unwrap_or_default();
+            let keys = keys
+                .split(',')
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| match entry.split_once(':') {
+                    Some((name, key)) => Ok((key.to_string(), name.to_string())),
+                    None => Err(ConfigError {
+                        key: "AUTH_KEYS".into(),
+                        value: entry.into(),
+                    }),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(AuthConfig::Static {
+                keys,
+                allow_anonymous: env_parse("AUTH_ANONYMOUS", true)?,
+            })
+        }
+        Ok(value) => Err(ConfigError {
+            key: "AUTH".into(),
+            value: value.into(),
+        }),
+    }
+}
+
+/// Rate limit overrides are set with `<prefix><COMMAND>=<rate>[:<burst>]`
+fn rate_limits_from_env(prefix: &str) -> Result<Vec<(String, RateLimit)>, ConfigError> {
+    let mut limits = Vec::new();
+    for (key, value) in std::env::vars() {
+        let Some(kind) = key.strip_prefix(prefix) else {
+            continue;
+        };
+        match value.parse() {
+            Ok(limit) => limits.push((kind.to_ascii_lowercase(), limit)),
+            Err(_) => return Err(ConfigError { key, value }),
+        }
+    }
+    Ok(limits)
+}
+
+/// Without `LISTENERS` a single listener is created on `PORT`, otherwise every listener in the list
+/// is configured with `LISTENER_<NAME>_*` variables
+fn listeners_from_env(rate_limits: &RateLimits) -> Result<Vec<ListenerConfig>, ConfigError> {
+    let tcp = tcp_options_from_env("", &TcpOptions::default())?;
+    let Ok(names) = std::env::var("LISTENERS") else {
+        let port = env_parse("PORT", 80)?;
+        let address = ListenAddress::Tcp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)));
+        let mut listener = ListenerConfig::new("default", address);
+        listener.allowed_origins = list_from_env("ALLOWED_ORIGINS");
+        listener.tcp = tcp;
+        return Ok(vec![listener]);
+    };
+
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let prefix = format!("LISTENER_{}_", name.to_ascii_uppercase());
+            let address_key = format!("{prefix}ADDRESS");
+            let address = std::env::var(&address_key).unwrap_or_default();
+            let address = address.parse().map_err(|_| ConfigError {
+                key: address_key,
+                value: address,
+            })?;
+            let mut listener = ListenerConfig::new(name, address);
+
+            if let Some(proxies) = list_from_env(&format!("{prefix}TRUSTED_PROXIES")) {
+                listener.trusted_proxies = proxies
+                    .into_iter()
+                    .map(|proxy| {
+                        parse_proxy(&proxy).ok_or_else(|| ConfigError {
+                            key: format!("{prefix}TRUSTED_PROXIES"),
+                            value: proxy,
+                        })
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            listener.allowed_origins = list_from_env(&format!("{prefix}ALLOWED_ORIGINS"));
+            listener.tcp = tcp_options_from_env(&prefix, &tcp)?;
+
+            let overrides = rate_limits_from_env(&format!("{prefix}RATE_LIMIT_"))?;
+            if !overrides.is_empty() {
+                let mut limits = rate_limits.clone();
+                for (kind, limit) in overrides {
+                    limits.set(&kind, limit);
+                }
+                listener.rate_limits = Some(Arc::new(limits));
+            }
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Socket options from `<prefix>TCP_*` variables, falling back to `defaults`
+fn tcp_options_from_env(prefix: &str, defaults: &TcpOptions) -> Result<TcpOptions, ConfigError> {
+    Ok(TcpOptions {
+        nodelay: env_parse(&format!("{prefix}TCP_NODELAY"), defaults.nodelay)?,
+        keepalive: env_optional_duration(&format!("{prefix}TCP_KEEPALIVE"), defaults.keepalive)?,
+        keepalive_interval: env_optional_duration(
+            &format!("{prefix}TCP_KEEPALIVE_INTERVAL"),
+            defaults.keepalive_interval,
+        )?,
+        keepalive_retries: env_optional(
+            &format!("{prefix}TCP_KEEPALIVE_RETRIES"),
+            defaults.keepalive_retries,
+        )?,
+        user_timeout: env_optional_duration(
+            &format!("{prefix}TCP_USER_TIMEOUT"),
+            defaults.user_timeout,
+        )?,
+        send_buffer: env_optional(&format!("{prefix}TCP_SEND_BUFFER"), defaults.send_buffer)?,
+        recv_buffer: env_optional(&format!("{prefix}TCP_RECV_BUFFER"), defaults.recv_buffer)?,
+    })
+}
+
+/// A comma separated list, an empty variable results in an empty list
+fn list_from_env(key: &str) -> Option<Vec<String>> {
+    let value = std::env::var(key).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
+
+fn env_parse<T: FromStr>(key: &str, default: T) -> Result<T, ConfigError> {
+    match std::env::var(key) {
+        Ok(value) => value.parse().map_err(|_| ConfigError {
+            key: key.to_string(),
+            value,
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_duration(key: &str, default: Duration) -> Result<Duration, ConfigError> {
+    match std::env::var(key) {
+        Ok(value) => parse_duration(&value).ok_or_else(|| ConfigError {
+            key: key.to_string(),
+            value,
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_optional<T: FromStr>(key: &str, default: Option<T>) -> Result<Option<T>, ConfigError> {
+    match std::env::var(key) {
+        Ok(value) => value.parse().map(Some).map_err(|_| ConfigError {
+            key: key.to_string(),
+            value,
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_optional_duration(
+    key: &str,
+    default: Option<Duration>,
+) -> Result<Option<Duration>, ConfigError> {
+    match std::env::var(key) {
+        Ok(value) => parse_duration(&value).map(Some).ok_or_else(|| ConfigError {
+            key: key.to_string(),
+            value,
+        }),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parse a duration like `90`, `15m` or `1h 30m`, numbers without a unit are seconds
+///
+/// Supported units are `ms`, `s`, `m`, `h` and `d`
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    if let Ok(seconds) = s.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = s;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit())?;
+        let (number, tail) = rest.split_at(number_end);
+        let value: u64 = number.parse().ok()?;
+        let unit_end = tail
+            .find(|c: char| c.is_ascii_digit() || c.is_whitespace())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+        let part = match unit {
+            "ms" => Duration::from_millis(value),
+            "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value.checked_mul(60)?),
+            "h" => Duration::from_secs(value.checked_mul(60 * 60)?),
+            "d" => Duration::from_secs(value.checked_mul(24 * 60 * 60)?),
+            _ => return None,
+        };
+        total = total.checked_add(part)?;
+        rest = tail.trim_start();
+    }
+    Some(total)
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    key: String,
+    value: String,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid value {:?} for {}", self.value, self.key)
+    }
+}
+
+impl Error for ConfigError {}
+
+/// All problems found when validating the configuration
+#[derive(Debug)]
+pub struct ValidationError {
+    pub problems: Vec<String>,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ValidationError {}
+
+/// Errors of the settings that can't be parsed, collected so they can be reported at once
+#[derive(Default)]
+struct ConfigErrors(Vec<ConfigError>);
+
+impl ConfigErrors {
+    /// The parsed setting, or a placeholder that is never used when the setting is invalid
+    fn check<T: Default>(&mut self, result: Result<T, ConfigError>) -> T {
+        self.check_or(result, T::default())
+    }
+
+    fn check_or<T>(&mut self, result: Result<T, ConfigError>, placeholder: T) -> T {
+        result.unwrap_or_else(|error| {
+            self.0.push(error);
+            placeholder
+        })
+    }
+
+    fn into_result<T>(self, value: T) -> Result<T, ValidationError> {
+        if self.0.is_empty() {
+            Ok(value)
+        } else {
+            Err(ValidationError {
+                problems: self.0.iter().map(ToString::to_string).collect(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        let parse = parse_duration;
+        assert_eq!(parse("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse("15m"), Some(Duration::from_secs(15 * 60)));
+        assert_eq!(parse("1h 30m"), Some(Duration::from_secs(90 * 60)));
+        assert_eq!(parse("1h30m"), Some(Duration::from_secs(90 * 60)));
+        assert_eq!(parse("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse(" 2d "), Some(Duration::from_secs(2 * 24 * 60 * 60)));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("  "), None);
+        assert_eq!(parse("5w"), None);
+        assert_eq!(parse("m"), None);
+        assert_eq!(parse("1.5h"), None);
+        assert_eq!(parse("-5s"), None);
+        assert_eq!(parse(&format!("{}d", u64::MAX)), None);
+        assert_eq!(parse(&format!("{}s {}s", u64::MAX, 1)), None);
+    }
+
+    #[test]
+    fn all_invalid_settings_are_reported() {
+        let mut errors = ConfigErrors::default();
+        let invalid = |key: &str, value: &str| -> Result<Duration, ConfigError> {
+            Err(ConfigError {
+                key: key.into(),
+                value: value.into(),
+            })
+        };
+        assert_eq!(
+            errors.check(Ok(Duration::from_secs(1))),
+            Duration::from_secs(1)
+        );
+        errors.check(invalid("SESSION_TIMEOUT", "soon"));
+        errors.check_or(invalid("FLOOD_WINDOW", "1.5h"), Duration::ZERO);
+        let problems = errors.into_result(()).unwrap_err().problems;
+        assert_eq!(
+            problems,
+            [
+                "invalid value \"soon\" for SESSION_TIMEOUT",
+                "invalid value \"1.5h\" for FLOOD_WINDOW"
+            ]
+        );
+        assert!(ConfigErrors::default().into_result(()).is_ok());
+    }
+
+    #[test]
+    fn validation_reports_all_problems() {
+        let address = ListenAddress::Tcp(SocketAddr::from((Ipv4Addr::LOCALHOST, 8080)));
+        let config = Config {
+            listeners: vec![
+                ListenerConfig::new("public", address.clone()),
+                ListenerConfig::new("internal", address),
+            ],
+            session_timeout: Duration::from_secs(2 * 60 * 60),
+            max_session_timeout: Duration::from_secs(60 * 60),
+            recorder_size: 0,
+            ..Config::default()
+        };
+        let problems = config.validate().unwrap_err().problems;
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("overlap"));
+        assert_eq!(
+            problems[1],
+            "SESSION_TIMEOUT is longer than MAX_SESSION_TIMEOUT"
+        );
+
+        let valid = Config {
+            recorder_size: 0,
+            ..Config::default()
+        };
+        assert!(valid.validate().is_ok());
+    }
+}
@@ -0,0 +1,153 @@
+//! Measure how often the shared maps are contended, to find out which sessions are the busiest
+
+use crate::telemetry;
+use dashmap::mapref::one::{Ref, RefMut};
+use dashmap::try_result::TryResult;
+use dashmap::DashMap;
+use metrics::{counter, histogram};
+use serde::Serialize;
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::Hash;
+use std::ops::Deref;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct KeyContention {
+    contended: u64,
+    wait: Duration,
+}
+
+/// An entry that had to wait for a lock
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Contention {
+    pub key: String,
+    pub contended: u64,
+    pub wait_ms: f64,
+}
+
+/// A `DashMap` that records when looking up an entry has to wait for a shard lock
+///
+/// Only `get` and `get_mut` are instrumented, everything else is passed to the map directly
+pub struct InstrumentedMap<K, V> {
+    name: &'static str,
+    map: DashMap<K, V>,
+    contention: DashMap<K, KeyContention>,
+}
+
+impl<K: Eq + Hash + Clone + Display, V> InstrumentedMap<K, V> {
+    pub fn with_capacity(name: &'static str, capacity: usize) -> Self {
+        InstrumentedMap {
+            name,
+            map: DashMap::with_capacity(capacity),
+            contention: DashMap::new(),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.map.try_get(key) {
+            TryResult::Present(entry) => Some(entry),
+            TryResult::Absent => None,
+            TryResult::Locked => {
+                let start = Instant::now();
+                let entry = self.map.get(key);
+                self.record(key, start.elapsed());
+                entry
+            }
+        }
+    }
+
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        match self.map.try_get_mut(key) {
+            TryResult::Present(entry) => Some(entry),
+            TryResult::Absent => None,
+            TryResult::Locked => {
+                let start = Instant::now();
+                let entry = self.map.get_mut(key);
+                self.record(key, start.elapsed());
+                entry
+            }
+        }
+    }
+
+    fn record<Q>(&self, key: &Q, wait: Duration)
+    where
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        counter!(telemetry::LOCK_CONTENDED, "map" => self.name).increment(1);
+        histogram!(telemetry::LOCK_WAIT, "map" => self.name).record(wait);
+        let mut stats = self.contention.entry(key.to_owned()).or_default();
+        stats.contended += 1;
+        stats.wait += wait;
+    }
+
+    /// Drop the contention statistics of a removed entry
+    pub fn forget<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.contention.remove(key);
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.forget(key);
+        self.map.remove(key)
+    }
+
+    /// The entries that spent the most time waiting for a lock
+    pub fn hottest(&self, limit: usize) -> Vec<Contention> {
+        let mut hottest: Vec<(Duration, Contention)> = self
+            .contention
+            .iter()
+            .map(|entry| {
+                let stats = entry.value();
+                (
+                    stats.wait,
+                    Contention {
+                        key: entry.key().to_string(),
+                        contended: stats.contended,
+                        wait_ms: stats.wait.as_secs_f64() * 1000.0,
+                    },
+                )
+            })
+            .collect();
+        hottest.sort_by_key(|(wait, _)| Reverse(*wait));
+        hottest
+            .into_iter()
+            .take(limit)
+            .map(|(_, contention)| contention)
+            .collect()
+    }
+}
+
+impl<K, V> Deref for InstrumentedMap<K, V> {
+    type Target = DashMap<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl<K: Eq + Hash + Debug, V: Debug> Debug for InstrumentedMap<K, V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InstrumentedMap")
+            .field("name", &self.name)
+            .field("map", &self.map)
+            .finish_non_exhaustive()
+    }
+}
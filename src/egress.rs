@@ -0,0 +1,270 @@
+//! Outbound byte rate budgets, so a single busy peer or session can't saturate the uplink
+//!
+//! When a budget is exhausted, frames are shed by priority: ticks are dropped first since the next tick
+//! replaces them anyway, bulk traffic like relayed frames and extension messages can overdraw the budget
+//! by one more burst and state changes are always send.
+
+use crate::peer::PeerId;
+use crate::ratelimit::RateLimit;
+use dashmap::DashMap;
+use std::hash::Hash;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Superseded by the next message of the same kind, shed as soon as the budget is exhausted
+    Coalescible,
+    /// Shed once the budget is overdrawn by more than one burst
+    Bulk,
+    /// Never shed, but still counted against the budget
+    Essential,
+}
+
+impl Priority {
+    /// The priority of a command by its kind
+    pub fn of(kind: &str) -> Self {
+        match kind {
+            "tick" => Priority::Coalescible,
+            "ext" | "reactions" => Priority::Bulk,
+            _ => Priority::Essential,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Coalescible => "coalescible",
+            Priority::Bulk => "bulk",
+            Priority::Essential => "essential",
+        }
+    }
+}
+
+/// Which budget caused a frame to be shed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Peer,
+    Session,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Peer => "peer",
+            Scope::Session => "session",
+        }
+    }
+}
+
+/// Token bucket that can go into debt for frames that can't be shed
+#[derive(Debug)]
+struct ByteBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ByteBucket {
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+    }
+
+    fn allows(&self, limit: RateLimit, size: f64, priority: Priority) -> bool {
+        match priority {
+            Priority::Coalescible => self.tokens >= size,
+            Priority::Bulk => self.tokens + limit.burst >= size,
+            Priority::Essential => true,
+        }
+    }
+}
+
+fn bucket<K: Eq + Hash>(
+    buckets: &DashMap<K, ByteBucket>,
+    key: K,
+    limit: RateLimit,
+    now: Instant,
+) -> dashmap::mapref::one::RefMut<'_, K, ByteBucket> {
+    let mut bucket = buckets.entry(key).or_insert(ByteBucket {
+        tokens: limit.burst,
+        updated: now,
+    });
+    bucket.refill(limit, now);
+    bucket
+}
+
+/// Outbound budgets for every peer and session, in bytes per second
+pub struct EgressLimiter {
+    peer_limit: Option<RateLimit>,
+    session_limit: Option<RateLimit>,
+    peers: DashMap<PeerId, ByteBucket>,
+    sessions: DashMap<String, ByteBucket>,
+}
+
+impl EgressLimiter {
+    pub fn new(peer_limit: Option<RateLimit>, session_limit: Option<RateLimit>) -> Self {
+        EgressLimiter {
+            peer_limit,
+            session_limit,
+            peers: DashMap::new(),
+            sessions: DashMap::new(),
+        }
+    }
+
+    /// Take `size` bytes from the budgets of the peer and the session the frame is send for
+    ///
+    /// Returns the exhausted budget if the frame has to be shed, in which case neither budget is charged
+    pub fn check(
+        &self,
+        peer: &PeerId,
+        session: Option<&str>,
+        size: usize,
+        priority: Priority,
+        now: Instant,
+    ) -> Result<(), Scope> {
+        let size = size as f64;
+        let mut peer_bucket = self
+            .peer_limit
+            .map(|limit| (limit, bucket(&self.peers, *peer, limit, now)));
+        if let Some((limit, bucket)) = &peer_bucket {
+            if !bucket.allows(*limit, size, priority) {
+                return Err(Scope::Peer);
+            }
+        }
+        let session_bucket = self.session_limit.zip(session).map(|(limit, session)| {
+            (
+                limit,
+                bucket(&self.sessions, session.to_string(), limit, now),
+            )
+        });
+        if let Some((limit, mut bucket)) = session_bucket {
+            if !bucket.allows(limit, size, priority) {
+                return Err(Scope::Session);
+            }
+            bucket.tokens -= size;
+        }
+        if let Some((_, bucket)) = &mut peer_bucket {
+            bucket.tokens -= size;
+        }
+        Ok(())
+    }
+
+    pub fn remove_peer(&self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    pub fn remove_session(&self, session: &str) {
+        self.sessions.remove(session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+    use tokio::time::advance;
+
+    fn peer(id: u64) -> PeerId {
+        PeerId::new(IpAddr::V4(Ipv4Addr::LOCALHOST), id)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peer_budget_sheds_by_priority() {
+        let limiter = EgressLimiter::new(Some(RateLimit::new(100.0, 100.0)), None);
+        let check = |size, priority| limiter.check(&peer(1), None, size, priority, Instant::now());
+
+        assert_eq!(check(80, Priority::Coalescible), Ok(()));
+        assert_eq!(check(30, Priority::Coalescible), Err(Scope::Peer));
+        // bulk traffic can overdraw the budget by one burst
+        assert_eq!(check(100, Priority::Bulk), Ok(()));
+        assert_eq!(check(30, Priority::Bulk), Err(Scope::Peer));
+        // essential frames always go through but are still charged
+        assert_eq!(check(500, Priority::Essential), Ok(()));
+        // other peers have their own budget
+        assert_eq!(
+            limiter.check(&peer(2), None, 100, Priority::Coalescible, Instant::now()),
+            Ok(())
+        );
+
+        // 580 bytes of debt: one second of refill is not enough to get back out of it
+        advance(Duration::from_secs(1)).await;
+        assert_eq!(check(1, Priority::Bulk), Err(Scope::Peer));
+        advance(Duration::from_secs(5)).await;
+        assert_eq!(check(20, Priority::Coalescible), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shed_frames_are_not_charged() {
+        let limiter = EgressLimiter::new(
+            Some(RateLimit::new(100.0, 100.0)),
+            Some(RateLimit::new(100.0, 200.0)),
+        );
+        let check = |peer_id, size| {
+            limiter.check(
+                &peer(peer_id),
+                Some("session"),
+                size,
+                Priority::Coalescible,
+                Instant::now(),
+            )
+        };
+
+        assert_eq!(check(1, 100), Ok(()));
+        assert_eq!(check(1, 10), Err(Scope::Peer));
+        assert_eq!(check(2, 90), Ok(()));
+        // the session budget is exhausted, the frame isn't charged against the peer
+        assert_eq!(check(3, 20), Err(Scope::Session));
+        assert_eq!(check(3, 10), Ok(()));
+        assert_eq!(
+            limiter.check(&peer(3), None, 90, Priority::Coalescible, Instant::now()),
+            Ok(())
+        );
+
+        advance(Duration::from_millis(500)).await;
+        assert_eq!(check(1, 50), Ok(()));
+        assert_eq!(check(2, 10), Err(Scope::Session));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removed_budgets_start_full() {
+        let limiter = EgressLimiter::new(
+            Some(RateLimit::new(10.0, 100.0)),
+            Some(RateLimit::new(10.0, 100.0)),
+        );
+        let now = Instant::now();
+        assert_eq!(
+            limiter.check(&peer(1), None, 100, Priority::Coalescible, now),
+            Ok(())
+        );
+        limiter.remove_peer(&peer(1));
+        assert_eq!(
+            limiter.check(&peer(1), None, 100, Priority::Coalescible, now),
+            Ok(())
+        );
+
+        assert_eq!(
+            limiter.check(&peer(2), Some("session"), 100, Priority::Coalescible, now),
+            Ok(())
+        );
+        limiter.remove_session("session");
+        assert_eq!(
+            limiter.check(&peer(3), Some("session"), 100, Priority::Coalescible, now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = EgressLimiter::new(None, None);
+        assert_eq!(
+            limiter.check(
+                &peer(1),
+                Some("session"),
+                usize::MAX,
+                Priority::Coalescible,
+                Instant::now()
+            ),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use crate::peer::PeerId;
+use tokio::sync::broadcast;
+
+/// Number of events buffered for each subscriber before it starts missing events
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Things happening on the server, for applications embedding it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    SessionCreated {
+        session: String,
+        owner: PeerId,
+    },
+    ClientJoined {
+        session: String,
+        peer: PeerId,
+    },
+    ClientLeft {
+        session: String,
+        peer: PeerId,
+    },
+    /// A command was send to all clients of a session
+    CommandBroadcast {
+        session: String,
+        kind: &'static str,
+        recipients: usize,
+    },
+    /// The number of messages to clients of a session that were dropped because their connection
+    /// couldn't keep up exceeded the alert threshold, raised at most once per `DROP_WINDOW`
+    MessagesDropped {
+        session: String,
+        dropped: u64,
+    },
+    SessionRenamed {
+        session: String,
+        new_name: String,
+    },
+    /// The owner of a session disconnected, the session expires unless the owner reconnects
+    OwnerLeft {
+        session: String,
+    },
+    /// A session was removed after being inactive for too long
+    SessionExpired {
+        session: String,
+    },
+    /// A session was closed by its owner
+    SessionClosed {
+        session: String,
+    },
+}
+
+/// Fan out events to all subscribers
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        EventBus {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Subscribe to all events published after this call
+    ///
+    /// Slow subscribers receive a `RecvError::Lagged` instead of blocking the server
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Publish an event, building it only when anyone is listening
+    pub fn publish(&self, event: impl FnOnce() -> Event) {
+        if self.sender.receiver_count() > 0 {
+            // sending only fails without receivers
+            let _ = self.sender.send(event());
+        }
+    }
+}
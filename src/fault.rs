@@ -0,0 +1,105 @@
+//! Artificial latency, dropped messages and disconnects for testing client reconnect logic
+
+use crate::peer::PeerId;
+use dashmap::DashMap;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FaultSettings {
+    /// Delay in milliseconds added to every message
+    pub latency: u64,
+    /// Random extra delay in milliseconds added on top of the latency
+    pub jitter: u64,
+    /// Probability that a message is dropped
+    pub drop_rate: f64,
+    /// Probability that the connection is closed instead of passing a message
+    pub disconnect_rate: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    /// Faults applied to peers without their own settings
+    pub defaults: FaultSettings,
+    /// Seed for reproducible faults, a random seed is used if not set
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Fault {
+    Deliver { delay: Duration },
+    Drop,
+    Disconnect,
+}
+
+/// Decide the fate of every message send to or received from a peer
+pub struct FaultInjector {
+    defaults: RwLock<FaultSettings>,
+    peers: DashMap<PeerId, FaultSettings>,
+    rng: Mutex<SmallRng>,
+}
+
+impl FaultInjector {
+    pub fn new(config: &FaultConfig) -> Self {
+        let rng = match config.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        FaultInjector {
+            defaults: RwLock::new(config.defaults),
+            peers: DashMap::new(),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub fn settings(&self, peer: Option<&PeerId>) -> FaultSettings {
+        peer.and_then(|peer| self.peers.get(peer).map(|settings| *settings))
+            .unwrap_or_else(|| *self.defaults.read().unwrap())
+    }
+
+    /// Change the faults for a single peer, or the defaults for all peers
+    pub fn set(&self, peer: Option<PeerId>, settings: FaultSettings) {
+        match peer {
+            Some(peer) => {
+                self.peers.insert(peer, settings);
+            }
+            None => *self.defaults.write().unwrap() = settings,
+        }
+    }
+
+    /// Reset a peer to the default faults, or disable the default faults
+    pub fn clear(&self, peer: Option<&PeerId>) {
+        match peer {
+            Some(peer) => {
+                self.peers.remove(peer);
+            }
+            None => *self.defaults.write().unwrap() = FaultSettings::default(),
+        }
+    }
+
+    pub fn decide(&self, peer: &PeerId) -> Fault {
+        let settings = self.settings(Some(peer));
+        let mut rng = self.rng.lock().unwrap();
+        if settings.disconnect_rate > 0.0 && rng.gen_bool(settings.disconnect_rate.min(1.0)) {
+            return Fault::Disconnect;
+        }
+        if settings.drop_rate > 0.0 && rng.gen_bool(settings.drop_rate.min(1.0)) {
+            return Fault::Drop;
+        }
+        let jitter = match settings.jitter {
+            0 => 0,
+            jitter => rng.gen_range(0..=jitter),
+        };
+        Fault::Deliver {
+            delay: Duration::from_millis(settings.latency + jitter),
+        }
+    }
+
+    pub fn remove_peer(&self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+}
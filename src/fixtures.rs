@@ -0,0 +1,1165 @@
+//! Canonical json samples of every command, to catch changes to the wire format used by the clients
+//!
+//! The samples are checked into the repository in `protocol/`, with the messages send by clients in `client/`
+//! and the messages send by the server in `server/`. The `protocol-fixtures` binary checks that every sample
+//! still serializes to, and deserializes from, its fixture, or rewrites the fixtures after an intentional change.
+
+use crate::admin::{AdminRequest, ExportedSession};
+use crate::fault::FaultSettings;
+use crate::recorder::Direction;
+use crate::{
+    ClientDetails, CloseReason, DemoInfo, EncryptionMode, ErrorCode, LoggedCommand, PublicSession,
+    RosterEntry, ServerEvent, SessionStats, SyncCommand,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A command as it is send over the wire by either the clients or the server
+pub struct Sample {
+    pub name: &'static str,
+    pub direction: Direction,
+    pub command: SampleMessage<'static>,
+}
+
+/// Either a command, which can be send in both directions, or an event only the server sends
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum SampleMessage<'a> {
+    Command(SyncCommand<'a>),
+    Event(ServerEvent<'a>),
+}
+
+impl<'a> From<SyncCommand<'a>> for SampleMessage<'a> {
+    fn from(command: SyncCommand<'a>) -> Self {
+        SampleMessage::Command(command)
+    }
+}
+
+impl<'a> From<ServerEvent<'a>> for SampleMessage<'a> {
+    fn from(event: ServerEvent<'a>) -> Self {
+        SampleMessage::Event(event)
+    }
+}
+
+impl SampleMessage<'_> {
+    /// Parse a fixture as the same type of message as the sample
+    fn parse<'f>(&self, fixture: &'f str) -> serde_json::Result<SampleMessage<'f>> {
+        Ok(match self {
+            SampleMessage::Command(_) => SampleMessage::Command(serde_json::from_str(fixture)?),
+            SampleMessage::Event(_) => SampleMessage::Event(serde_json::from_str(fixture)?),
+        })
+    }
+}
+
+impl Sample {
+    fn new(
+        name: &'static str,
+        direction: Direction,
+        command: impl Into<SampleMessage<'static>>,
+    ) -> Self {
+        Sample {
+            name,
+            direction,
+            command: command.into(),
+        }
+    }
+
+    /// Path of the fixture relative to the fixture directory
+    pub fn path(&self) -> PathBuf {
+        let dir = match self.direction {
+            Direction::In => "client",
+            Direction::Out => "server",
+        };
+        Path::new(dir).join(format!("{}.json", self.name))
+    }
+}
+
+fn admin(request: AdminRequest) -> SyncCommand<'static> {
+    SyncCommand::Admin {
+        token: "admin-token",
+        request,
+    }
+}
+
+/// One sample for every command, commands with optional fields have a sample with and without them
+pub fn samples() -> Vec<Sample> {
+    use Direction::{In, Out};
+
+    let session = "demo";
+    let token = "owner-token";
+    vec![
+        Sample::new(
+            "hello",
+            In,
+            SyncCommand::Hello {
+                version: 1,
+                capabilities: vec!["markers", "voice"],
+            },
+        ),
+        Sample::new(
+            "time",
+            In,
+            SyncCommand::Time {
+                client_echo: 12345.5,
+            },
+        ),
+        Sample::new(
+            "create",
+            In,
+            SyncCommand::Create {
+                session: Some(session),
+                token: Some(token),
+                public: Some(true),
+                password: Some(Some("secret")),
+                monotonic: Some(false),
+                timeout: Some(Some(3600)),
+                max_clients: Some(Some(50)),
+                log: Some(true),
+                auto_advance: Some(true),
+            },
+        ),
+        Sample::new(
+            "create_reset",
+            In,
+            SyncCommand::Create {
+                session: Some(session),
+                token: Some(token),
+                public: None,
+                password: Some(None),
+                monotonic: None,
+                timeout: Some(None),
+                max_clients: None,
+                log: None,
+                auto_advance: None,
+            },
+        ),
+        Sample::new(
+            "create_generated",
+            In,
+            SyncCommand::Create {
+                session: None,
+                token: None,
+                public: None,
+                password: None,
+                monotonic: None,
+                timeout: None,
+                max_clients: None,
+                log: None,
+                auto_advance: None,
+            },
+        ),
+        Sample::new(
+            "join",
+            In,
+            SyncCommand::Join {
+                session,
+                password: None,
+                name: Some("viewer"),
+                capabilities: vec!["annotations", "queue"],
+            },
+        ),
+        Sample::new(
+            "join_with_password",
+            In,
+            SyncCommand::Join {
+                session,
+                password: Some("secret"),
+                name: None,
+                capabilities: Vec::new(),
+            },
+        ),
+        Sample::new("leave", In, SyncCommand::Leave { session }),
+        Sample::new(
+            "subscribe",
+            In,
+            SyncCommand::Subscribe {
+                session,
+                kinds: Some(vec!["tick".into(), "play".into()]),
+            },
+        ),
+        Sample::new(
+            "subscribe_all",
+            In,
+            SyncCommand::Subscribe {
+                session,
+                kinds: None,
+            },
+        ),
+        Sample::new(
+            "tick",
+            In,
+            SyncCommand::Tick {
+                session,
+                tick: 1234,
+                timestamp: None,
+                tickrate: None,
+            },
+        ),
+        Sample::new(
+            "tick_with_tickrate",
+            In,
+            SyncCommand::Tick {
+                session,
+                tick: 1234,
+                timestamp: None,
+                tickrate: Some(66.67),
+            },
+        ),
+        Sample::new(
+            "seek",
+            In,
+            SyncCommand::Seek {
+                session,
+                tick: 5000,
+            },
+        ),
+        Sample::new(
+            "play",
+            In,
+            SyncCommand::Play {
+                session,
+                play: true,
+            },
+        ),
+        Sample::new(
+            "playat",
+            In,
+            SyncCommand::PlayAt {
+                session,
+                tick: 1234,
+                play: false,
+            },
+        ),
+        Sample::new(
+            "demo",
+            In,
+            SyncCommand::Demo {
+                session,
+                demo: Some(DemoInfo {
+                    id: Some(12345),
+                    url: Some("https://static.demos.tf/demos/12345.dem".into()),
+                    map: Some("cp_process_f12".into()),
+                    duration: Some(1800.5),
+                    max_tick: Some(120033),
+                    pause_at_end: true,
+                }),
+            },
+        ),
+        Sample::new(
+            "pov",
+            In,
+            SyncCommand::Pov {
+                session,
+                entity_id: Some(3),
+            },
+        ),
+        Sample::new(
+            "pov_free_camera",
+            In,
+            SyncCommand::Pov {
+                session,
+                entity_id: None,
+            },
+        ),
+        Sample::new(
+            "marker",
+            In,
+            SyncCommand::Marker {
+                session,
+                tick: 41230,
+                label: "mid fight".into(),
+            },
+        ),
+        Sample::new(
+            "schedule",
+            In,
+            SyncCommand::Schedule {
+                session,
+                start_at: Some(1700000000000),
+            },
+        ),
+        Sample::new(
+            "schedule_cancel",
+            In,
+            SyncCommand::Schedule {
+                session,
+                start_at: None,
+            },
+        ),
+        Sample::new(
+            "react",
+            In,
+            SyncCommand::React {
+                session,
+                emote: "clap",
+            },
+        ),
+        Sample::new("presence", In, SyncCommand::Presence { session }),
+        Sample::new(
+            "progress",
+            In,
+            SyncCommand::Progress {
+                session,
+                tick: 1200,
+            },
+        ),
+        Sample::new("contest", In, SyncCommand::Contest { session }),
+        Sample::new(
+            "rotate",
+            In,
+            SyncCommand::Rotate {
+                session,
+                token,
+                new_token: "new-owner-token",
+            },
+        ),
+        Sample::new(
+            "transfer",
+            In,
+            SyncCommand::Transfer {
+                session,
+                token,
+                new_owner: "0123abcd",
+            },
+        ),
+        Sample::new(
+            "ext",
+            In,
+            SyncCommand::Ext {
+                session,
+                kind: "chat",
+                payload: json!({"text": "hello"}),
+            },
+        ),
+        Sample::new(
+            "sealed",
+            In,
+            SyncCommand::Sealed {
+                session,
+                payload: "bm90IHJlYWxseSBlbmNyeXB0ZWQ=".into(),
+            },
+        ),
+        Sample::new(
+            "encryption",
+            In,
+            SyncCommand::Encryption {
+                session,
+                mode: EncryptionMode::Full,
+            },
+        ),
+        Sample::new(
+            "voice",
+            In,
+            SyncCommand::Voice {
+                session,
+                enabled: true,
+            },
+        ),
+        Sample::new(
+            "mirror",
+            In,
+            SyncCommand::Mirror {
+                session,
+                target: "mirror",
+                token: "mirror-token",
+                enabled: true,
+            },
+        ),
+        Sample::new(
+            "rename",
+            In,
+            SyncCommand::Rename {
+                session,
+                new_name: "renamed",
+                redirect: true,
+            },
+        ),
+        Sample::new(
+            "listen",
+            In,
+            SyncCommand::Listen {
+                session,
+                channel: 1,
+                listen: true,
+            },
+        ),
+        Sample::new("close", In, SyncCommand::Close { session, token }),
+        Sample::new("resync", In, SyncCommand::Resync { session }),
+        Sample::new("list", In, SyncCommand::List),
+        Sample::new("exists", In, SyncCommand::Exists { session }),
+        Sample::new("stats", In, SyncCommand::Stats { session }),
+        Sample::new("history", In, SyncCommand::History { session }),
+        Sample::new(
+            "kick",
+            In,
+            SyncCommand::Kick {
+                session,
+                client: "0123abcd",
+            },
+        ),
+        Sample::new(
+            "queueadd",
+            In,
+            SyncCommand::QueueAdd {
+                session,
+                demo: DemoInfo {
+                    id: Some(12346),
+                    url: Some("https://static.demos.tf/demos/12346.dem".into()),
+                    map: Some("cp_gullywash_f9".into()),
+                    duration: Some(1800.0),
+                    max_tick: Some(119997),
+                    pause_at_end: false,
+                },
+            },
+        ),
+        Sample::new(
+            "queueremove",
+            In,
+            SyncCommand::QueueRemove { session, index: 0 },
+        ),
+        Sample::new("next", In, SyncCommand::Next { session }),
+        Sample::new(
+            "setmeta",
+            In,
+            SyncCommand::SetMeta {
+                session,
+                title: Some("Grand finals".into()),
+                description: Some("Reviewing the last round".into()),
+            },
+        ),
+        Sample::new(
+            "countdown",
+            In,
+            SyncCommand::Countdown {
+                session,
+                seconds: 5,
+                start_at: None,
+            },
+        ),
+        Sample::new(
+            "countdown",
+            Out,
+            SyncCommand::Countdown {
+                session,
+                seconds: 5,
+                start_at: Some(1700000005000),
+            },
+        ),
+        Sample::new(
+            "loop",
+            In,
+            SyncCommand::Loop {
+                session,
+                from_tick: 1000,
+                to_tick: 1500,
+                enabled: true,
+            },
+        ),
+        Sample::new(
+            "annotate",
+            In,
+            SyncCommand::Annotate {
+                session,
+                payload: serde_json::json!({"shape": "arrow", "from": [0.25, 0.5], "to": [0.4, 0.6]}),
+                retain: true,
+            },
+        ),
+        Sample::new(
+            "signal",
+            In,
+            SyncCommand::Signal {
+                session,
+                to: "0123abcd",
+                from: None,
+                payload: serde_json::json!({"type": "offer", "sdp": "v=0"}),
+            },
+        ),
+        Sample::new(
+            "promote",
+            In,
+            SyncCommand::Promote {
+                session,
+                client: "0123abcd",
+            },
+        ),
+        Sample::new(
+            "demote",
+            In,
+            SyncCommand::Demote {
+                session,
+                client: "0123abcd",
+            },
+        ),
+        Sample::new(
+            "admin_dump_recorder",
+            In,
+            admin(AdminRequest::DumpRecorder {
+                session: Some(session.into()),
+            }),
+        ),
+        Sample::new(
+            "admin_export_session",
+            In,
+            admin(AdminRequest::ExportSession {
+                session: session.into(),
+                include_token: true,
+            }),
+        ),
+        Sample::new(
+            "admin_import_session",
+            In,
+            admin(AdminRequest::ImportSession {
+                session: Box::new(ExportedSession {
+                    name: session.into(),
+                    owner_token: None,
+                    tick: 1234,
+                    playing: false,
+                    scheduled_start: None,
+                    last_seek: Some(1000),
+                    public: false,
+                    monotonic: false,
+                    auto_advance: false,
+                    timeout: None,
+                    max_clients: None,
+                    log: false,
+                    pov: None,
+                    loop_region: None,
+                    voice: false,
+                    encryption: EncryptionMode::Off,
+                    demo: None,
+                    password: None,
+                    markers: Vec::new(),
+                    queue: Vec::new(),
+                    title: None,
+                    description: None,
+                }),
+            }),
+        ),
+        Sample::new(
+            "admin_lock_contention",
+            In,
+            admin(AdminRequest::LockContention { limit: 5 }),
+        ),
+        Sample::new(
+            "admin_capture_logs",
+            In,
+            admin(AdminRequest::CaptureLogs {
+                session: session.into(),
+                enabled: true,
+                duration: 60,
+            }),
+        ),
+        Sample::new("admin_list_archive", In, admin(AdminRequest::ListArchive)),
+        Sample::new(
+            "admin_get_archive",
+            In,
+            admin(AdminRequest::GetArchive {
+                session: session.into(),
+            }),
+        ),
+        Sample::new(
+            "admin_session_stats",
+            In,
+            admin(AdminRequest::SessionStats {
+                session: session.into(),
+            }),
+        ),
+        Sample::new(
+            "admin_set_faults",
+            In,
+            admin(AdminRequest::SetFaults {
+                peer: None,
+                faults: FaultSettings {
+                    latency: 100,
+                    jitter: 20,
+                    drop_rate: 0.5,
+                    disconnect_rate: 0.0,
+                },
+            }),
+        ),
+        Sample::new(
+            "admin_mirror",
+            In,
+            admin(AdminRequest::Mirror {
+                source: session.into(),
+                target: "mirror".into(),
+                enabled: false,
+            }),
+        ),
+        Sample::new(
+            "admin_clear_faults",
+            In,
+            admin(AdminRequest::ClearFaults {
+                peer: Some("127.0.0.1-1".into()),
+            }),
+        ),
+        Sample::new(
+            "serverinfo",
+            Out,
+            ServerEvent::ServerInfo {
+                connection_id: "0123abcd",
+                version: "0.1.0",
+            },
+        ),
+        Sample::new(
+            "hello",
+            Out,
+            ServerEvent::Hello {
+                version: 1,
+                capabilities: vec!["markers"],
+            },
+        ),
+        Sample::new(
+            "time",
+            Out,
+            ServerEvent::Time {
+                client_echo: 12345.5,
+                server_time: 1700000000000,
+            },
+        ),
+        Sample::new("created", Out, ServerEvent::Created { session, token }),
+        Sample::new(
+            "tick",
+            Out,
+            SyncCommand::Tick {
+                session,
+                tick: 1234,
+                timestamp: Some(1700000000000),
+                tickrate: Some(66.67),
+            },
+        ),
+        Sample::new(
+            "seek",
+            Out,
+            SyncCommand::Seek {
+                session,
+                tick: 5000,
+            },
+        ),
+        Sample::new(
+            "play",
+            Out,
+            SyncCommand::Play {
+                session,
+                play: false,
+            },
+        ),
+        Sample::new(
+            "playat",
+            Out,
+            SyncCommand::PlayAt {
+                session,
+                tick: 1234,
+                play: false,
+            },
+        ),
+        Sample::new(
+            "demo",
+            Out,
+            SyncCommand::Demo {
+                session,
+                demo: Some(DemoInfo {
+                    id: Some(12345),
+                    url: None,
+                    map: Some("cp_process_f12".into()),
+                    duration: None,
+                    max_tick: None,
+                    pause_at_end: false,
+                }),
+            },
+        ),
+        Sample::new(
+            "pov",
+            Out,
+            SyncCommand::Pov {
+                session,
+                entity_id: Some(3),
+            },
+        ),
+        Sample::new(
+            "marker",
+            Out,
+            SyncCommand::Marker {
+                session,
+                tick: 41230,
+                label: "mid fight".into(),
+            },
+        ),
+        Sample::new(
+            "schedule",
+            Out,
+            SyncCommand::Schedule {
+                session,
+                start_at: Some(1700000000000),
+            },
+        ),
+        Sample::new(
+            "clients",
+            Out,
+            ServerEvent::Clients {
+                session,
+                count: 3,
+                idle: 1,
+                ids: vec!["0123abcd".into(), "4567ef01".into(), "89abcdef".into()],
+                clients: vec![
+                    ClientDetails {
+                        id: "0123abcd".into(),
+                        name: Some("viewer".into()),
+                        joined_at: 1700000000000,
+                        tick: Some(1234),
+                        idle: false,
+                        latency: Some(42),
+                    },
+                    ClientDetails {
+                        id: "4567ef01".into(),
+                        name: None,
+                        joined_at: 1700000005000,
+                        tick: None,
+                        idle: true,
+                        latency: None,
+                    },
+                    ClientDetails {
+                        id: "89abcdef".into(),
+                        name: None,
+                        joined_at: 1700000010000,
+                        tick: Some(1200),
+                        idle: false,
+                        latency: Some(118),
+                    },
+                ],
+            },
+        ),
+        Sample::new(
+            "roster",
+            Out,
+            ServerEvent::Roster {
+                session,
+                clients: vec![
+                    RosterEntry {
+                        id: "0123abcd".into(),
+                        name: Some("viewer".into()),
+                    },
+                    RosterEntry {
+                        id: "4567ef01".into(),
+                        name: None,
+                    },
+                ],
+            },
+        ),
+        Sample::new("kicked", Out, ServerEvent::Kicked { session }),
+        Sample::new(
+            "promoted",
+            Out,
+            ServerEvent::Promoted {
+                session,
+                promoted: true,
+            },
+        ),
+        Sample::new(
+            "starting",
+            Out,
+            ServerEvent::Starting {
+                session,
+                start_at: 1700000000000,
+                in_seconds: 10,
+            },
+        ),
+        Sample::new(
+            "ended",
+            Out,
+            ServerEvent::Ended {
+                session,
+                tick: 120033,
+            },
+        ),
+        Sample::new(
+            "reactions",
+            Out,
+            ServerEvent::Reactions {
+                session,
+                counts: BTreeMap::from([("clap", 3), ("fire", 1)]),
+            },
+        ),
+        Sample::new(
+            "progressreport",
+            Out,
+            ServerEvent::ProgressReport {
+                session,
+                clients: 3,
+                min: Some(1100),
+                max: Some(1234),
+                median: Some(1200),
+            },
+        ),
+        Sample::new(
+            "progressreport_without_clients",
+            Out,
+            ServerEvent::ProgressReport {
+                session,
+                clients: 0,
+                min: None,
+                max: None,
+                median: None,
+            },
+        ),
+        Sample::new(
+            "takeover",
+            Out,
+            ServerEvent::Takeover {
+                session,
+                contest_until: Some(1700000000000),
+            },
+        ),
+        Sample::new(
+            "rotated",
+            Out,
+            ServerEvent::Rotated {
+                session,
+                previous_valid_until: 1700000000000,
+            },
+        ),
+        Sample::new(
+            "transferred",
+            Out,
+            ServerEvent::Transferred {
+                session,
+                token: Some("new-owner-token"),
+            },
+        ),
+        Sample::new(
+            "transferred_previous_owner",
+            Out,
+            ServerEvent::Transferred {
+                session,
+                token: None,
+            },
+        ),
+        Sample::new(
+            "ext",
+            Out,
+            SyncCommand::Ext {
+                session,
+                kind: "chat",
+                payload: json!({"text": "hello"}),
+            },
+        ),
+        Sample::new(
+            "sealed",
+            Out,
+            SyncCommand::Sealed {
+                session,
+                payload: "bm90IHJlYWxseSBlbmNyeXB0ZWQ=".into(),
+            },
+        ),
+        Sample::new(
+            "encryption",
+            Out,
+            SyncCommand::Encryption {
+                session,
+                mode: EncryptionMode::Payloads,
+            },
+        ),
+        Sample::new(
+            "renamed",
+            Out,
+            ServerEvent::Renamed {
+                session,
+                new_name: "renamed",
+            },
+        ),
+        Sample::new(
+            "adminresponse",
+            Out,
+            ServerEvent::AdminResponse {
+                data: json!({"sessions": [], "peers": []}),
+            },
+        ),
+        Sample::new(
+            "stats",
+            Out,
+            ServerEvent::Stats {
+                session,
+                stats: SessionStats {
+                    created_at: 1700000000000,
+                    clients: 12,
+                    peak_clients: 20,
+                    joins: 31,
+                    broadcasts: 5820,
+                    reactions: 44,
+                },
+            },
+        ),
+        Sample::new(
+            "history",
+            Out,
+            ServerEvent::History {
+                session,
+                commands: vec![
+                    LoggedCommand {
+                        at: 1700000000000,
+                        command: json!({"type": "play", "session": session, "play": true}),
+                    },
+                    LoggedCommand {
+                        at: 1700000001000,
+                        command: json!({"type": "seek", "session": session, "tick": 1200}),
+                    },
+                ],
+                dropped: 0,
+            },
+        ),
+        Sample::new(
+            "exists",
+            Out,
+            ServerEvent::Exists {
+                session,
+                exists: true,
+                clients: 3,
+                public: false,
+                protected: true,
+            },
+        ),
+        Sample::new(
+            "exists_not_found",
+            Out,
+            ServerEvent::Exists {
+                session,
+                exists: false,
+                clients: 0,
+                public: false,
+                protected: false,
+            },
+        ),
+        Sample::new(
+            "sessions",
+            Out,
+            ServerEvent::Sessions {
+                sessions: vec![PublicSession {
+                    name: session.into(),
+                    clients: 3,
+                    protected: false,
+                    tick: Some(1234),
+                    title: Some("Grand finals".into()),
+                }],
+            },
+        ),
+        Sample::new(
+            "owneraway",
+            Out,
+            ServerEvent::OwnerAway {
+                session,
+                expires_in: 900,
+            },
+        ),
+        Sample::new("ownerreturned", Out, ServerEvent::OwnerReturned { session }),
+        Sample::new(
+            "sessionexpiring",
+            Out,
+            ServerEvent::SessionExpiring {
+                session,
+                in_seconds: 60,
+            },
+        ),
+        Sample::new(
+            "sessionclosed",
+            Out,
+            ServerEvent::SessionClosed {
+                session,
+                reason: CloseReason::Closed,
+            },
+        ),
+        Sample::new(
+            "error",
+            Out,
+            ServerEvent::Error {
+                code: ErrorCode::InvalidToken,
+                session: Some(session),
+                message: "invalid owner token".into(),
+            },
+        ),
+        Sample::new(
+            "error_session_not_found",
+            Out,
+            ServerEvent::Error {
+                code: ErrorCode::SessionNotFound,
+                session: Some(session),
+                message: "session demo doesn't exist".into(),
+            },
+        ),
+        Sample::new(
+            "error_without_session",
+            Out,
+            ServerEvent::Error {
+                code: ErrorCode::RateLimited,
+                session: None,
+                message: "too many messages".into(),
+            },
+        ),
+    ]
+}
+
+/// A fixture that doesn't match its sample
+#[derive(Debug)]
+pub enum Mismatch {
+    Missing(PathBuf),
+    Unreadable(PathBuf, io::Error),
+    /// The sample serializes to different json than the fixture
+    Serialize {
+        path: PathBuf,
+        expected: Value,
+        actual: Value,
+    },
+    /// The fixture can't be parsed, or parses to a different command than the sample
+    Deserialize {
+        path: PathBuf,
+        error: String,
+    },
+    /// A fixture for which no sample exists anymore
+    Unknown(PathBuf),
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mismatch::Missing(path) => write!(f, "{}: fixture is missing", path.display()),
+            Mismatch::Unreadable(path, e) => write!(f, "{}: failed to read: {e}", path.display()),
+            Mismatch::Serialize {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: serialized as {actual} instead of {expected}",
+                path.display()
+            ),
+            Mismatch::Deserialize { path, error } => {
+                write!(f, "{}: {error}", path.display())
+            }
+            Mismatch::Unknown(path) => write!(f, "{}: no sample for fixture", path.display()),
+        }
+    }
+}
+
+fn encode(command: &SampleMessage) -> String {
+    let mut json =
+        serde_json::to_string_pretty(command).expect("commands can always be serialized");
+    json.push('\n');
+    json
+}
+
+fn check_sample(dir: &Path, sample: &Sample) -> Option<Mismatch> {
+    let path = dir.join(sample.path());
+    let fixture = match fs::read_to_string(&path) {
+        Ok(fixture) => fixture,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Some(Mismatch::Missing(path)),
+        Err(e) => return Some(Mismatch::Unreadable(path, e)),
+    };
+
+    let parsed = match sample.command.parse(&fixture) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            return Some(Mismatch::Deserialize {
+                path,
+                error: e.to_string(),
+            })
+        }
+    };
+    if parsed != sample.command {
+        return Some(Mismatch::Deserialize {
+            path,
+            error: format!("parsed as {parsed:?} instead of {:?}", sample.command),
+        });
+    }
+
+    let expected: Value = serde_json::from_str(&fixture).expect("fixture was parsed before");
+    let actual = serde_json::to_value(&sample.command).expect("commands can always be serialized");
+    (expected != actual).then_some(Mismatch::Serialize {
+        path,
+        expected,
+        actual,
+    })
+}
+
+/// The fixture files in the `client` and `server` directories
+fn fixture_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for sub_dir in ["client", "server"] {
+        let Ok(entries) = fs::read_dir(dir.join(sub_dir)) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Compare every sample against its fixture in `dir`
+pub fn check(dir: &Path) -> io::Result<Vec<Mismatch>> {
+    let samples = samples();
+    let mut mismatches: Vec<Mismatch> = samples
+        .iter()
+        .filter_map(|sample| check_sample(dir, sample))
+        .collect();
+    for file in fixture_files(dir)? {
+        if !samples.iter().any(|sample| dir.join(sample.path()) == file) {
+            mismatches.push(Mismatch::Unknown(file));
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Rewrite all fixtures in `dir` from the samples and delete the fixtures without sample
+///
+/// Returns the paths of the fixtures that were changed
+pub fn update(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let samples = samples();
+    let mut changed = Vec::new();
+    for sample in &samples {
+        let path = dir.join(sample.path());
+        let json = encode(&sample.command);
+        if fs::read_to_string(&path).ok().as_deref() != Some(json.as_str()) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, json)?;
+            changed.push(path);
+        }
+    }
+    for file in fixture_files(dir)? {
+        if !samples.iter().any(|sample| dir.join(sample.path()) == file) {
+            fs::remove_file(&file)?;
+            changed.push(file);
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use std::path::Path;
+
+    #[test]
+    fn fixtures_match_the_protocol() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("protocol");
+        let mismatches = check(&dir).unwrap();
+        let report: Vec<String> = mismatches.iter().map(ToString::to_string).collect();
+        assert!(
+            mismatches.is_empty(),
+            "run `cargo run --bin protocol-fixtures -- update` if the protocol change is intentional:\n{}",
+            report.join("\n")
+        );
+    }
+}
@@ -0,0 +1,4068 @@
+pub mod admin;
+pub mod archive;
+pub mod auth;
+pub mod backpressure;
+pub mod batch;
+pub mod capture;
+pub mod config;
+pub mod contention;
+pub mod egress;
+pub mod events;
+pub mod fault;
+pub mod fixtures;
+pub mod listener;
+pub mod middleware;
+pub mod msgpack;
+pub mod peer;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod ratelimit;
+pub mod recorder;
+pub mod relay;
+pub mod replay;
+mod session;
+pub mod sim;
+pub mod store;
+pub mod task;
+pub mod telemetry;
+pub mod transport;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+use crate::admin::{AdminRequest, ExportedSession};
+use crate::archive::Archive;
+use crate::auth::{AuthProvider, Handshake, Identity};
+use crate::backpressure::DropTracker;
+use crate::batch::MAX_BATCH_SIZE;
+use crate::capture::LogCapture;
+use crate::config::Config;
+use crate::contention::InstrumentedMap;
+use crate::egress::{EgressLimiter, Priority};
+use crate::events::{Event, EventBus, EVENT_CAPACITY};
+use crate::fault::{Fault, FaultInjector, FaultSettings};
+use crate::listener::ListenerConfig;
+use crate::middleware::Middleware;
+use crate::peer::{
+    generate_connection_id, query_param, ConnectionInfo, Peer, PeerId, Peers, MAX_JOINED_SESSIONS,
+};
+use crate::ratelimit::{CreateCounter, RateLimit, RateLimitResult, Violation};
+use crate::recorder::{Direction, FlightRecorder, Record};
+use crate::relay::{RelayFrame, VOICE_CHANNEL};
+use crate::session::{Ended, ScheduleEvent, Session, SetOwner, MAX_MIRRORS, MAX_SUBSCRIBED_KINDS};
+use crate::store::{SessionStore, StoreError, StoreWriter};
+use crate::transport::{
+    Encoding, Frame, PeerTransport, WebSocketTransport, CLOSE_IDLE, CLOSE_POLICY, CLOSE_PROTOCOL,
+    MSGPACK_PROTOCOL,
+};
+use dashmap::mapref::entry::Entry;
+use dashmap::mapref::one::RefMut;
+use dashmap::DashMap;
+use futures_channel::mpsc::channel;
+use futures_channel::mpsc::Receiver;
+use futures_util::{FutureExt, StreamExt};
+use metrics::{counter, gauge, histogram};
+use rand::Rng;
+use real_ip::real_ip;
+use std::any::Any;
+use std::net::IpAddr;
+use std::ops::ControlFlow;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{
+    HeaderValue, ORIGIN, SEC_WEBSOCKET_PROTOCOL, USER_AGENT,
+};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+type Sessions = InstrumentedMap<String, Session>;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum SyncCommand<'a> {
+    Create {
+        /// Name of the session, a short code is generated by the server when omitted
+        #[serde(default, borrow)]
+        session: Option<&'a str>,
+        /// Token to reclaim the session with, generated by the server when omitted
+        #[serde(default, borrow)]
+        token: Option<&'a str>,
+        // the settings are only changed when a reclaimed session is created again if they're sent,
+        // `null` resets the optional ones
+        /// Whether the session is included in the session list
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public: Option<bool>,
+        /// Password that clients need to join the session
+        #[serde(
+            default,
+            borrow,
+            deserialize_with = "sent_or_null",
+            skip_serializing_if = "Option::is_none"
+        )]
+        password: Option<Option<&'a str>>,
+        /// Ignore ticks that go backwards, the owner has to seek to go back
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        monotonic: Option<bool>,
+        /// Seconds the session is kept after the owner left, limited by the server
+        #[serde(
+            default,
+            deserialize_with = "sent_or_null",
+            skip_serializing_if = "Option::is_none"
+        )]
+        timeout: Option<Option<u64>>,
+        /// Maximum number of clients that can join the session, limited by the server
+        #[serde(
+            default,
+            deserialize_with = "sent_or_null",
+            skip_serializing_if = "Option::is_none"
+        )]
+        max_clients: Option<Option<usize>>,
+        /// Log the owner commands so the owner can download them with `history`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log: Option<bool>,
+        /// Play the next demo of the queue once the current demo ended
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auto_advance: Option<bool>,
+    },
+    Join {
+        session: &'a str,
+        #[serde(default, borrow)]
+        password: Option<&'a str>,
+        /// Display name shown to the owner and the other clients
+        #[serde(default, borrow)]
+        name: Option<&'a str>,
+        /// Optional features the client supports, in addition to the capabilities negotiated with `hello`
+        #[serde(default, borrow)]
+        capabilities: Vec<&'a str>,
+    },
+    /// Stop receiving the messages of a joined session, other joined sessions are unaffected
+    Leave {
+        session: &'a str,
+    },
+    /// Only receive the given kinds of commands from a joined session, or all commands if `kinds` is null
+    Subscribe {
+        session: &'a str,
+        kinds: Option<Vec<String>>,
+    },
+    Tick {
+        session: &'a str,
+        tick: u64,
+        /// Unix timestamp in milliseconds at which the server received the tick, set by the server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        /// Ticks per second of the demo, as reported by the owner
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tickrate: Option<f64>,
+    },
+    /// An explicit jump to a position, unlike `tick` clients should flush their buffers instead of playing up to it
+    Seek {
+        session: &'a str,
+        tick: u64,
+    },
+    Play {
+        session: &'a str,
+        play: bool,
+    },
+    /// Seek and start or stop playing at the same time, so clients never apply only one of them
+    PlayAt {
+        session: &'a str,
+        tick: u64,
+        play: bool,
+    },
+    /// The demo played in the session, so clients know which demo to load
+    Demo {
+        session: &'a str,
+        demo: Option<DemoInfo>,
+    },
+    /// Add a demo to the end of the queue of the session
+    QueueAdd {
+        session: &'a str,
+        demo: DemoInfo,
+    },
+    /// Remove the demo at `index` from the queue of the session
+    QueueRemove {
+        session: &'a str,
+        index: usize,
+    },
+    /// Play the first demo from the queue, which is removed from the queue
+    Next {
+        session: &'a str,
+    },
+    /// The player the owner is spectating, `null` for a free camera
+    Pov {
+        session: &'a str,
+        entity_id: Option<u32>,
+    },
+    /// Repeat the region between two ticks, stored for clients joining later
+    Loop {
+        session: &'a str,
+        from_tick: u64,
+        to_tick: u64,
+        enabled: bool,
+    },
+    /// Flag a moment in the demo, send by the owner and stored for clients joining later
+    Marker {
+        session: &'a str,
+        tick: u64,
+        label: String,
+    },
+    /// Set the title and description of the session, `null` clears them
+    SetMeta {
+        session: &'a str,
+        #[serde(default)]
+        title: Option<String>,
+        #[serde(default)]
+        description: Option<String>,
+    },
+    /// Drawing data shown on top of the demo, not interpreted by the server
+    ///
+    /// A retained annotation is send to clients joining later and replaces the previously retained annotation,
+    /// retaining a `null` payload clears it
+    Annotate {
+        session: &'a str,
+        payload: serde_json::Value,
+        #[serde(default)]
+        retain: bool,
+    },
+    /// Let a client, identified by its connection id, send playback commands like the owner
+    Promote {
+        session: &'a str,
+        client: &'a str,
+    },
+    /// Stop a client from sending playback commands
+    Demote {
+        session: &'a str,
+        client: &'a str,
+    },
+    /// Remove a client from the session by its connection id, the client can't rejoin with the same connection
+    Kick {
+        session: &'a str,
+        client: &'a str,
+    },
+    Schedule {
+        session: &'a str,
+        start_at: Option<u64>,
+    },
+    /// Start playing after a number of seconds, all members start at the same server time
+    Countdown {
+        session: &'a str,
+        seconds: u64,
+        /// Unix timestamp in milliseconds at which the playback starts, set by the server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_at: Option<u64>,
+    },
+    React {
+        session: &'a str,
+        emote: &'a str,
+    },
+    Presence {
+        session: &'a str,
+    },
+    /// Request the current state of a joined session again, for clients that lost track of it
+    Resync {
+        session: &'a str,
+    },
+    /// Send by clients to report the tick they are at
+    Progress {
+        session: &'a str,
+        tick: u64,
+    },
+    Contest {
+        session: &'a str,
+    },
+    Rotate {
+        session: &'a str,
+        token: &'a str,
+        new_token: &'a str,
+    },
+    /// Hand the session to one of its clients, identified by its connection id
+    Transfer {
+        session: &'a str,
+        token: &'a str,
+        new_owner: &'a str,
+    },
+    /// Extension message relayed to the other session members without being interpreted
+    Ext {
+        session: &'a str,
+        kind: &'a str,
+        payload: serde_json::Value,
+    },
+    /// WebRTC signaling data, like SDP offers and ICE candidates, forwarded to a single other member of the session
+    Signal {
+        session: &'a str,
+        /// Connection id of the recipient
+        to: &'a str,
+        /// Connection id of the sender, set by the server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<String>,
+        payload: serde_json::Value,
+    },
+    /// A message encrypted by the clients, relayed between the members of an encrypted session without inspection
+    Sealed {
+        session: &'a str,
+        payload: String,
+    },
+    /// Set which messages of a session are encrypted, only clients that have the key can read them
+    Encryption {
+        session: &'a str,
+        mode: EncryptionMode,
+    },
+    /// Enable or disable the voice relay for a session
+    Voice {
+        session: &'a str,
+        enabled: bool,
+    },
+    /// Repeat the owner commands of the session in the target session, authorized by the owner token of the target
+    Mirror {
+        session: &'a str,
+        target: &'a str,
+        token: &'a str,
+        enabled: bool,
+    },
+    /// Rename a session, optionally redirecting joins for the old name for a while
+    Rename {
+        session: &'a str,
+        new_name: &'a str,
+        #[serde(default)]
+        redirect: bool,
+    },
+    /// Start or stop receiving the binary frames relayed on a channel
+    Listen {
+        session: &'a str,
+        channel: u16,
+        listen: bool,
+    },
+    Admin {
+        token: &'a str,
+        request: AdminRequest,
+    },
+    /// Remove a session immediately, authorized by the owner token
+    Close {
+        session: &'a str,
+        token: &'a str,
+    },
+    /// Request the list of public sessions
+    List,
+    /// Check whether a session exists before joining it
+    Exists {
+        session: &'a str,
+    },
+    /// Request the statistics of a session, only for the owner
+    Stats {
+        session: &'a str,
+    },
+    /// Request the logged commands of a session, only for the owner
+    History {
+        session: &'a str,
+    },
+    /// Protocol handshake send by the client after connecting, answered with the negotiated version and
+    /// the requested capabilities that the server supports
+    Hello {
+        version: u32,
+        #[serde(default, borrow)]
+        capabilities: Vec<&'a str>,
+    },
+    /// Request the server time, to estimate the clock offset and latency to the server
+    Time {
+        /// Value echoed back unchanged, usually the local time at which the request was send
+        client_echo: f64,
+    },
+}
+
+/// Messages that only the server sends, clients sending them are answered with an error
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum ServerEvent<'a> {
+    /// Send to a peer that became the owner of a session, either by creating it or by joining it with
+    /// `AUTO_CREATE`, with the owner token that can be used to reclaim it
+    Created {
+        session: &'a str,
+        token: &'a str,
+    },
+    /// The clients of a session with their names, send to the owner and the clients when it changes
+    Roster {
+        session: &'a str,
+        clients: Vec<RosterEntry>,
+    },
+    Clients {
+        session: &'a str,
+        count: usize,
+        idle: usize,
+        /// Connection ids of the clients, which the owner can use to kick them
+        #[serde(default)]
+        ids: Vec<String>,
+        #[serde(default)]
+        clients: Vec<ClientDetails>,
+    },
+    /// Send to a client that was kicked from a session by the owner
+    Kicked {
+        session: &'a str,
+    },
+    /// Send to a client that was promoted to co-owner or demoted again
+    Promoted {
+        session: &'a str,
+        promoted: bool,
+    },
+    Starting {
+        session: &'a str,
+        start_at: u64,
+        in_seconds: u64,
+    },
+    /// Playback reached the last tick of the demo
+    Ended {
+        session: &'a str,
+        tick: u64,
+    },
+    /// Send to the owner with the ticks reported by the clients, when they changed
+    ProgressReport {
+        session: &'a str,
+        /// Number of clients that reported their progress
+        clients: usize,
+        min: Option<u64>,
+        max: Option<u64>,
+        median: Option<u64>,
+    },
+    Reactions {
+        session: &'a str,
+        #[serde(borrow)]
+        counts: BTreeMap<&'a str, u32>,
+    },
+    Takeover {
+        session: &'a str,
+        /// Unix timestamp in milliseconds until which the takeover can be contested
+        contest_until: Option<u64>,
+    },
+    Rotated {
+        session: &'a str,
+        /// Unix timestamp in milliseconds until which the old token is still accepted
+        previous_valid_until: u64,
+    },
+    /// Send to the previous and the new owner after a transfer, only the new owner receives the new owner token
+    Transferred {
+        session: &'a str,
+        token: Option<&'a str>,
+    },
+    /// Send to the members of a session after it was renamed
+    Renamed {
+        session: &'a str,
+        new_name: &'a str,
+    },
+    AdminResponse {
+        data: serde_json::Value,
+    },
+    /// Send to the clients of a session when the owner disconnected, the session is removed after
+    /// `expires_in` seconds unless the owner reconnects
+    OwnerAway {
+        session: &'a str,
+        expires_in: u64,
+    },
+    /// Send to the clients of a session when the owner reconnected
+    OwnerReturned {
+        session: &'a str,
+    },
+    /// Send to the clients of a session without owner before it's removed
+    SessionExpiring {
+        session: &'a str,
+        in_seconds: u64,
+    },
+    /// Send to the clients of a session when it's removed
+    SessionClosed {
+        session: &'a str,
+        #[serde(default)]
+        reason: CloseReason,
+    },
+    /// Answer to an exists request, the other fields are only set if the session exists
+    Exists {
+        session: &'a str,
+        exists: bool,
+        #[serde(default)]
+        clients: usize,
+        #[serde(default)]
+        public: bool,
+        /// Whether a password is needed to join the session
+        #[serde(default)]
+        protected: bool,
+    },
+    /// Answer to a stats request
+    Stats {
+        session: &'a str,
+        stats: SessionStats,
+    },
+    /// Answer to a history request, with the logged commands oldest first
+    History {
+        session: &'a str,
+        commands: Vec<LoggedCommand>,
+        /// Number of commands dropped from the start of the log because it was full
+        dropped: u64,
+    },
+    /// The public sessions, with the most clients first
+    Sessions {
+        sessions: Vec<PublicSession>,
+    },
+    /// Answer to the hello of a client, with the negotiated version and the supported capabilities it requested
+    Hello {
+        version: u32,
+        #[serde(borrow)]
+        capabilities: Vec<&'a str>,
+    },
+    /// Answer to a time request
+    Time {
+        client_echo: f64,
+        /// Unix timestamp in milliseconds at which the request was handled
+        server_time: u64,
+    },
+    /// Send to every peer after connecting, the connection id can be used to find the logs for the connection
+    ServerInfo {
+        connection_id: &'a str,
+        version: &'a str,
+    },
+    Error {
+        code: ErrorCode,
+        #[serde(borrow)]
+        session: Option<&'a str>,
+        message: String,
+    },
+}
+
+impl SyncCommand<'_> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SyncCommand::Create { .. } => "create",
+            SyncCommand::Join { .. } => "join",
+            SyncCommand::Leave { .. } => "leave",
+            SyncCommand::Subscribe { .. } => "subscribe",
+            SyncCommand::Tick { .. } => "tick",
+            SyncCommand::Seek { .. } => "seek",
+            SyncCommand::Play { .. } => "play",
+            SyncCommand::PlayAt { .. } => "playat",
+            SyncCommand::Demo { .. } => "demo",
+            SyncCommand::QueueAdd { .. } => "queueadd",
+            SyncCommand::QueueRemove { .. } => "queueremove",
+            SyncCommand::Next { .. } => "next",
+            SyncCommand::Pov { .. } => "pov",
+            SyncCommand::Loop { .. } => "loop",
+            SyncCommand::Marker { .. } => "marker",
+            SyncCommand::SetMeta { .. } => "setmeta",
+            SyncCommand::Annotate { .. } => "annotate",
+            SyncCommand::Promote { .. } => "promote",
+            SyncCommand::Demote { .. } => "demote",
+            SyncCommand::Kick { .. } => "kick",
+            SyncCommand::Schedule { .. } => "schedule",
+            SyncCommand::Countdown { .. } => "countdown",
+            SyncCommand::React { .. } => "react",
+            SyncCommand::Presence { .. } => "presence",
+            SyncCommand::Resync { .. } => "resync",
+            SyncCommand::Progress { .. } => "progress",
+            SyncCommand::Contest { .. } => "contest",
+            SyncCommand::Rotate { .. } => "rotate",
+            SyncCommand::Transfer { .. } => "transfer",
+            SyncCommand::Ext { .. } => "ext",
+            SyncCommand::Listen { .. } => "listen",
+            SyncCommand::Voice { .. } => "voice",
+            SyncCommand::Signal { .. } => "signal",
+            SyncCommand::Sealed { .. } => "sealed",
+            SyncCommand::Encryption { .. } => "encryption",
+            SyncCommand::Mirror { .. } => "mirror",
+            SyncCommand::Rename { .. } => "rename",
+            SyncCommand::Close { .. } => "close",
+            SyncCommand::Admin { .. } => "admin",
+            SyncCommand::List => "list",
+            SyncCommand::Exists { .. } => "exists",
+            SyncCommand::Stats { .. } => "stats",
+            SyncCommand::History { .. } => "history",
+            SyncCommand::Hello { .. } => "hello",
+            SyncCommand::Time { .. } => "time",
+        }
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        match self {
+            SyncCommand::Create { session, .. } => *session,
+            SyncCommand::Join { session, .. }
+            | SyncCommand::Leave { session }
+            | SyncCommand::Subscribe { session, .. }
+            | SyncCommand::Tick { session, .. }
+            | SyncCommand::Seek { session, .. }
+            | SyncCommand::Play { session, .. }
+            | SyncCommand::PlayAt { session, .. }
+            | SyncCommand::Demo { session, .. }
+            | SyncCommand::QueueAdd { session, .. }
+            | SyncCommand::QueueRemove { session, .. }
+            | SyncCommand::Next { session }
+            | SyncCommand::Pov { session, .. }
+            | SyncCommand::Loop { session, .. }
+            | SyncCommand::Marker { session, .. }
+            | SyncCommand::SetMeta { session, .. }
+            | SyncCommand::Annotate { session, .. }
+            | SyncCommand::Promote { session, .. }
+            | SyncCommand::Demote { session, .. }
+            | SyncCommand::Kick { session, .. }
+            | SyncCommand::Schedule { session, .. }
+            | SyncCommand::Countdown { session, .. }
+            | SyncCommand::React { session, .. }
+            | SyncCommand::Presence { session }
+            | SyncCommand::Resync { session }
+            | SyncCommand::Progress { session, .. }
+            | SyncCommand::Contest { session }
+            | SyncCommand::Rotate { session, .. }
+            | SyncCommand::Transfer { session, .. }
+            | SyncCommand::Ext { session, .. }
+            | SyncCommand::Listen { session, .. }
+            | SyncCommand::Voice { session, .. }
+            | SyncCommand::Signal { session, .. }
+            | SyncCommand::Sealed { session, .. }
+            | SyncCommand::Encryption { session, .. }
+            | SyncCommand::Mirror { session, .. }
+            | SyncCommand::Rename { session, .. }
+            | SyncCommand::Close { session, .. }
+            | SyncCommand::Exists { session }
+            | SyncCommand::Stats { session }
+            | SyncCommand::History { session } => Some(session),
+            SyncCommand::Admin { .. }
+            | SyncCommand::List
+            | SyncCommand::Hello { .. }
+            | SyncCommand::Time { .. } => None,
+        }
+    }
+
+    /// All session names in the command, including the new name of a renamed session and the target of a mirror
+    fn session_names(&self) -> impl Iterator<Item = &str> {
+        let other = match self {
+            SyncCommand::Rename { new_name, .. } => Some(*new_name),
+            SyncCommand::Mirror { target, .. } => Some(*target),
+            _ => None,
+        };
+        self.session().into_iter().chain(other)
+    }
+
+    /// Commands that drive the playback, which co-owners can send as well
+    fn is_playback(&self) -> bool {
+        matches!(
+            self,
+            SyncCommand::Tick { .. }
+                | SyncCommand::Seek { .. }
+                | SyncCommand::Play { .. }
+                | SyncCommand::PlayAt { .. }
+                | SyncCommand::Pov { .. }
+                | SyncCommand::Loop { .. }
+                | SyncCommand::Marker { .. }
+        )
+    }
+
+    /// Stamp ticks with the time they were received, so clients can extrapolate the current tick
+    fn stamped(self, now: u64) -> Self {
+        match self {
+            SyncCommand::Tick {
+                session,
+                tick,
+                tickrate,
+                ..
+            } => SyncCommand::Tick {
+                session,
+                tick,
+                timestamp: Some(now),
+                tickrate,
+            },
+            SyncCommand::Countdown {
+                session, seconds, ..
+            } => SyncCommand::Countdown {
+                session,
+                seconds,
+                // saturating, the length of the countdown is only checked after stamping
+                start_at: Some(now.saturating_add(seconds.saturating_mul(1000))),
+            },
+            command => command,
+        }
+    }
+
+    /// The same state change for another session, `None` for commands that aren't mirrored
+    fn mirrored<'b>(&self, session: &'b str) -> Option<SyncCommand<'b>> {
+        match *self {
+            SyncCommand::Tick {
+                tick,
+                timestamp,
+                tickrate,
+                ..
+            } => Some(SyncCommand::Tick {
+                session,
+                tick,
+                timestamp,
+                tickrate,
+            }),
+            SyncCommand::Seek { tick, .. } => Some(SyncCommand::Seek { session, tick }),
+            SyncCommand::Play { play, .. } => Some(SyncCommand::Play { session, play }),
+            SyncCommand::PlayAt { tick, play, .. } => Some(SyncCommand::PlayAt {
+                session,
+                tick,
+                play,
+            }),
+            SyncCommand::Demo { ref demo, .. } => Some(SyncCommand::Demo {
+                session,
+                demo: demo.clone(),
+            }),
+            SyncCommand::Pov { entity_id, .. } => Some(SyncCommand::Pov { session, entity_id }),
+            SyncCommand::Loop {
+                from_tick,
+                to_tick,
+                enabled,
+                ..
+            } => Some(SyncCommand::Loop {
+                session,
+                from_tick,
+                to_tick,
+                enabled,
+            }),
+            SyncCommand::Marker {
+                tick, ref label, ..
+            } => Some(SyncCommand::Marker {
+                session,
+                tick,
+                label: label.clone(),
+            }),
+            SyncCommand::Annotate {
+                ref payload,
+                retain,
+                ..
+            } => Some(SyncCommand::Annotate {
+                session,
+                payload: payload.clone(),
+                retain,
+            }),
+            SyncCommand::Schedule { start_at, .. } => {
+                Some(SyncCommand::Schedule { session, start_at })
+            }
+            SyncCommand::Countdown {
+                seconds, start_at, ..
+            } => Some(SyncCommand::Countdown {
+                session,
+                seconds,
+                start_at,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl ServerEvent<'_> {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerEvent::Created { .. } => "created",
+            ServerEvent::Roster { .. } => "roster",
+            ServerEvent::Clients { .. } => "clients",
+            ServerEvent::Kicked { .. } => "kicked",
+            ServerEvent::Promoted { .. } => "promoted",
+            ServerEvent::Starting { .. } => "starting",
+            ServerEvent::Ended { .. } => "ended",
+            ServerEvent::ProgressReport { .. } => "progressreport",
+            ServerEvent::Reactions { .. } => "reactions",
+            ServerEvent::Takeover { .. } => "takeover",
+            ServerEvent::Rotated { .. } => "rotated",
+            ServerEvent::Transferred { .. } => "transferred",
+            ServerEvent::Renamed { .. } => "renamed",
+            ServerEvent::AdminResponse { .. } => "adminresponse",
+            ServerEvent::OwnerAway { .. } => "owneraway",
+            ServerEvent::OwnerReturned { .. } => "ownerreturned",
+            ServerEvent::SessionExpiring { .. } => "sessionexpiring",
+            ServerEvent::SessionClosed { .. } => "sessionclosed",
+            ServerEvent::Exists { .. } => "exists",
+            ServerEvent::Stats { .. } => "stats",
+            ServerEvent::History { .. } => "history",
+            ServerEvent::Sessions { .. } => "sessions",
+            ServerEvent::Hello { .. } => "hello",
+            ServerEvent::Time { .. } => "time",
+            ServerEvent::ServerInfo { .. } => "serverinfo",
+            ServerEvent::Error { .. } => "error",
+        }
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        match self {
+            ServerEvent::Created { session, .. }
+            | ServerEvent::Roster { session, .. }
+            | ServerEvent::Clients { session, .. }
+            | ServerEvent::Kicked { session }
+            | ServerEvent::Promoted { session, .. }
+            | ServerEvent::Starting { session, .. }
+            | ServerEvent::Ended { session, .. }
+            | ServerEvent::ProgressReport { session, .. }
+            | ServerEvent::Reactions { session, .. }
+            | ServerEvent::Takeover { session, .. }
+            | ServerEvent::Rotated { session, .. }
+            | ServerEvent::Transferred { session, .. }
+            | ServerEvent::Renamed { session, .. }
+            | ServerEvent::OwnerAway { session, .. }
+            | ServerEvent::OwnerReturned { session }
+            | ServerEvent::SessionExpiring { session, .. }
+            | ServerEvent::SessionClosed { session, .. }
+            | ServerEvent::Exists { session, .. }
+            | ServerEvent::Stats { session, .. }
+            | ServerEvent::History { session, .. } => Some(session),
+            ServerEvent::Error { session, .. } => *session,
+            ServerEvent::AdminResponse { .. }
+            | ServerEvent::Sessions { .. }
+            | ServerEvent::Hello { .. }
+            | ServerEvent::Time { .. }
+            | ServerEvent::ServerInfo { .. } => None,
+        }
+    }
+}
+
+/// A message send to peers, either a command relayed by the server or an event from the server itself
+pub trait Outgoing: Serialize {
+    fn kind(&self) -> &'static str;
+    fn session(&self) -> Option<&str>;
+}
+
+impl Outgoing for SyncCommand<'_> {
+    fn kind(&self) -> &'static str {
+        SyncCommand::kind(self)
+    }
+
+    fn session(&self) -> Option<&str> {
+        SyncCommand::session(self)
+    }
+}
+
+impl Outgoing for ServerEvent<'_> {
+    fn kind(&self) -> &'static str {
+        ServerEvent::kind(self)
+    }
+
+    fn session(&self) -> Option<&str> {
+        ServerEvent::session(self)
+    }
+}
+
+/// Version of the message protocol, increased on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version that is still supported
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// Optional features clients can ask for in the hello handshake
+pub const CAPABILITIES: &[&str] = &[
+    "relay",
+    "voice",
+    "encryption",
+    "markers",
+    "transfer",
+    "batch",
+    "signal",
+    "annotations",
+    "queue",
+    "loop",
+];
+
+/// Message kinds that are only send to clients that declared the capability to handle them
+const GATED_KINDS: &[(&str, &str)] = &[
+    ("annotate", "annotations"),
+    ("queueadd", "queue"),
+    ("queueremove", "queue"),
+    ("next", "queue"),
+    ("loop", "loop"),
+];
+
+/// The capability a client needs to receive messages of a kind
+fn required_capability(kind: &str) -> Option<&'static str> {
+    GATED_KINDS
+        .iter()
+        .find(|(gated, _)| *gated == kind)
+        .map(|(_, capability)| *capability)
+}
+
+/// Maximum length of the kind of extension messages
+const MAX_EXT_KIND_LENGTH: usize = 64;
+
+/// Maximum length of the url and map of a demo
+const MAX_DEMO_FIELD_LENGTH: usize = 512;
+
+/// The demo played in a session
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+#[serde(default)]
+pub struct DemoInfo {
+    /// Id of the demo on demos.tf
+    pub id: Option<u64>,
+    pub url: Option<String>,
+    pub map: Option<String>,
+    /// Length of the demo in seconds
+    pub duration: Option<f64>,
+    /// The last tick of the demo, ticks and seeks past it are clamped
+    pub max_tick: Option<u64>,
+    /// Pause the session once the last tick is reached
+    pub pause_at_end: bool,
+}
+
+impl DemoInfo {
+    fn is_too_large(&self) -> bool {
+        [&self.url, &self.map]
+            .into_iter()
+            .flatten()
+            .any(|field| field.len() > MAX_DEMO_FIELD_LENGTH)
+    }
+}
+
+/// Maximum length of a countdown
+const MAX_COUNTDOWN_SECONDS: u64 = 300;
+
+/// Why a session with the encryption mode refuses a cleartext command, `None` if the command is accepted
+fn encryption_refusal(mode: EncryptionMode, command: &SyncCommand) -> Option<&'static str> {
+    match (mode, command) {
+        (EncryptionMode::Off, _) => None,
+        // annotations are chat like, the server must not read them in any encrypted session
+        (_, SyncCommand::Annotate { .. }) => {
+            Some("this session is encrypted, send annotations as sealed messages")
+        }
+        (
+            EncryptionMode::Full,
+            SyncCommand::Play { .. }
+            | SyncCommand::PlayAt { .. }
+            | SyncCommand::Tick { .. }
+            | SyncCommand::Seek { .. }
+            | SyncCommand::Demo { .. }
+            | SyncCommand::QueueAdd { .. }
+            | SyncCommand::QueueRemove { .. }
+            | SyncCommand::Pov { .. }
+            | SyncCommand::Loop { .. }
+            | SyncCommand::Marker { .. }
+            | SyncCommand::Schedule { .. }
+            | SyncCommand::Countdown { .. },
+        ) => Some("the state of this session is encrypted, send it as sealed messages"),
+        _ => None,
+    }
+}
+
+/// Maximum length of a log capture, one day
+const MAX_CAPTURE_SECONDS: u64 = 24 * 60 * 60;
+
+/// Maximum length of the title of a session
+const MAX_TITLE_LENGTH: usize = 128;
+
+/// Maximum length of the description of a session
+const MAX_DESCRIPTION_LENGTH: usize = 1024;
+
+/// Maximum length of the label of a marker
+const MAX_MARKER_LABEL_LENGTH: usize = 128;
+
+/// A moment in the demo flagged by the owner
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Marker {
+    pub tick: u64,
+    pub label: String,
+}
+
+/// Maximum length of the display name of a client
+const MAX_NAME_LENGTH: usize = 32;
+
+/// A client in the roster of a session
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct RosterEntry {
+    /// Connection id of the client
+    pub id: String,
+    pub name: Option<String>,
+}
+
+/// Presence information about a client, send to the owner
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ClientDetails {
+    /// Connection id of the client
+    pub id: String,
+    pub name: Option<String>,
+    /// Unix timestamp in milliseconds at which the client joined
+    pub joined_at: u64,
+    /// The tick the client last reported to be at
+    pub tick: Option<u64>,
+    /// Whether the client stopped sending presence pings
+    pub idle: bool,
+    /// Round trip time of the connection in milliseconds, measured with websocket pings
+    #[serde(default)]
+    pub latency: Option<u64>,
+}
+
+/// Deserialize an optional field that can be left out (`None`) or sent as `null` (`Some(None)`)
+fn sent_or_null<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// The settings of a session chosen by the owner with `create`, `None` for settings that weren't sent
+struct CreateOptions<'a> {
+    public: Option<bool>,
+    password: Option<Option<&'a str>>,
+    monotonic: Option<bool>,
+    /// Time the session is kept after the owner left, instead of the configured timeout
+    timeout: Option<Option<Duration>>,
+    max_clients: Option<Option<usize>>,
+    /// Whether the owner commands are logged
+    log: Option<bool>,
+    auto_advance: Option<bool>,
+}
+
+/// Maximum number of sessions included in the session list
+const MAX_LISTED_SESSIONS: usize = 100;
+
+/// A session in the list of public sessions
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PublicSession {
+    pub name: String,
+    pub clients: usize,
+    /// Whether a password is needed to join the session
+    pub protected: bool,
+    /// The current tick, unknown for sessions with encrypted state
+    pub tick: Option<u64>,
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// Statistics of a running session
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SessionStats {
+    /// Unix timestamp in milliseconds, for restored sessions the time it was restored
+    pub created_at: u64,
+    pub clients: usize,
+    /// Highest number of clients connected at the same time
+    pub peak_clients: usize,
+    /// Number of times a client joined the session
+    pub joins: u64,
+    /// Number of messages send to the clients of the session
+    pub broadcasts: u64,
+    pub reactions: u64,
+}
+
+/// An owner command in the log of a session
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct LoggedCommand {
+    /// Unix timestamp in milliseconds at which the command was applied
+    pub at: u64,
+    pub command: serde_json::Value,
+}
+
+/// Why a session was removed
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The owner was gone for too long
+    #[default]
+    Expired,
+    /// Removed to make room for a new session of the same creator
+    Evicted,
+    /// Closed by the owner
+    Closed,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Expired => "expired",
+            CloseReason::Evicted => "evicted",
+            CloseReason::Closed => "closed",
+        }
+    }
+}
+
+/// Which messages of a session are end-to-end encrypted by the clients
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionMode {
+    #[default]
+    Off,
+    /// Extension messages have to be send as `sealed` messages, the playback state is still readable by the server
+    Payloads,
+    /// The playback state is also send in `sealed` messages, the server only relays them
+    Full,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    RateLimited,
+    InvalidToken,
+    Unauthorized,
+    /// The command was refused by a middleware
+    Rejected,
+    OwnershipContested,
+    ContestFailed,
+    PayloadTooLarge,
+    /// A request that is invalid or not supported by this server
+    InvalidRequest,
+    /// The owner reached the maximum number of sessions
+    TooManySessions,
+    /// The password for joining a session is missing or wrong
+    WrongPassword,
+    SessionNotFound,
+    /// A command that only the owner of the session can send
+    NotOwner,
+    /// The protocol version of the client is no longer supported
+    UnsupportedVersion,
+    /// The session reached its maximum number of clients
+    SessionFull,
+    /// A session name that is empty, too long or contains invalid characters
+    InvalidSessionName,
+}
+
+pub struct Server {
+    config: Config,
+    auth: Box<dyn AuthProvider>,
+    /// Only read from directly, writes go through the writer
+    store: Arc<dyn SessionStore>,
+    store_writer: StoreWriter,
+    middleware: Vec<Box<dyn Middleware>>,
+    id_counter: AtomicU64,
+    peers: Peers,
+    sessions: Sessions,
+    /// Ips that are refused new connections until the given time
+    penalties: DashMap<IpAddr, Instant>,
+    /// Sessions recently created from every ip
+    creations: DashMap<IpAddr, CreateCounter>,
+    events: EventBus,
+    recorder: FlightRecorder,
+    archive: Archive,
+    faults: Option<FaultInjector>,
+    drops: DropTracker,
+    egress: EgressLimiter,
+    /// Old names of renamed sessions with their new name, until the given time
+    redirects: DashMap<String, (String, Instant)>,
+    /// Highest number of frames queued for a single peer
+    queue_high_water: AtomicUsize,
+    log_capture: Option<Arc<LogCapture>>,
+    #[cfg(feature = "plugins")]
+    plugins: Option<plugin::PluginHost>,
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    Store(StoreError),
+    #[cfg(feature = "plugins")]
+    Plugin(plugin::PluginError),
+    #[cfg(not(feature = "plugins"))]
+    PluginsDisabled,
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::Store(e) => write!(f, "failed to open session store: {e}"),
+            #[cfg(feature = "plugins")]
+            BuildError::Plugin(e) => write!(f, "failed to load plugins: {e}"),
+            #[cfg(not(feature = "plugins"))]
+            BuildError::PluginsDisabled => {
+                write!(
+                    f,
+                    "a plugin directory is configured but plugin support is not enabled"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Assemble a server, with the authentication and storage from the config unless overwritten
+pub struct ServerBuilder {
+    config: Config,
+    auth: Option<Box<dyn AuthProvider>>,
+    store: Option<Box<dyn SessionStore>>,
+    middleware: Vec<Box<dyn Middleware>>,
+    log_capture: Option<Arc<LogCapture>>,
+}
+
+impl ServerBuilder {
+    pub fn new(config: Config) -> Self {
+        ServerBuilder {
+            config,
+            auth: None,
+            store: None,
+            middleware: Vec::new(),
+            log_capture: None,
+        }
+    }
+
+    pub fn auth<A: AuthProvider + 'static>(mut self, auth: A) -> Self {
+        self.auth = Some(Box::new(auth));
+        self
+    }
+
+    pub fn store<S: SessionStore + 'static>(mut self, store: S) -> Self {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// Add a middleware, middleware is invoked in the order it was added
+    pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Allow capturing the logs of single sessions with admin commands,
+    /// the [`CaptureLayer`](capture::CaptureLayer) of the capture has to be installed in the tracing subscriber
+    pub fn log_capture(mut self, capture: Arc<LogCapture>) -> Self {
+        self.log_capture = Some(capture);
+        self
+    }
+
+    pub fn build(self) -> Result<Server, BuildError> {
+        let store = match self.store {
+            Some(store) => store,
+            None => self
+                .config
+                .store
+                .open(self.config.store_keys.as_ref())
+                .map_err(BuildError::Store)?,
+        };
+        let auth = self.auth.unwrap_or_else(|| self.config.auth.provider());
+        #[cfg(feature = "plugins")]
+        let plugins = match &self.config.plugins.dir {
+            Some(dir) => Some(
+                plugin::PluginHost::load(dir, &self.config.plugins).map_err(BuildError::Plugin)?,
+            ),
+            None => None,
+        };
+        #[cfg(not(feature = "plugins"))]
+        if self.config.plugins.dir.is_some() {
+            return Err(BuildError::PluginsDisabled);
+        }
+
+        let server = Server::new(self.config, auth, store, self.middleware);
+        let server = Server {
+            log_capture: self.log_capture,
+            ..server
+        };
+        #[cfg(feature = "plugins")]
+        let server = Server { plugins, ..server };
+        Ok(server)
+    }
+}
+
+impl Server {
+    fn new(
+        config: Config,
+        auth: Box<dyn AuthProvider>,
+        store: Box<dyn SessionStore>,
+        middleware: Vec<Box<dyn Middleware>>,
+    ) -> Self {
+        let store: Arc<dyn SessionStore> = Arc::from(store);
+        Server {
+            recorder: FlightRecorder::new(config.recorder_size),
+            archive: Archive::new(config.archive_retention),
+            faults: config.faults.as_ref().map(FaultInjector::new),
+            drops: DropTracker::new(config.drop_alert_rate),
+            egress: EgressLimiter::new(config.egress_peer_rate, config.egress_session_rate),
+            redirects: DashMap::new(),
+            queue_high_water: AtomicUsize::default(),
+            auth,
+            store_writer: StoreWriter::spawn(store.clone()),
+            store,
+            middleware,
+            config,
+            id_counter: AtomicU64::default(),
+            peers: Peers::with_capacity(128),
+            sessions: Sessions::with_capacity("sessions", 64),
+            penalties: DashMap::new(),
+            creations: DashMap::new(),
+            events: EventBus::new(EVENT_CAPACITY),
+            log_capture: None,
+            #[cfg(feature = "plugins")]
+            plugins: None,
+        }
+    }
+
+    /// Get a stream of everything happening on the server
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Allocate the id for a new peer
+    pub fn next_peer_id(&self) -> u64 {
+        self.id_counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn send_text<S: Into<String>>(
+        &self,
+        peer: &PeerId,
+        session: Option<&str>,
+        priority: Priority,
+        text: S,
+    ) {
+        self.send_frame(peer, session, priority, Frame::Text(text.into()));
+    }
+
+    /// Queue a frame for a peer, keeping track of queue depths and dropped frames
+    ///
+    /// Frames are shed by priority when the outbound budget of the peer or session is exhausted
+    fn send_frame(&self, peer: &PeerId, session: Option<&str>, priority: Priority, frame: Frame) {
+        let size = frame.size();
+        if let Err(scope) = self
+            .egress
+            .check(peer, session, size, priority, Instant::now())
+        {
+            debug!(%peer, session, scope = scope.as_str(), size, "outbound budget exhausted, shedding frame");
+            counter!(telemetry::EGRESS_SHED, "scope" => scope.as_str(), "priority" => priority.as_str())
+                .increment(1);
+            counter!(telemetry::EGRESS_SHED_BYTES, "scope" => scope.as_str())
+                .increment(size as u64);
+            return;
+        }
+        match self.peers.send(peer, frame) {
+            Ok(Some(queued)) => {
+                gauge!(telemetry::QUEUED_FRAMES).increment(1);
+                if self.queue_high_water.fetch_max(queued, Ordering::Relaxed) < queued {
+                    gauge!(telemetry::QUEUE_HIGH_WATER).set(queued as f64);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let reason = if e.is_full() {
+                    "queue_full"
+                } else {
+                    "disconnected"
+                };
+                error!(%peer, reason, "failed to send message to client");
+                counter!(telemetry::DROPPED_FRAMES, "reason" => reason).increment(1);
+                let Some(session) = session else {
+                    return;
+                };
+                if let Some(dropped) = self.drops.record(session, Instant::now()) {
+                    warn!(session, dropped, "session is dropping messages");
+                    self.events.publish(|| Event::MessagesDropped {
+                        session: session.to_string(),
+                        dropped,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Add a message to the flight recorder, with the connection id of the peer so replays can refer to it
+    fn record(
+        &self,
+        session: &str,
+        direction: Direction,
+        peer: Option<&PeerId>,
+        command: &impl Serialize,
+    ) {
+        if self.recorder.is_enabled() {
+            let peer = peer.map(|peer| (peer, self.peers.connection_id(peer)));
+            self.recorder.record(session, direction, peer, command);
+        }
+    }
+
+    pub fn send_command<M: Outgoing>(&self, peer: &PeerId, command: &M) {
+        if let Some(session) = command.session() {
+            self.record(session, Direction::Out, Some(peer), command);
+        }
+        self.send_text(
+            peer,
+            command.session(),
+            Priority::of(command.kind()),
+            serde_json::to_string(command).unwrap(),
+        )
+    }
+
+    fn send_to_owner<M: Outgoing>(&self, session: &Session, command: &M) {
+        if let Some(owner) = session.owner() {
+            self.send_command(&owner, command);
+        }
+    }
+
+    /// Apply an owner command to the session and send it to the clients, unless it didn't change anything
+    fn apply_owner_command(&self, session: &mut Session, command: &SyncCommand) -> bool {
+        // clients get the position the session was clamped to, not the one past the end of the demo
+        let clamped = session.clamp(command);
+        let command = clamped.as_ref().unwrap_or(command);
+        let changed = session.handle_command(command);
+        if changed {
+            // only commands that changed the state, so replaying the log reproduces the session
+            session.log_command(command, self.config.max_command_log);
+            self.send_to_clients(session, command);
+        } else {
+            counter!(telemetry::SUPPRESSED_BROADCASTS, "kind" => command.kind()).increment(1);
+        }
+        if let Some(ended) = session.take_ended() {
+            self.send_ended(session, ended);
+        }
+        changed
+    }
+
+    /// Tell the owner and clients that playback reached the end of the demo
+    fn send_ended(&self, session: &Session, ended: Ended) {
+        debug!(session = session.token, tick = ended.tick, "demo ended");
+        if ended.paused {
+            let command = SyncCommand::Play {
+                session: &session.token,
+                play: false,
+            };
+            self.send_to_owner(session, &command);
+            self.send_to_clients(session, &command);
+        }
+        let event = ServerEvent::Ended {
+            session: &session.token,
+            tick: ended.tick,
+        };
+        self.send_to_owner(session, &event);
+        self.send_to_clients(session, &event);
+    }
+
+    fn send_to_clients<M: Outgoing>(&self, session: &Session, command: &M) {
+        self.recorder
+            .record(&session.token, Direction::Out, None, command);
+        session.count_broadcast();
+        let command_text = serde_json::to_string(command).unwrap();
+        let priority = Priority::of(command.kind());
+        let batching = batch::collecting();
+        let capability = required_capability(command.kind());
+        let mut recipients = 0;
+        for peer in session.subscribers(command.kind()) {
+            if capability.is_some_and(|capability| !self.peers.has_capability(peer, capability)) {
+                continue;
+            }
+            recipients += 1;
+            if batching && self.peers.has_capability(peer, "batch") {
+                batch::defer(peer, &session.token, priority, &command_text);
+            } else {
+                self.send_text(peer, Some(&session.token), priority, &command_text);
+            }
+        }
+        counter!(telemetry::BROADCASTS, "kind" => command.kind()).increment(1);
+        self.events.publish(|| Event::CommandBroadcast {
+            session: session.token.clone(),
+            kind: command.kind(),
+            recipients,
+        });
+    }
+
+    /// Whether a peer can handle messages of a kind, based on the capabilities it declared
+    fn accepts(&self, peer: &PeerId, kind: &str) -> bool {
+        required_capability(kind)
+            .is_none_or(|capability| self.peers.has_capability(peer, capability))
+    }
+
+    pub fn send_error(
+        &self,
+        peer: &PeerId,
+        code: ErrorCode,
+        session: Option<&str>,
+        message: String,
+    ) {
+        self.send_command(
+            peer,
+            &ServerEvent::Error {
+                code,
+                session,
+                message,
+            },
+        )
+    }
+
+    /// The public sessions with the most clients
+    fn public_sessions(&self) -> Vec<PublicSession> {
+        let mut sessions: Vec<PublicSession> = self
+            .sessions
+            .iter()
+            .filter_map(|session| session.listing())
+            .collect();
+        sessions.sort_by(|a, b| b.clients.cmp(&a.clients).then_with(|| a.name.cmp(&b.name)));
+        sessions.truncate(MAX_LISTED_SESSIONS);
+        sessions
+    }
+
+    /// Tell a peer that the session it send a command for doesn't exist
+    fn send_session_not_found(&self, peer: &PeerId, session: &str) {
+        debug!(%peer, session, "session not found for command");
+        self.send_error(
+            peer,
+            ErrorCode::SessionNotFound,
+            Some(session),
+            format!("session {session} doesn't exist"),
+        )
+    }
+
+    /// report the number of (idle) clients to the session owner
+    fn send_client_count(&self, session: &mut Session) {
+        let now = Instant::now();
+        let idle = session.idle_count(now, self.config.presence_timeout);
+        session.update_reported_idle(idle);
+        self.send_to_owner(
+            session,
+            &ServerEvent::Clients {
+                session: &session.token,
+                count: session.clients().count(),
+                idle,
+                ids: session
+                    .clients()
+                    .filter_map(|client| self.peers.connection_id(client))
+                    .collect(),
+                clients: session
+                    .client_details(now, self.config.presence_timeout, |client| {
+                        self.peers.connection_id(client)
+                    })
+                    .collect(),
+            },
+        )
+    }
+
+    /// Record a protocol violation, closing the connection if the peer keeps misbehaving
+    fn record_violation(&self, peer: &PeerId, violation: Violation) -> ControlFlow<()> {
+        let policy = &self.config.flood_policy;
+        let now = Instant::now();
+        if !self.peers.record_violation(peer, violation, policy, now) {
+            return ControlFlow::Continue(());
+        }
+
+        warn!(%peer, ?violation, "disconnecting flooding peer");
+        self.penalties.insert(peer.ip(), now + policy.penalty);
+        let close = Frame::Close {
+            code: CLOSE_POLICY,
+            reason: "too many invalid or rate limited messages".into(),
+        };
+        self.send_frame(peer, None, Priority::Essential, close);
+        ControlFlow::Break(())
+    }
+
+    fn rate_limit(&self, peer: &PeerId, kind: &str) -> RateLimit {
+        self.peers
+            .rate_limit(peer, kind)
+            .unwrap_or_else(|| self.config.rate_limits.get(kind))
+    }
+
+    fn is_penalized(&self, ip: &IpAddr) -> bool {
+        self.penalties
+            .get(ip)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    fn handle_frame(&self, frame: Frame, peer_id: PeerId) -> ControlFlow<()> {
+        let message = match frame {
+            Frame::Text(message) => message,
+            Frame::Binary(data) => return self.handle_binary(data, peer_id),
+            Frame::Pong(data) => {
+                self.record_latency(&peer_id, &data);
+                return ControlFlow::Continue(());
+            }
+            Frame::Ping(_) | Frame::Close { .. } => return ControlFlow::Continue(()),
+        };
+        if batch::is_batch(&message) {
+            return self.handle_batch(&message, peer_id);
+        }
+        match serde_json::from_str::<SyncCommand>(&message) {
+            Ok(command) => self.dispatch_command(command, peer_id),
+            Err(e) => {
+                if let Ok(event) = serde_json::from_str::<ServerEvent>(&message) {
+                    let kind = event.kind();
+                    debug!(sender = %peer_id, kind, "client sent a server event");
+                    self.send_error(
+                        &peer_id,
+                        ErrorCode::InvalidRequest,
+                        event.session(),
+                        format!("{kind} messages can only be send by the server"),
+                    );
+                } else {
+                    warn!(sender = %peer_id, message, error = %e, "Error while decoding message");
+                }
+                counter!(telemetry::DECODE_ERRORS).increment(1);
+                self.record_violation(&peer_id, Violation::DecodeError)
+            }
+        }
+    }
+
+    /// Handle the commands of a batch in order, sending the resulting broadcasts as a single frame per client
+    fn handle_batch(&self, message: &str, peer_id: PeerId) -> ControlFlow<()> {
+        let commands = match serde_json::from_str::<Vec<SyncCommand>>(message) {
+            Ok(commands) => commands,
+            Err(e) => {
+                warn!(sender = %peer_id, message, error = %e, "Error while decoding batch");
+                counter!(telemetry::DECODE_ERRORS).increment(1);
+                return self.record_violation(&peer_id, Violation::DecodeError);
+            }
+        };
+        if commands.len() > MAX_BATCH_SIZE {
+            self.send_error(
+                &peer_id,
+                ErrorCode::InvalidRequest,
+                None,
+                format!("a batch can contain at most {MAX_BATCH_SIZE} commands"),
+            );
+            return ControlFlow::Continue(());
+        }
+
+        let collecting = batch::collect();
+        let result = commands
+            .into_iter()
+            .try_for_each(|command| self.dispatch_command(command, peer_id));
+        for frame in collecting.finish() {
+            self.send_text(
+                &frame.peer,
+                Some(&frame.session),
+                frame.priority,
+                frame.text,
+            );
+        }
+        result
+    }
+
+    fn dispatch_command(&self, command: SyncCommand, peer_id: PeerId) -> ControlFlow<()> {
+        let kind = command.kind();
+        let span = info_span!("command", kind, session = command.session());
+        let _entered = span.enter();
+        debug!(sender = %peer_id, message = ?command, "Received a message");
+        let start = Instant::now();
+        counter!(telemetry::COMMANDS, "kind" => kind).increment(1);
+        self.peers.touch(&peer_id, start);
+        let result = self.handle_command(command, peer_id);
+        histogram!(telemetry::COMMAND_DURATION, "kind" => kind).record(start.elapsed());
+        result
+    }
+
+    /// Forward a relay frame from the session owner to the clients listening on the channel
+    fn handle_binary(&self, data: Vec<u8>, sender: PeerId) -> ControlFlow<()> {
+        let Some(frame) = RelayFrame::parse(&data) else {
+            warn!(%sender, length = data.len(), "invalid binary frame");
+            return self.record_violation(&sender, Violation::DecodeError);
+        };
+        let now = Instant::now();
+        self.peers.touch(&sender, now);
+        let limit = self.rate_limit(&sender, "relay");
+        match self.peers.check_rate(&sender, "relay", limit, now) {
+            RateLimitResult::Allowed => {}
+            RateLimitResult::Limited | RateLimitResult::StillLimited => {
+                counter!(telemetry::RATE_LIMITED, "kind" => "relay").increment(1);
+                return self.record_violation(&sender, Violation::RateLimited);
+            }
+        }
+        if frame.payload.len() > self.config.relay_max_frame {
+            self.send_error(
+                &sender,
+                ErrorCode::PayloadTooLarge,
+                Some(frame.session),
+                format!(
+                    "relayed frames are limited to {} bytes",
+                    self.config.relay_max_frame
+                ),
+            );
+            return ControlFlow::Continue(());
+        }
+
+        let Some(mut session) = self.sessions.get_mut(frame.session) else {
+            return ControlFlow::Continue(());
+        };
+        if !session.is_owner(&sender) {
+            debug!(%sender, session = frame.session, "dropping relay frame from non owner");
+            return ControlFlow::Continue(());
+        }
+        let voice = frame.channel == VOICE_CHANNEL;
+        if voice && !session.voice_enabled() {
+            debug!(%sender, session = frame.session, "dropping voice frame, voice is disabled");
+            return ControlFlow::Continue(());
+        }
+        let (limit, quota) = if voice {
+            let limit = self.config.voice_rate;
+            (limit, session.check_voice(data.len(), limit, now))
+        } else {
+            let limit = self.config.relay_rate;
+            (limit, session.check_relay(data.len(), limit, now))
+        };
+        match quota {
+            RateLimitResult::Allowed => {
+                let recipients: Vec<PeerId> = if voice {
+                    session.clients().copied().collect()
+                } else {
+                    session.listeners(frame.channel).copied().collect()
+                };
+                for peer in recipients {
+                    self.send_frame(
+                        &peer,
+                        Some(frame.session),
+                        Priority::Bulk,
+                        Frame::Binary(data.clone()),
+                    );
+                }
+            }
+            RateLimitResult::Limited => self.send_error(
+                &sender,
+                ErrorCode::RateLimited,
+                Some(frame.session),
+                format!(
+                    "relay quota exceeded, at most {} bytes per second are allowed",
+                    limit.rate
+                ),
+            ),
+            RateLimitResult::StillLimited => {}
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Check if a peer is allowed to create a session, notifying it if it isn't
+    fn authorize_create(&self, session_name: &str, sender: PeerId) -> bool {
+        let identity = self.peers.identity(&sender).unwrap_or(Identity::Anonymous);
+        if let Err(error) = self.auth.authorize_create(&identity, session_name) {
+            warn!(%sender, %identity, session = session_name, %error, "session creation refused");
+            self.send_error(
+                &sender,
+                ErrorCode::Unauthorized,
+                Some(session_name),
+                error.to_string(),
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Create a session for a peer joining a session that doesn't exist, in lobby mode
+    fn handle_auto_create(&self, session_name: &str, password: Option<&str>, sender: PeerId) {
+        let creator = self.creator_key(&sender);
+        if !self.authorize_create(session_name, sender)
+            || !self.enforce_create_rate(session_name, sender)
+            || !self.enforce_session_limit(&creator, session_name, sender)
+        {
+            return;
+        }
+        let token = generate_token();
+        let Entry::Vacant(entry) = self.sessions.entry(session_name.to_string()) else {
+            debug!(%sender, session = session_name, "session was created before it could be auto created");
+            return;
+        };
+        let mut session = entry.insert(
+            Session::new(sender, session_name.into(), token.clone())
+                .with_creator(creator)
+                .with_password(password.map(String::from)),
+        );
+        self.persist(&mut session);
+        drop(session);
+        info!(session = session_name, owner = %sender, "session auto created on join");
+        self.events.publish(|| Event::SessionCreated {
+            session: session_name.into(),
+            owner: sender,
+        });
+        self.send_command(
+            &sender,
+            &ServerEvent::Created {
+                session: session_name,
+                token: &token,
+            },
+        );
+    }
+
+    /// Sessions are limited per authenticated user, or per ip for anonymous peers
+    fn creator_key(&self, peer: &PeerId) -> String {
+        match self.peers.identity(peer) {
+            Some(Identity::User(name)) => format!("user:{name}"),
+            _ => format!("ip:{}", peer.ip()),
+        }
+    }
+
+    /// Count a new session towards the limit of the ip of the peer
+    ///
+    /// Notifies the peer if it can't create the session
+    fn enforce_create_rate(&self, session_name: &str, sender: PeerId) -> bool {
+        let policy = &self.config.create_policy;
+        if policy.max_sessions == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let result = self
+            .creations
+            .entry(sender.ip())
+            .or_insert_with(|| CreateCounter::new(now))
+            .record(policy, now);
+        let Err(until) = result else {
+            return true;
+        };
+        let in_seconds = until.duration_since(now).as_secs().max(1);
+        warn!(%sender, session = session_name, in_seconds, "session creation limit of ip reached");
+        self.send_error(
+            &sender,
+            ErrorCode::TooManySessions,
+            Some(session_name),
+            format!(
+                "too many sessions created from your address, try again in {in_seconds} seconds"
+            ),
+        );
+        false
+    }
+
+    /// Check if a peer can create another session, evicting an abandoned session if configured
+    ///
+    /// Notifies the peer if it can't create the session
+    fn enforce_session_limit(&self, creator: &str, session_name: &str, sender: PeerId) -> bool {
+        let max = self.config.max_owner_sessions;
+        if max == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut count = 0;
+        let mut most_idle: Option<(String, Duration)> = None;
+        for session in self.sessions.iter() {
+            if session.creator() != Some(creator) {
+                continue;
+            }
+            count += 1;
+            if let Some(inactive) = session.inactive_time(now) {
+                if most_idle.as_ref().is_none_or(|(_, most)| inactive > *most) {
+                    most_idle = Some((session.token.clone(), inactive));
+                }
+            }
+        }
+        if count < max {
+            return true;
+        }
+        if let (true, Some((idle_session, _))) = (self.config.evict_idle_sessions, most_idle) {
+            info!(
+                session = idle_session,
+                creator, "evicting idle session for new session"
+            );
+            if let Some((_, session)) = self.sessions.remove(&idle_session) {
+                self.cleanup_session(&session, CloseReason::Evicted);
+            }
+            return true;
+        }
+        warn!(%sender, creator, session = session_name, "session limit reached");
+        self.send_error(
+            &sender,
+            ErrorCode::TooManySessions,
+            Some(session_name),
+            format!("at most {max} sessions can be created"),
+        );
+        false
+    }
+
+    fn handle_create(
+        &self,
+        session_name: &str,
+        token: &str,
+        options: CreateOptions,
+        sender: PeerId,
+    ) {
+        let CreateOptions {
+            public,
+            password,
+            monotonic,
+            timeout,
+            max_clients,
+            log,
+            auto_advance,
+        } = options;
+        if !self.authorize_create(session_name, sender) {
+            return;
+        }
+        let creator = self.creator_key(&sender);
+        if !self.sessions.contains_key(session_name)
+            && (!self.enforce_create_rate(session_name, sender)
+                || !self.enforce_session_limit(&creator, session_name, sender))
+        {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut session = match self.sessions.entry(session_name.to_string()) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                let mut session = entry.insert(
+                    Session::new(sender, session_name.into(), token.into())
+                        .with_creator(creator)
+                        .with_public(public.unwrap_or_default())
+                        .with_password(password.flatten().map(String::from))
+                        .with_monotonic(monotonic.unwrap_or_default())
+                        .with_timeout(timeout.flatten())
+                        .with_max_clients(max_clients.flatten())
+                        .with_log(log.unwrap_or_default())
+                        .with_auto_advance(auto_advance.unwrap_or_default()),
+                );
+                self.persist(&mut session);
+                self.events.publish(|| Event::SessionCreated {
+                    session: session_name.into(),
+                    owner: sender,
+                });
+                self.send_command(
+                    &sender,
+                    &ServerEvent::Created {
+                        session: session_name,
+                        token,
+                    },
+                );
+                return;
+            }
+        };
+
+        let accepted = match session.set_owner(sender, token, now) {
+            SetOwner::Rejected => {
+                warn!(%sender, token, "invalid owner token");
+                self.send_error(
+                    &sender,
+                    ErrorCode::InvalidToken,
+                    Some(session_name),
+                    "session already exists with a different token".into(),
+                );
+                false
+            }
+            SetOwner::Locked => {
+                warn!(%sender, session = session_name, "takeover refused after contest");
+                self.send_error(
+                    &sender,
+                    ErrorCode::OwnershipContested,
+                    Some(session_name),
+                    "the owner recently contested a takeover of this session".into(),
+                );
+                false
+            }
+            SetOwner::Unchanged => true,
+            SetOwner::Claimed => {
+                info!(session = session_name, owner = %sender, "session claimed");
+                self.send_to_clients(
+                    &session,
+                    &ServerEvent::OwnerReturned {
+                        session: session_name,
+                    },
+                );
+                self.send_client_count(&mut session);
+                true
+            }
+            SetOwner::Changed { previous } => {
+                info!(
+                    target: "audit",
+                    session = session_name,
+                    previous = %previous,
+                    owner = %sender,
+                    "session ownership taken over"
+                );
+                let contest_until = (self.peers.contains(&previous)
+                    && !self.config.takeover_grace.is_zero())
+                .then(|| unix_millis() + self.config.takeover_grace.as_millis() as u64);
+                self.send_command(
+                    &previous,
+                    &ServerEvent::Takeover {
+                        session: session_name,
+                        contest_until,
+                    },
+                );
+                self.send_client_count(&mut session);
+                true
+            }
+        };
+        if accepted {
+            if let Some(public) = public {
+                session.set_public(public);
+            }
+            if let Some(password) = password {
+                session.set_password(password.map(String::from));
+            }
+            if let Some(monotonic) = monotonic {
+                session.set_monotonic(monotonic);
+            }
+            if let Some(timeout) = timeout {
+                session.set_timeout(timeout);
+            }
+            if let Some(max_clients) = max_clients {
+                session.set_max_clients(max_clients);
+            }
+            if let Some(log) = log {
+                session.set_log(log);
+            }
+            if let Some(auto_advance) = auto_advance {
+                session.set_auto_advance(auto_advance);
+            }
+            self.send_command(
+                &sender,
+                &ServerEvent::Created {
+                    session: session_name,
+                    token,
+                },
+            );
+        }
+    }
+
+    /// Negotiate the protocol version, clients that only speak versions older than supported are disconnected
+    fn handle_hello(&self, sender: PeerId, version: u32, capabilities: &[&str]) -> ControlFlow<()> {
+        if version < MIN_PROTOCOL_VERSION {
+            info!(%sender, version, "unsupported protocol version");
+            self.send_error(
+                &sender,
+                ErrorCode::UnsupportedVersion,
+                None,
+                format!("protocol version {version} is not supported, at least version {MIN_PROTOCOL_VERSION} is required"),
+            );
+            let close = Frame::Close {
+                code: CLOSE_PROTOCOL,
+                reason: "unsupported protocol version".into(),
+            };
+            self.send_frame(&sender, None, Priority::Essential, close);
+            return ControlFlow::Break(());
+        }
+
+        // newer clients are downgraded to the version of the server
+        let version = version.min(PROTOCOL_VERSION);
+        let capabilities: Vec<&str> = capabilities
+            .iter()
+            .copied()
+            .filter(|capability| CAPABILITIES.contains(capability))
+            .collect();
+        debug!(%sender, version, ?capabilities, "protocol negotiated");
+        self.peers.set_protocol(
+            &sender,
+            version,
+            capabilities
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect(),
+        );
+        self.send_command(
+            &sender,
+            &ServerEvent::Hello {
+                version,
+                capabilities,
+            },
+        );
+        ControlFlow::Continue(())
+    }
+
+    fn handle_command(&self, command: SyncCommand, sender: PeerId) -> ControlFlow<()> {
+        self.handle_command_at(command, sender, unix_millis())
+    }
+
+    /// Handle a command received at `now`, in unix milliseconds
+    fn handle_command_at(&self, command: SyncCommand, sender: PeerId, now: u64) -> ControlFlow<()> {
+        let command = command.stamped(now);
+        let kind = command.kind();
+        let limit = self.rate_limit(&sender, kind);
+        match self.peers.check_rate(&sender, kind, limit, Instant::now()) {
+            RateLimitResult::Allowed => {}
+            RateLimitResult::Limited => {
+                warn!(%sender, kind, "peer is being rate limited");
+                counter!(telemetry::RATE_LIMITED, "kind" => kind).increment(1);
+                self.send_error(
+                    &sender,
+                    ErrorCode::RateLimited,
+                    command.session(),
+                    format!(
+                        "too many {kind} commands, at most {} per second are allowed",
+                        limit.rate
+                    ),
+                );
+                return self.record_violation(&sender, Violation::RateLimited);
+            }
+            RateLimitResult::StillLimited => {
+                counter!(telemetry::RATE_LIMITED, "kind" => kind).increment(1);
+                return self.record_violation(&sender, Violation::RateLimited);
+            }
+        }
+        // checked before recording, so the recorder is never keyed by invalid names
+        if let Err(message) = command.session_names().try_for_each(session::validate_name) {
+            debug!(%sender, kind, "invalid session name");
+            self.send_error(&sender, ErrorCode::InvalidSessionName, None, message);
+            return ControlFlow::Continue(());
+        }
+        if let Some(session) = command.session() {
+            self.record(session, Direction::In, Some(&sender), &command);
+        }
+
+        if let Err(rejection) = self
+            .middleware
+            .iter()
+            .try_for_each(|middleware| middleware.before_command(&sender, &command))
+        {
+            debug!(%sender, kind, code = ?rejection.code, "command rejected by middleware");
+            self.send_error(
+                &sender,
+                rejection.code,
+                command.session(),
+                rejection.message,
+            );
+            return ControlFlow::Continue(());
+        }
+
+        match &command {
+            SyncCommand::Create {
+                session,
+                token,
+                public,
+                password,
+                monotonic,
+                timeout,
+                max_clients,
+                log,
+                auto_advance,
+            } => {
+                let options = CreateOptions {
+                    public: *public,
+                    password: *password,
+                    monotonic: *monotonic,
+                    timeout: timeout.map(|timeout| {
+                        timeout.map(|timeout| {
+                            Duration::from_secs(timeout).min(self.config.max_session_timeout)
+                        })
+                    }),
+                    max_clients: *max_clients,
+                    log: log.map(|log| log && self.config.max_command_log > 0),
+                    auto_advance: *auto_advance,
+                };
+                let session = session
+                    .map(String::from)
+                    .unwrap_or_else(|| self.generate_session_name());
+                let token = token.map(String::from).unwrap_or_else(generate_token);
+                self.handle_create(&session, &token, options, sender);
+                self.gc_sessions();
+            }
+            SyncCommand::Hello {
+                version,
+                capabilities,
+            } => return self.handle_hello(sender, *version, capabilities),
+            SyncCommand::Exists { session } => {
+                let event = match self.sessions.get(*session) {
+                    Some(found) => ServerEvent::Exists {
+                        session,
+                        exists: true,
+                        clients: found.clients().count(),
+                        public: found.is_public(),
+                        protected: found.is_protected(),
+                    },
+                    None => ServerEvent::Exists {
+                        session,
+                        exists: false,
+                        clients: 0,
+                        public: false,
+                        protected: false,
+                    },
+                };
+                self.send_command(&sender, &event);
+            }
+            SyncCommand::Stats {
+                session: session_name,
+            } => match self.sessions.get(*session_name) {
+                Some(session) if session.is_owner(&sender) => {
+                    let stats = session.stats();
+                    drop(session);
+                    self.send_command(
+                        &sender,
+                        &ServerEvent::Stats {
+                            session: session_name,
+                            stats,
+                        },
+                    );
+                }
+                Some(session) => {
+                    drop(session);
+                    self.send_error(
+                        &sender,
+                        ErrorCode::NotOwner,
+                        Some(session_name),
+                        "only the owner can request the statistics of a session".into(),
+                    );
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::History {
+                session: session_name,
+            } => match self.sessions.get(*session_name) {
+                Some(session) if session.is_owner(&sender) => match session.command_log() {
+                    Some((commands, dropped)) => {
+                        drop(session);
+                        self.send_command(
+                            &sender,
+                            &ServerEvent::History {
+                                session: session_name,
+                                commands,
+                                dropped,
+                            },
+                        );
+                    }
+                    None => {
+                        drop(session);
+                        self.send_error(
+                            &sender,
+                            ErrorCode::InvalidRequest,
+                            Some(session_name),
+                            "the commands of this session aren't logged".into(),
+                        );
+                    }
+                },
+                Some(session) => {
+                    drop(session);
+                    self.send_error(
+                        &sender,
+                        ErrorCode::NotOwner,
+                        Some(session_name),
+                        "only the owner can request the history of a session".into(),
+                    );
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::Time { client_echo } => self.send_command(
+                &sender,
+                &ServerEvent::Time {
+                    client_echo: *client_echo,
+                    server_time: unix_millis(),
+                },
+            ),
+            SyncCommand::List => self.send_command(
+                &sender,
+                &ServerEvent::Sessions {
+                    sessions: self.public_sessions(),
+                },
+            ),
+            SyncCommand::Rotate {
+                session: session_name,
+                token,
+                new_token,
+            } => match self.sessions.get_mut(*session_name) {
+                Some(mut session) => {
+                    if session.is_owner(&sender) && session.is_owner_token(token) {
+                        let grace = self.config.token_rotation_grace;
+                        session.rotate_token(new_token.to_string(), Instant::now(), grace);
+                        info!(target: "audit", session = session_name, owner = %sender, "owner token rotated");
+                        self.send_command(
+                            &sender,
+                            &ServerEvent::Rotated {
+                                session: session_name,
+                                previous_valid_until: unix_millis() + grace.as_millis() as u64,
+                            },
+                        );
+                    } else {
+                        self.send_error(
+                            &sender,
+                            ErrorCode::InvalidToken,
+                            Some(session_name),
+                            "only the owner can rotate the session token".into(),
+                        );
+                    }
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::Contest {
+                session: session_name,
+            } => match self.sessions.get_mut(*session_name) {
+                Some(mut session) => {
+                    let grace = self.config.takeover_grace;
+                    let usurper = session.owner();
+                    match (session.contest(sender, Instant::now(), grace), usurper) {
+                        (true, Some(usurper)) => {
+                            info!(
+                                target: "audit",
+                                session = session_name,
+                                owner = %sender,
+                                usurper = %usurper,
+                                "session takeover contested"
+                            );
+                            self.send_error(
+                                &usurper,
+                                ErrorCode::OwnershipContested,
+                                Some(session_name),
+                                "the previous owner reclaimed the session".into(),
+                            );
+                            self.send_client_count(&mut session);
+                        }
+                        (true, None) => self.send_client_count(&mut session),
+                        (false, _) => self.send_error(
+                            &sender,
+                            ErrorCode::ContestFailed,
+                            Some(session_name),
+                            "no takeover to contest".into(),
+                        ),
+                    }
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::Join {
+                session: requested,
+                name: Some(name),
+                ..
+            } if name.len() > MAX_NAME_LENGTH => self.send_error(
+                &sender,
+                ErrorCode::PayloadTooLarge,
+                Some(requested),
+                format!("names are limited to {MAX_NAME_LENGTH} bytes"),
+            ),
+            SyncCommand::Join {
+                session: requested,
+                password,
+                name,
+                capabilities,
+            } => {
+                self.peers.add_capabilities(
+                    &sender,
+                    capabilities
+                        .iter()
+                        .filter(|capability| CAPABILITIES.contains(capability))
+                        .map(|capability| capability.to_string()),
+                );
+                let redirect = self.redirect(requested);
+                let session_name = redirect.as_deref().unwrap_or(requested);
+                match self.sessions.get_mut(session_name) {
+                    Some(mut session) => {
+                        if session.is_banned(&sender) {
+                            drop(session);
+                            self.send_error(
+                                &sender,
+                                ErrorCode::Unauthorized,
+                                Some(requested),
+                                "you were kicked from this session".into(),
+                            );
+                        } else if !session.accepts_password(*password) {
+                            drop(session);
+                            debug!(%sender, session = session_name, "wrong session password");
+                            self.send_error(
+                                &sender,
+                                ErrorCode::WrongPassword,
+                                Some(requested),
+                                "wrong password for this session".into(),
+                            );
+                        } else if self.client_limit(&session).is_some_and(|limit| {
+                            !session.has_client(&sender) && session.clients().count() >= limit
+                        }) {
+                            drop(session);
+                            debug!(%sender, session = session_name, "session is full");
+                            self.send_error(
+                                &sender,
+                                ErrorCode::SessionFull,
+                                Some(requested),
+                                "this session has reached its maximum number of clients".into(),
+                            );
+                        } else {
+                            if redirect.is_some() {
+                                self.send_command(
+                                    &sender,
+                                    &ServerEvent::Renamed {
+                                        session: requested,
+                                        new_name: session_name,
+                                    },
+                                );
+                            }
+                            if !self.peers.join_session(&sender, session_name) {
+                                drop(session);
+                                self.send_error(
+                                    &sender,
+                                    ErrorCode::InvalidRequest,
+                                    Some(requested),
+                                    format!(
+                                        "a connection can join at most {MAX_JOINED_SESSIONS} sessions"
+                                    ),
+                                );
+                            } else {
+                                for initial_command in session.initial_state() {
+                                    if self.accepts(&sender, initial_command.kind()) {
+                                        self.send_command(&sender, &initial_command);
+                                    }
+                                }
+                                // joining a session again only resends the state
+                                if session.join(sender, name.map(String::from)) {
+                                    self.events.publish(|| Event::ClientJoined {
+                                        session: session_name.to_string(),
+                                        peer: sender,
+                                    });
+                                    for middleware in &self.middleware {
+                                        middleware.on_join(&sender, session_name);
+                                    }
+                                    self.send_client_count(&mut session);
+                                    // plugins can send commands into the session, so it can't be locked
+                                    drop(session);
+                                    #[cfg(feature = "plugins")]
+                                    self.dispatch_plugin_event(plugin::PluginEvent::Join {
+                                        peer: sender.to_string(),
+                                        session: session_name,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                    None if self.config.auto_create => {
+                        self.handle_auto_create(session_name, *password, sender);
+                        self.gc_sessions();
+                    }
+                    None => self.send_session_not_found(&sender, session_name),
+                }
+            }
+            SyncCommand::Next { session } => self.next_demo(sender, session, &command),
+            SyncCommand::Kick { session, client } => self.kick(sender, session, client),
+            SyncCommand::Promote { session, client } => self.promote(sender, session, client, true),
+            SyncCommand::Demote { session, client } => self.promote(sender, session, client, false),
+            SyncCommand::Close { session, token } => self.close_session(sender, session, token),
+            SyncCommand::Transfer {
+                session,
+                token,
+                new_owner,
+            } => self.transfer(sender, session, token, new_owner),
+            SyncCommand::Leave { session } => {
+                if self.peers.leave_session(&sender, session) {
+                    self.remove_client(&sender, session);
+                } else {
+                    debug!(%sender, session, "leaving a session that wasn't joined");
+                }
+            }
+            SyncCommand::Subscribe {
+                session: session_name,
+                kinds,
+            } => {
+                if kinds
+                    .as_ref()
+                    .is_some_and(|kinds| kinds.len() > MAX_SUBSCRIBED_KINDS)
+                {
+                    self.send_error(
+                        &sender,
+                        ErrorCode::InvalidRequest,
+                        Some(session_name),
+                        format!("at most {MAX_SUBSCRIBED_KINDS} kinds can be subscribed to"),
+                    );
+                } else if let Some(mut session) = self.sessions.get_mut(*session_name) {
+                    if !session.subscribe(&sender, kinds.clone()) {
+                        debug!(%sender, session = session_name, "subscribing to a session that wasn't joined");
+                    }
+                } else {
+                    self.send_session_not_found(&sender, session_name);
+                }
+            }
+            SyncCommand::Demo {
+                session,
+                demo: Some(demo),
+            }
+            | SyncCommand::QueueAdd { session, demo } if demo.is_too_large() => self.send_error(
+                &sender,
+                ErrorCode::PayloadTooLarge,
+                Some(session),
+                format!("the url and map of a demo are limited to {MAX_DEMO_FIELD_LENGTH} bytes"),
+            ),
+            SyncCommand::Countdown {
+                session, seconds, ..
+            } if *seconds > MAX_COUNTDOWN_SECONDS => self.send_error(
+                &sender,
+                ErrorCode::InvalidRequest,
+                Some(session),
+                format!("countdowns are limited to {MAX_COUNTDOWN_SECONDS} seconds"),
+            ),
+            SyncCommand::Loop {
+                session,
+                from_tick,
+                to_tick,
+                enabled: true,
+            } if from_tick >= to_tick => self.send_error(
+                &sender,
+                ErrorCode::InvalidRequest,
+                Some(session),
+                "a loop has to end after it starts".into(),
+            ),
+            SyncCommand::Marker { session, label, .. } if label.len() > MAX_MARKER_LABEL_LENGTH => {
+                self.send_error(
+                    &sender,
+                    ErrorCode::PayloadTooLarge,
+                    Some(session),
+                    format!("the label of a marker is limited to {MAX_MARKER_LABEL_LENGTH} bytes"),
+                )
+            }
+            SyncCommand::SetMeta {
+                session,
+                title,
+                description,
+            } if title.as_ref().is_some_and(|title| title.len() > MAX_TITLE_LENGTH)
+                || description
+                    .as_ref()
+                    .is_some_and(|description| description.len() > MAX_DESCRIPTION_LENGTH) =>
+            {
+                self.send_error(
+                    &sender,
+                    ErrorCode::PayloadTooLarge,
+                    Some(session),
+                    format!("the title and description of a session are limited to {MAX_TITLE_LENGTH} and {MAX_DESCRIPTION_LENGTH} bytes"),
+                )
+            }
+            SyncCommand::Annotate {
+                session, payload, ..
+            } if payload.to_string().len() > self.config.ext_max_payload => self.send_error(
+                &sender,
+                ErrorCode::PayloadTooLarge,
+                Some(session),
+                format!(
+                    "annotations are limited to {} bytes",
+                    self.config.ext_max_payload
+                ),
+            ),
+            SyncCommand::Play { session, .. }
+            | SyncCommand::PlayAt { session, .. }
+            | SyncCommand::Tick { session, .. }
+            | SyncCommand::Seek { session, .. }
+            | SyncCommand::Demo { session, .. }
+            | SyncCommand::QueueAdd { session, .. }
+            | SyncCommand::QueueRemove { session, .. }
+            | SyncCommand::Pov { session, .. }
+            | SyncCommand::Loop { session, .. }
+            | SyncCommand::Marker { session, .. }
+            | SyncCommand::Annotate { session, .. }
+            | SyncCommand::SetMeta { session, .. }
+            | SyncCommand::Schedule { session, .. }
+            | SyncCommand::Countdown { session, .. }
+            | SyncCommand::Voice { session, .. }
+            | SyncCommand::Encryption { session, .. } => match self.sessions.get_mut(*session) {
+                Some(mut session) => {
+                    if let Some(refusal) = encryption_refusal(session.encryption(), &command) {
+                        drop(session);
+                        self.send_error(
+                            &sender,
+                            ErrorCode::InvalidRequest,
+                            command.session(),
+                            refusal.into(),
+                        );
+                    } else if session.is_owner(&sender)
+                        || (session.is_co_owner(&sender) && command.is_playback())
+                    {
+                        let changed = self.apply_owner_command(&mut session, &command);
+                        // keep the owner in sync with the playback driven by a co-owner,
+                        // the owner also needs the start time of a countdown
+                        if changed
+                            && (!session.is_owner(&sender)
+                                || matches!(command, SyncCommand::Countdown { .. }))
+                        {
+                            self.send_to_owner(&session, &command);
+                        }
+                        let mirrors = session.mirrors().to_vec();
+                        // the mirrors might be in the same shard
+                        drop(session);
+                        self.mirror_command(&mirrors, &command);
+                        if let Some(session) = command.session() {
+                            self.auto_advance(session);
+                        }
+                    } else {
+                        drop(session);
+                        self.send_error(
+                            &sender,
+                            ErrorCode::NotOwner,
+                            command.session(),
+                            format!("only the owner can send {kind} commands"),
+                        );
+                    }
+                }
+                None => self.send_session_not_found(&sender, session),
+            },
+            SyncCommand::React {
+                session: session_name,
+                emote,
+            } => match self.sessions.get_mut(*session_name) {
+                Some(mut session) => {
+                    if !session.react(sender, emote, Instant::now()) {
+                        debug!(%sender, session = session_name, emote, "dropping reaction");
+                    }
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::Presence {
+                session: session_name,
+            } => match self.sessions.get_mut(*session_name) {
+                Some(mut session) => {
+                    let now = Instant::now();
+                    if session.presence(&sender, now) {
+                        self.send_idle_change(&mut session, now);
+                    }
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::Resync {
+                session: session_name,
+            } => match self.sessions.get(*session_name) {
+                Some(session) if session.is_member(&sender) => {
+                    for command in session.initial_state() {
+                        if self.accepts(&sender, command.kind()) {
+                            self.send_command(&sender, &command);
+                        }
+                    }
+                }
+                Some(session) => {
+                    drop(session);
+                    self.send_error(
+                        &sender,
+                        ErrorCode::InvalidRequest,
+                        Some(session_name),
+                        "only members of a session can request its state".into(),
+                    );
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::Progress {
+                session: session_name,
+                tick,
+            } => match self.sessions.get_mut(*session_name) {
+                Some(mut session) => {
+                    if !session.progress(&sender, *tick) {
+                        debug!(%sender, session = session_name, "progress from non client");
+                    }
+                }
+                None => self.send_session_not_found(&sender, session_name),
+            },
+            SyncCommand::Ext {
+                session: session_name,
+                kind,
+                payload,
+            } => {
+                if kind.len() > MAX_EXT_KIND_LENGTH
+                    || payload.to_string().len() > self.config.ext_max_payload
+                {
+                    self.send_error(
+                        &sender,
+                        ErrorCode::PayloadTooLarge,
+                        Some(session_name),
+                        format!(
+                            "extension payloads are limited to {} bytes",
+                            self.config.ext_max_payload
+                        ),
+                    );
+                } else if let Some(mut session) = self.sessions.get_mut(*session_name) {
+                    if session.encryption() != EncryptionMode::Off {
+                        drop(session);
+                        self.send_error(
+                            &sender,
+                            ErrorCode::InvalidRequest,
+                            Some(session_name),
+                            "this session is encrypted, send extension messages as sealed messages"
+                                .into(),
+                        );
+                    } else {
+                        if session.is_owner(&sender) {
+                            session.record_ext(kind, payload);
+                        }
+                        self.relay_ext(&session, sender, &command);
+                    }
+                } else {
+                    self.send_session_not_found(&sender, session_name);
+                }
+            }
+            SyncCommand::Signal {
+                session: session_name,
+                to,
+                payload,
+                ..
+            } => {
+                if payload.to_string().len() > self.config.ext_max_payload {
+                    self.send_error(
+                        &sender,
+                        ErrorCode::PayloadTooLarge,
+                        Some(session_name),
+                        format!(
+                            "signaling payloads are limited to {} bytes",
+                            self.config.ext_max_payload
+                        ),
+                    );
+                } else {
+                    self.signal(sender, session_name, to, payload);
+                }
+            }
+            SyncCommand::Sealed {
+                session: session_name,
+                payload,
+            } => {
+                if payload.len() > self.config.ext_max_payload {
+                    self.send_error(
+                        &sender,
+                        ErrorCode::PayloadTooLarge,
+                        Some(session_name),
+                        format!(
+                            "sealed payloads are limited to {} bytes",
+                            self.config.ext_max_payload
+                        ),
+                    );
+                } else if let Some(session) = self.sessions.get(*session_name) {
+                    if session.encryption() == EncryptionMode::Off {
+                        drop(session);
+                        self.send_error(
+                            &sender,
+                            ErrorCode::InvalidRequest,
+                            Some(session_name),
+                            "this session is not encrypted".into(),
+                        );
+                    } else {
+                        self.relay_sealed(&session, sender, &command);
+                    }
+                } else {
+                    self.send_session_not_found(&sender, session_name);
+                }
+            }
+            SyncCommand::Listen {
+                session: session_name,
+                channel,
+                listen,
+            } => {
+                if let Some(mut session) = self.sessions.get_mut(*session_name) {
+                    if !session.listen(&sender, *channel, *listen) {
+                        debug!(%sender, session = session_name, "listen from non client");
+                    }
+                } else {
+                    self.send_session_not_found(&sender, session_name);
+                }
+            }
+            SyncCommand::Mirror {
+                session: session_name,
+                target,
+                token,
+                enabled,
+            } => {
+                let is_owner = self
+                    .sessions
+                    .get(*session_name)
+                    .is_some_and(|session| session.is_owner(&sender));
+                let target_token = self
+                    .sessions
+                    .get(*target)
+                    .is_some_and(|target| target.is_owner_token(token));
+                if !is_owner || !target_token {
+                    self.send_error(
+                        &sender,
+                        ErrorCode::InvalidToken,
+                        Some(session_name),
+                        "mirroring requires owning the session and the owner token of the target"
+                            .into(),
+                    );
+                } else if let Err(message) = self.set_mirror(session_name, target, *enabled) {
+                    self.send_error(
+                        &sender,
+                        ErrorCode::InvalidRequest,
+                        Some(session_name),
+                        message,
+                    );
+                } else {
+                    info!(target: "audit", session = session_name, mirror = target, enabled, "session mirror changed");
+                }
+            }
+            SyncCommand::Rename {
+                session: session_name,
+                new_name,
+                redirect,
+            } => {
+                if let Err((code, message)) =
+                    self.rename_session(sender, session_name, new_name, *redirect)
+                {
+                    self.send_error(&sender, code, Some(session_name), message);
+                }
+            }
+            SyncCommand::Admin { token, request } => self.handle_admin(sender, token, request),
+        }
+
+        for middleware in &self.middleware {
+            middleware.after_command(&sender, &command);
+        }
+        #[cfg(feature = "plugins")]
+        self.dispatch_plugin_event(plugin::PluginEvent::Command {
+            peer: sender.to_string(),
+            command: &command,
+        });
+        ControlFlow::Continue(())
+    }
+
+    fn handle_admin(&self, sender: PeerId, token: &str, request: &AdminRequest) {
+        if self.config.admin_token.as_deref() != Some(token) {
+            warn!(%sender, "invalid admin token");
+            self.send_error(
+                &sender,
+                ErrorCode::Unauthorized,
+                None,
+                "invalid admin token".into(),
+            );
+            return;
+        }
+        info!(target: "audit", %sender, ?request, "admin request");
+        let data = match request {
+            AdminRequest::DumpRecorder { session } => {
+                serde_json::to_value(self.dump_recorder(session.as_deref()))
+            }
+            AdminRequest::SetFaults { peer, faults } => {
+                match self.update_faults(peer.as_deref(), Some(*faults)) {
+                    Ok(faults) => serde_json::to_value(faults),
+                    Err(message) => {
+                        return self.send_error(&sender, ErrorCode::InvalidRequest, None, message)
+                    }
+                }
+            }
+            AdminRequest::Mirror {
+                source,
+                target,
+                enabled,
+            } => match self.set_mirror(source, target, *enabled) {
+                Ok(()) => serde_json::to_value(
+                    self.sessions
+                        .get(source.as_str())
+                        .map(|session| session.mirrors().to_vec()),
+                ),
+                Err(message) => {
+                    return self.send_error(&sender, ErrorCode::InvalidRequest, None, message)
+                }
+            },
+            AdminRequest::ExportSession {
+                session,
+                include_token,
+            } => serde_json::to_value(self.sessions.get(session.as_str()).map(|session| {
+                info!(target: "audit", session = session.token, include_token, "session exported");
+                ExportedSession::new(session.snapshot(), *include_token)
+            })),
+            AdminRequest::ImportSession { session } => {
+                match self.import_session(*session.clone()) {
+                    Ok(token) => Ok(serde_json::json!({ "session": session.name, "token": token })),
+                    Err(message) => {
+                        return self.send_error(&sender, ErrorCode::InvalidRequest, None, message)
+                    }
+                }
+            }
+            AdminRequest::LockContention { limit } => Ok(serde_json::json!({
+                "sessions": self.sessions.hottest(*limit),
+                "peers": self.peers.hottest(*limit),
+            })),
+            AdminRequest::CaptureLogs {
+                session,
+                enabled,
+                duration,
+            } => match self.capture_logs(session, *enabled, *duration) {
+                Ok(data) => Ok(data),
+                Err(message) => {
+                    return self.send_error(&sender, ErrorCode::InvalidRequest, None, message)
+                }
+            },
+            AdminRequest::ListArchive => serde_json::to_value(self.archive.list()),
+            AdminRequest::GetArchive { session } => serde_json::to_value(self.archive.get(session)),
+            AdminRequest::SessionStats { session } => serde_json::to_value(
+                self.sessions
+                    .get(session.as_str())
+                    .map(|session| session.stats()),
+            ),
+            AdminRequest::ClearFaults { peer } => match self.update_faults(peer.as_deref(), None) {
+                Ok(faults) => serde_json::to_value(faults),
+                Err(message) => {
+                    return self.send_error(&sender, ErrorCode::InvalidRequest, None, message)
+                }
+            },
+        };
+        match data {
+            Ok(data) => self.send_command(&sender, &ServerEvent::AdminResponse { data }),
+            Err(error) => error!(%error, "failed to encode admin response"),
+        }
+    }
+
+    /// Start or stop capturing the logs of a session to a file in the recorder directory
+    fn capture_logs(
+        &self,
+        session: &str,
+        enabled: bool,
+        duration: u64,
+    ) -> Result<serde_json::Value, String> {
+        let Some(capture) = &self.log_capture else {
+            return Err("log capture is not enabled on this server".into());
+        };
+        if !enabled {
+            return Ok(serde_json::json!({ "stopped": capture.stop(session) }));
+        }
+        if duration > MAX_CAPTURE_SECONDS {
+            return Err(format!(
+                "captures can't be longer than {MAX_CAPTURE_SECONDS} seconds"
+            ));
+        }
+        let file_name: String = session
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = self
+            .config
+            .recorder_dir
+            .join(format!("sync-capture-{file_name}-{}.log", unix_millis()));
+        let until = Instant::now() + Duration::from_secs(duration);
+        capture
+            .start(session, &path, until)
+            .map_err(|error| format!("failed to open {}: {error}", path.display()))?;
+        info!(target: "audit", session, path = %path.display(), duration, "capturing session logs");
+        Ok(serde_json::json!({ "path": path }))
+    }
+
+    /// Create a session from an export, returns the owner token needed to reclaim it
+    fn import_session(&self, export: ExportedSession) -> Result<String, String> {
+        session::validate_name(&export.name)?;
+        let token = export.owner_token.clone().unwrap_or_else(generate_token);
+        let snapshot = export.into_snapshot(token.clone());
+        let Entry::Vacant(entry) = self.sessions.entry(snapshot.name.clone()) else {
+            return Err(format!(
+                "a session named {:?} already exists",
+                snapshot.name
+            ));
+        };
+        self.store_writer.save(snapshot.clone());
+        info!(target: "audit", session = snapshot.name, "session imported");
+        entry.insert(Session::restore(snapshot, Instant::now()));
+        Ok(token)
+    }
+
+    /// Set or clear the faults of a peer or the defaults, returns the faults now in effect
+    fn update_faults(
+        &self,
+        peer: Option<&str>,
+        faults: Option<FaultSettings>,
+    ) -> Result<FaultSettings, String> {
+        let Some(injector) = &self.faults else {
+            return Err("fault injection is not enabled".into());
+        };
+        let peer = match peer {
+            Some(peer) => Some(PeerId::parse(peer).ok_or_else(|| format!("invalid peer {peer}"))?),
+            None => None,
+        };
+        match faults {
+            Some(faults) => injector.set(peer, faults),
+            None => injector.clear(peer.as_ref()),
+        }
+        Ok(injector.settings(peer.as_ref()))
+    }
+
+    /// Get the recently send and received messages, for a single session or all sessions
+    pub fn dump_recorder(&self, session: Option<&str>) -> BTreeMap<String, Vec<Record>> {
+        self.recorder.dump(session)
+    }
+
+    /// Write the flight recorder of all sessions to a file in the configured directory
+    pub fn write_recorder_dump(&self) -> std::io::Result<PathBuf> {
+        let path = self
+            .config
+            .recorder_dir
+            .join(format!("sync-recorder-{}.json", unix_millis()));
+        std::fs::write(&path, serde_json::to_vec_pretty(&self.dump_recorder(None))?)?;
+        Ok(path)
+    }
+
+    /// The new name of a renamed session, if joins for the old name are still redirected
+    fn redirect(&self, session: &str) -> Option<String> {
+        if self.sessions.contains_key(session) {
+            return None;
+        }
+        let redirect = self.redirects.get(session)?;
+        let (new_name, until) = redirect.value();
+        (*until > Instant::now()).then(|| new_name.clone())
+    }
+
+    /// Move a session to a new name, only the owner can rename a session
+    fn rename_session(
+        &self,
+        sender: PeerId,
+        session_name: &str,
+        new_name: &str,
+        redirect: bool,
+    ) -> Result<(), (ErrorCode, String)> {
+        if new_name.is_empty() || self.sessions.contains_key(new_name) {
+            return Err((
+                ErrorCode::InvalidRequest,
+                format!("a session named {new_name:?} already exists"),
+            ));
+        }
+        let Some((_, mut session)) = self
+            .sessions
+            .remove_if(session_name, |_, session| session.is_owner(&sender))
+        else {
+            return Err((
+                ErrorCode::InvalidToken,
+                "only the owner can rename the session".into(),
+            ));
+        };
+        session.rename(new_name.to_string());
+        let mut session = match self.sessions.entry(new_name.to_string()) {
+            Entry::Vacant(entry) => entry.insert(session),
+            Entry::Occupied(_) => {
+                // created since the check above, put the session back
+                session.rename(session_name.to_string());
+                self.sessions.insert(session_name.to_string(), session);
+                return Err((
+                    ErrorCode::InvalidRequest,
+                    format!("a session named {new_name:?} already exists"),
+                ));
+            }
+        };
+        info!(target: "audit", session = session_name, new_name, owner = %sender, "session renamed");
+
+        self.persist(&mut session);
+        self.store_writer.delete(session_name);
+        for client in session.clients() {
+            self.peers.rename_session(client, session_name, new_name);
+        }
+        let renamed = ServerEvent::Renamed {
+            session: session_name,
+            new_name,
+        };
+        self.send_to_owner(&session, &renamed);
+        self.send_to_clients(&session, &renamed);
+        drop(session);
+
+        self.recorder.remove(session_name);
+        self.drops.remove(session_name);
+        self.egress.remove_session(session_name);
+        for mut other in self.sessions.iter_mut() {
+            other.rename_mirror(session_name, new_name);
+        }
+        for mut existing in self.redirects.iter_mut() {
+            if existing.0 == session_name {
+                existing.0 = new_name.to_string();
+            }
+        }
+        if redirect && !self.config.rename_redirect.is_zero() {
+            let until = Instant::now() + self.config.rename_redirect;
+            self.redirects
+                .insert(session_name.to_string(), (new_name.to_string(), until));
+        }
+        self.events.publish(|| Event::SessionRenamed {
+            session: session_name.to_string(),
+            new_name: new_name.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Link or unlink a mirror, newly linked mirrors are brought up to date with the source session
+    pub fn set_mirror(&self, source: &str, target: &str, enabled: bool) -> Result<(), String> {
+        if source == target {
+            return Err("a session can't mirror itself".into());
+        }
+        if enabled && !self.sessions.contains_key(target) {
+            return Err(format!("session {target} not found"));
+        }
+        let Some(mut session) = self.sessions.get_mut(source) else {
+            return Err(format!("session {source} not found"));
+        };
+        if !session.set_mirror(target, enabled) {
+            return Err(format!(
+                "a session can be mirrored into at most {MAX_MIRRORS} sessions"
+            ));
+        }
+        if !enabled {
+            return Ok(());
+        }
+        let state: Vec<SyncCommand> = session
+            .initial_state()
+            .filter_map(|command| command.mirrored(target))
+            .collect();
+        drop(session);
+        for command in state {
+            self.mirror_command(&[target.to_string()], &command);
+        }
+        Ok(())
+    }
+
+    /// Apply an owner command to the sessions mirroring its session
+    ///
+    /// Mirrors that are encrypted don't receive the cleartext commands they would refuse from their own owner.
+    fn mirror_command(&self, mirrors: &[String], command: &SyncCommand) {
+        for target in mirrors {
+            let Some(command) = command.mirrored(target) else {
+                continue;
+            };
+            if let Some(mut session) = self.sessions.get_mut(target.as_str()) {
+                if encryption_refusal(session.encryption(), &command).is_some() {
+                    debug!(
+                        session = target,
+                        kind = command.kind(),
+                        "not mirroring cleartext command into encrypted session"
+                    );
+                    continue;
+                }
+                self.apply_owner_command(&mut session, &command);
+            }
+        }
+    }
+
+    /// Relay an extension message from the owner to the clients, or from a client to everyone else
+    fn relay_ext(&self, session: &Session, sender: PeerId, command: &SyncCommand) {
+        if session.is_owner(&sender) {
+            self.send_to_clients(session, command);
+        } else if self.config.ext_from_clients && session.is_member(&sender) {
+            let text = serde_json::to_string(command).unwrap();
+            for peer in session.owner().iter().chain(session.subscribers("ext")) {
+                if *peer != sender {
+                    self.send_text(peer, Some(&session.token), Priority::Bulk, &text);
+                }
+            }
+        } else {
+            debug!(%sender, session = session.token, "dropping extension message from non member");
+        }
+    }
+
+    /// Forward signaling data to a single member of the session, tagged with the connection id of the sender
+    fn signal(&self, sender: PeerId, session_name: &str, to: &str, payload: &serde_json::Value) {
+        let Some(session) = self.sessions.get(session_name) else {
+            return self.send_session_not_found(&sender, session_name);
+        };
+        if !session.is_member(&sender) {
+            debug!(%sender, session = session_name, "dropping signal from non member");
+            return;
+        }
+        let target = session
+            .owner()
+            .filter(|owner| self.peers.connection_id(owner).as_deref() == Some(to))
+            .or_else(|| self.find_client(&session, to));
+        drop(session);
+        let Some(target) = target else {
+            return self.send_error(
+                &sender,
+                ErrorCode::InvalidRequest,
+                Some(session_name),
+                format!("{to} isn't a member of this session"),
+            );
+        };
+        self.send_command(
+            &target,
+            &SyncCommand::Signal {
+                session: session_name,
+                to,
+                from: self.peers.connection_id(&sender),
+                payload: payload.clone(),
+            },
+        );
+    }
+
+    /// Relay an encrypted message to all other members of the session
+    fn relay_sealed(&self, session: &Session, sender: PeerId, command: &SyncCommand) {
+        if !session.is_member(&sender) {
+            debug!(%sender, session = session.token, "dropping sealed message from non member");
+            return;
+        }
+        let text = serde_json::to_string(command).unwrap();
+        for peer in session.owner().iter().chain(session.subscribers("sealed")) {
+            if *peer != sender {
+                self.send_text(peer, Some(&session.token), Priority::Essential, &text);
+            }
+        }
+    }
+
+    /// Let the plugins handle an event and apply the commands they emitted
+    #[cfg(feature = "plugins")]
+    fn dispatch_plugin_event(&self, event: plugin::PluginEvent) {
+        let Some(plugins) = &self.plugins else {
+            return;
+        };
+        for emitted in plugins.dispatch(&event) {
+            match serde_json::from_str(&emitted)
+                .map(|command: SyncCommand| command.stamped(unix_millis()))
+            {
+                Ok(
+                    command @ (SyncCommand::Play { session, .. }
+                    | SyncCommand::PlayAt { session, .. }
+                    | SyncCommand::Tick { session, .. }
+                    | SyncCommand::Seek { session, .. }
+                    | SyncCommand::Schedule { session, .. }),
+                ) => {
+                    if let Some(mut session) = self.sessions.get_mut(session) {
+                        if self.apply_owner_command(&mut session, &command) {
+                            self.send_to_owner(&session, &command);
+                        }
+                    }
+                    self.auto_advance(session);
+                }
+                Ok(command) => warn!(kind = command.kind(), "plugins can't emit this command"),
+                Err(error) => warn!(%error, emitted, "invalid command emitted by plugin"),
+            }
+        }
+    }
+
+    fn handle_disconnect(&self, peer: &PeerId) {
+        let Some(connected) = self.peers.remove(peer) else {
+            return;
+        };
+        if let Some(faults) = &self.faults {
+            faults.remove_peer(peer);
+        }
+        self.egress.remove_peer(peer);
+        let now = Instant::now();
+        info!(
+            %peer,
+            connection = connected.connection_id(),
+            identity = %connected.identity(),
+            user_agent = connected.user_agent(),
+            client_version = connected.client_version(),
+            protocol_version = connected.protocol_version(),
+            connected_for = ?connected.connected_for(now),
+            idle_for = ?connected.idle_for(now),
+            queue_high_water = connected.high_water(),
+            "disconnected"
+        );
+        for session_name in connected.sessions() {
+            self.remove_client(peer, session_name);
+        }
+        self.owner_left(peer, now);
+    }
+
+    /// Start the expiry of the sessions owned by a disconnected peer and tell their clients
+    fn owner_left(&self, peer: &PeerId, now: Instant) {
+        for mut session in self.sessions.iter_mut() {
+            if !session.owner_disconnected(peer, now) {
+                continue;
+            }
+            let expires_in = self.session_timeout(&session).as_secs();
+            info!(session = session.token, owner = %peer, expires_in, "owner left");
+            self.send_to_clients(
+                &session,
+                &ServerEvent::OwnerAway {
+                    session: &session.token,
+                    expires_in,
+                },
+            );
+            self.events.publish(|| Event::OwnerLeft {
+                session: session.token.clone(),
+            });
+        }
+    }
+
+    /// Advance the session to the first demo in its queue
+    fn next_demo(&self, sender: PeerId, session_name: &str, command: &SyncCommand) {
+        let Some(mut session) = self.sessions.get_mut(session_name) else {
+            return self.send_session_not_found(&sender, session_name);
+        };
+        let error = if !session.is_owner(&sender) {
+            Some((ErrorCode::NotOwner, "only the owner can send next commands"))
+        } else if session.encryption() == EncryptionMode::Full {
+            Some((
+                ErrorCode::InvalidRequest,
+                "the state of this session is encrypted, send it as sealed messages",
+            ))
+        } else {
+            None
+        };
+        if let Some((code, message)) = error {
+            drop(session);
+            return self.send_error(&sender, code, Some(session_name), message.into());
+        }
+        let Some(demo) = session.next_demo() else {
+            drop(session);
+            return self.send_error(
+                &sender,
+                ErrorCode::InvalidRequest,
+                Some(session_name),
+                "the queue of this session is empty".into(),
+            );
+        };
+        // clients that show the queue drop the first demo, the others only need the new demo
+        self.send_to_clients(&session, command);
+        self.play_demo(session, session_name, demo, false);
+    }
+
+    /// Play the next demo of the queue once the current demo ended, in sessions with auto advance
+    fn auto_advance(&self, session_name: &str) {
+        let Some(mut session) = self.sessions.get_mut(session_name) else {
+            return;
+        };
+        if !session.take_advance() {
+            return;
+        }
+        let Some(demo) = session.next_demo() else {
+            return;
+        };
+        debug!(session = session_name, "advancing to the next demo");
+        let next = SyncCommand::Next {
+            session: session_name,
+        };
+        self.send_to_owner(&session, &next);
+        self.send_to_clients(&session, &next);
+        self.play_demo(session, session_name, demo, true);
+    }
+
+    /// Load a demo taken from the queue from the start, in the session and its mirrors
+    fn play_demo(
+        &self,
+        mut session: RefMut<'_, String, Session>,
+        session_name: &str,
+        demo: DemoInfo,
+        notify_owner: bool,
+    ) {
+        let commands = [
+            SyncCommand::Demo {
+                session: session_name,
+                demo: Some(demo),
+            },
+            SyncCommand::Seek {
+                session: session_name,
+                tick: 0,
+            },
+        ];
+        for command in &commands {
+            self.apply_owner_command(&mut session, command);
+            if notify_owner {
+                self.send_to_owner(&session, command);
+            }
+        }
+        let mirrors = session.mirrors().to_vec();
+        drop(session);
+        for command in &commands {
+            self.mirror_command(&mirrors, command);
+        }
+    }
+
+    /// Find a client of a session by its connection id
+    fn find_client(&self, session: &Session, connection_id: &str) -> Option<PeerId> {
+        session
+            .clients()
+            .copied()
+            .find(|peer| self.peers.connection_id(peer).as_deref() == Some(connection_id))
+    }
+
+    /// Let a client drive the playback of a session alongside the owner, or stop it from doing so
+    fn promote(&self, sender: PeerId, session_name: &str, client: &str, promoted: bool) {
+        let Some(mut session) = self.sessions.get_mut(session_name) else {
+            return self.send_session_not_found(&sender, session_name);
+        };
+        if !session.is_owner(&sender) {
+            drop(session);
+            return self.send_error(
+                &sender,
+                ErrorCode::NotOwner,
+                Some(session_name),
+                "only the owner can promote clients".into(),
+            );
+        }
+        let Some(target) = self.find_client(&session, client) else {
+            drop(session);
+            return self.send_error(
+                &sender,
+                ErrorCode::InvalidRequest,
+                Some(session_name),
+                format!("client {client} isn't in this session"),
+            );
+        };
+        if session.set_co_owner(target, promoted) {
+            info!(target: "audit", session = session_name, owner = %sender, peer = %target, promoted, "co-owner changed");
+            self.send_command(
+                &target,
+                &ServerEvent::Promoted {
+                    session: session_name,
+                    promoted,
+                },
+            );
+        }
+    }
+
+    /// Remove a client from a session on request of the owner
+    fn kick(&self, sender: PeerId, session_name: &str, client: &str) {
+        let Some(mut session) = self.sessions.get_mut(session_name) else {
+            return self.send_session_not_found(&sender, session_name);
+        };
+        if !session.is_owner(&sender) {
+            drop(session);
+            return self.send_error(
+                &sender,
+                ErrorCode::NotOwner,
+                Some(session_name),
+                "only the owner can kick clients".into(),
+            );
+        }
+        let target = self.find_client(&session, client);
+        let Some(target) = target else {
+            drop(session);
+            return self.send_error(
+                &sender,
+                ErrorCode::InvalidRequest,
+                Some(session_name),
+                format!("client {client} isn't in this session"),
+            );
+        };
+        session.ban(target);
+        drop(session);
+
+        info!(target: "audit", session = session_name, owner = %sender, peer = %target, "client kicked");
+        self.peers.leave_session(&target, session_name);
+        self.remove_client(&target, session_name);
+        self.send_command(
+            &target,
+            &ServerEvent::Kicked {
+                session: session_name,
+            },
+        );
+    }
+
+    /// Make one of the clients of a session the owner, with a new owner token
+    fn transfer(&self, sender: PeerId, session_name: &str, token: &str, new_owner: &str) {
+        let Some(mut session) = self.sessions.get_mut(session_name) else {
+            return self.send_session_not_found(&sender, session_name);
+        };
+        if !session.is_owner(&sender) || !session.is_owner_token(token) {
+            drop(session);
+            return self.send_error(
+                &sender,
+                ErrorCode::InvalidToken,
+                Some(session_name),
+                "only the owner can transfer the session".into(),
+            );
+        }
+        let target = self.find_client(&session, new_owner);
+        let Some(target) = target else {
+            drop(session);
+            return self.send_error(
+                &sender,
+                ErrorCode::InvalidRequest,
+                Some(session_name),
+                format!("client {new_owner} isn't in this session"),
+            );
+        };
+        let new_token = generate_token();
+        session.transfer(target, new_token.clone());
+        drop(session);
+
+        info!(
+            target: "audit",
+            session = session_name,
+            previous = %sender,
+            owner = %target,
+            "session ownership transferred"
+        );
+        self.send_command(
+            &sender,
+            &ServerEvent::Transferred {
+                session: session_name,
+                token: None,
+            },
+        );
+        self.send_command(
+            &target,
+            &ServerEvent::Transferred {
+                session: session_name,
+                token: Some(&new_token),
+            },
+        );
+        // the new owner stops being a client, which also sends it the client count
+        self.peers.leave_session(&target, session_name);
+        self.remove_client(&target, session_name);
+    }
+
+    /// Remove a client from a session it joined, after it left or disconnected
+    fn remove_client(&self, peer: &PeerId, session_name: &str) {
+        if let Some(mut session) = self.sessions.get_mut(session_name) {
+            session.remove_client(peer);
+            self.send_client_count(&mut session);
+            self.events.publish(|| Event::ClientLeft {
+                session: session_name.to_string(),
+                peer: *peer,
+            });
+        }
+        for middleware in &self.middleware {
+            middleware.on_leave(peer, session_name);
+        }
+        #[cfg(feature = "plugins")]
+        self.dispatch_plugin_event(plugin::PluginEvent::Leave {
+            peer: peer.to_string(),
+            session: session_name,
+        });
+    }
+
+    /// Periodic maintenance, should be called about once a second
+    pub fn housekeeping(&self) {
+        self.run_schedules();
+        self.warn_expiring_sessions();
+        self.gc_sessions();
+        self.flush_reactions();
+        self.report_progress();
+        self.report_rosters();
+        self.update_presence();
+        self.ping_peers();
+        self.disconnect_idle_peers();
+        self.expire_penalties();
+        self.persist_sessions();
+        self.archive.expire(unix_millis());
+        if let Some(capture) = &self.log_capture {
+            capture.expire(Instant::now());
+        }
+        gauge!(telemetry::SESSIONS).set(self.sessions.len() as f64);
+    }
+
+    /// send out reminders for scheduled sessions and start the ones that are due
+    fn run_schedules(&self) {
+        let now = unix_millis();
+        for mut session in self.sessions.iter_mut() {
+            match session.poll_schedule(now) {
+                Some(ScheduleEvent::Reminder {
+                    start_at,
+                    in_seconds,
+                }) => {
+                    let event = ServerEvent::Starting {
+                        session: &session.token,
+                        start_at,
+                        in_seconds,
+                    };
+                    self.send_to_owner(&session, &event);
+                    self.send_to_clients(&session, &event);
+                }
+                Some(ScheduleEvent::Start) => {
+                    info!(session = session.token, "starting scheduled session");
+                    let command = SyncCommand::Play {
+                        session: &session.token,
+                        play: true,
+                    };
+                    self.send_to_owner(&session, &command);
+                    self.send_to_clients(&session, &command);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Warn the clients of sessions that will be removed soon because the owner left
+    fn warn_expiring_sessions(&self) {
+        let now = Instant::now();
+        for mut session in self.sessions.iter_mut() {
+            let timeout = self.session_timeout(&session);
+            let Some(in_seconds) = session.poll_expiry(now, timeout) else {
+                continue;
+            };
+            if session.clients().next().is_some() {
+                let command = ServerEvent::SessionExpiring {
+                    session: &session.token,
+                    in_seconds,
+                };
+                self.send_to_clients(&session, &command);
+            }
+        }
+    }
+
+    /// broadcast the reactions aggregated over the last window
+    fn flush_reactions(&self) {
+        for mut session in self.sessions.iter_mut() {
+            let reactions = session.take_reactions();
+            if reactions.is_empty() {
+                continue;
+            }
+            let command = ServerEvent::Reactions {
+                session: &session.token,
+                counts: reactions
+                    .iter()
+                    .map(|(emote, count)| (emote.as_str(), *count))
+                    .collect(),
+            };
+            self.send_to_owner(&session, &command);
+            self.send_to_clients(&session, &command);
+        }
+    }
+
+    /// Send the progress of the clients to the owners, if it changed since the last report
+    fn report_progress(&self) {
+        for mut session in self.sessions.iter_mut() {
+            let Some(progress) = session.take_progress() else {
+                continue;
+            };
+            self.send_to_owner(
+                &session,
+                &ServerEvent::ProgressReport {
+                    session: &session.token,
+                    clients: progress.clients,
+                    min: progress.min,
+                    max: progress.max,
+                    median: progress.median,
+                },
+            );
+        }
+    }
+
+    /// Send the roster of sessions in which clients joined, left or changed their name
+    fn report_rosters(&self) {
+        for mut session in self.sessions.iter_mut() {
+            let Some(roster) = session.take_roster() else {
+                continue;
+            };
+            let clients = roster
+                .filter_map(|(peer, name)| {
+                    Some(RosterEntry {
+                        id: self.peers.connection_id(peer)?,
+                        name: name.map(String::from),
+                    })
+                })
+                .collect();
+            let roster = ServerEvent::Roster {
+                session: &session.token,
+                clients,
+            };
+            self.send_to_owner(&session, &roster);
+            self.send_to_clients(&session, &roster);
+        }
+    }
+
+    fn send_idle_change(&self, session: &mut Session, now: Instant) {
+        let idle = session.idle_count(now, self.config.presence_timeout);
+        if session.update_reported_idle(idle) {
+            self.send_client_count(session);
+        }
+    }
+
+    /// notify owners when clients went idle since the last report
+    fn update_presence(&self) {
+        let now = Instant::now();
+        for mut session in self.sessions.iter_mut() {
+            self.send_idle_change(&mut session, now);
+        }
+    }
+
+    /// Ping the peers to measure their latency and find the ones that went away without closing the connection
+    fn ping_peers(&self) {
+        for (peer, payload) in self.peers.due_pings(Instant::now()) {
+            self.send_frame(&peer, None, Priority::Essential, Frame::Ping(payload));
+        }
+    }
+
+    /// Disconnect the peers that neither send a message nor answered a ping within the idle timeout
+    fn disconnect_idle_peers(&self) {
+        let timeout = self.config.idle_timeout;
+        if timeout.is_zero() {
+            return;
+        }
+        for peer in self.peers.idle_peers(Instant::now(), timeout) {
+            info!(%peer, ?timeout, "disconnecting idle peer");
+            let close = Frame::Close {
+                code: CLOSE_IDLE,
+                reason: "idle for too long".into(),
+            };
+            self.send_frame(&peer, None, Priority::Essential, close);
+            // removing the peer ends its connection task once the close frame is send,
+            // even if the peer never answers it
+            self.handle_disconnect(&peer);
+        }
+    }
+
+    /// Store the round trip time of an answered ping with the sessions of the client
+    fn record_latency(&self, peer: &PeerId, payload: &[u8]) {
+        let Some((latency, sessions)) = self.peers.pong(peer, payload, Instant::now()) else {
+            return;
+        };
+        debug!(%peer, ?latency, "measured latency");
+        for session in sessions {
+            if let Some(mut session) = self.sessions.get_mut(&session) {
+                if session.set_latency(peer, latency) {
+                    self.send_client_count(&mut session);
+                }
+            }
+        }
+    }
+
+    fn expire_penalties(&self) {
+        let now = Instant::now();
+        self.penalties.retain(|_, until| *until > now);
+        let policy = &self.config.create_policy;
+        self.creations
+            .retain(|_, counter| !counter.is_expired(policy, now));
+    }
+
+    /// Queue a snapshot of a changed session to be saved, the store is written without holding the session
+    fn persist(&self, session: &mut Session) {
+        if session.take_dirty() {
+            self.store_writer.save(session.snapshot());
+        }
+    }
+
+    /// save every session that changed since it was last persisted
+    fn persist_sessions(&self) {
+        for mut session in self.sessions.iter_mut() {
+            self.persist(&mut session);
+        }
+    }
+
+    /// load the sessions saved by a previous instance, owners have to reclaim them with their token
+    pub fn restore_sessions(&self) -> Result<(), StoreError> {
+        let now = Instant::now();
+        for name in self.store.list()? {
+            if let Some(snapshot) = self.store.load(&name)? {
+                self.sessions
+                    .insert(snapshot.name.clone(), Session::restore(snapshot, now));
+            }
+        }
+        info!(count = self.sessions.len(), "restored sessions");
+        Ok(())
+    }
+
+    /// Maximum number of clients in a session, the lower of the limits of the server and the owner
+    fn client_limit(&self, session: &Session) -> Option<usize> {
+        let server_limit = self.config.max_session_clients;
+        session
+            .max_clients()
+            .into_iter()
+            .chain((server_limit > 0).then_some(server_limit))
+            .min()
+    }
+
+    /// A short random name that isn't used by a session or redirect
+    ///
+    /// Another session could still be created with the name before the generated name is used, but since the
+    /// token is generated as well, that only fails the create instead of giving access to the other session
+    fn generate_session_name(&self) -> String {
+        loop {
+            let name = generate_session_code();
+            if !self.sessions.contains_key(&name) && !self.redirects.contains_key(&name) {
+                return name;
+            }
+        }
+    }
+
+    /// Time a session is kept after the owner left
+    fn session_timeout(&self, session: &Session) -> Duration {
+        session.timeout().unwrap_or(self.config.session_timeout)
+    }
+
+    /// cleanup sessions where the owner hasn't reconnected within the session timeout
+    fn gc_sessions(&self) {
+        let now = Instant::now();
+        self.redirects.retain(|_, (_, until)| *until > now);
+        self.sessions.retain(|_, session| {
+            let expired = session
+                .inactive_time(now)
+                .is_some_and(|inactive| inactive > self.session_timeout(session));
+            if expired {
+                self.cleanup_session(session, CloseReason::Expired);
+            }
+            !expired
+        });
+    }
+
+    /// Remove a session on request of its owner
+    fn close_session(&self, sender: PeerId, session_name: &str, token: &str) {
+        let removed = self.sessions.remove_if(session_name, |_, session| {
+            session.is_owner(&sender) && session.is_owner_token(token)
+        });
+        let Some((_, session)) = removed else {
+            if self.sessions.contains_key(session_name) {
+                self.send_error(
+                    &sender,
+                    ErrorCode::InvalidToken,
+                    Some(session_name),
+                    "only the owner can close the session".into(),
+                );
+            } else {
+                self.send_session_not_found(&sender, session_name);
+            }
+            return;
+        };
+        info!(target: "audit", session = session_name, owner = %sender, "session closed by owner");
+        self.cleanup_session(&session, CloseReason::Closed);
+        self.send_command(
+            &sender,
+            &ServerEvent::SessionClosed {
+                session: session_name,
+                reason: CloseReason::Closed,
+            },
+        );
+    }
+
+    /// Archive a removed session and remove everything else kept for it
+    fn cleanup_session(&self, session: &Session, reason: CloseReason) {
+        let name = session.token.as_str();
+        self.send_to_clients(
+            session,
+            &ServerEvent::SessionClosed {
+                session: name,
+                reason,
+            },
+        );
+        for client in session.clients() {
+            self.peers.leave_session(client, name);
+        }
+        self.archive.insert(session.archive(unix_millis()));
+        self.store_writer.delete(name);
+        self.recorder.remove(name);
+        self.drops.remove(name);
+        self.egress.remove_session(name);
+        self.sessions.forget(name);
+        self.events.publish(|| match reason {
+            CloseReason::Expired | CloseReason::Evicted => Event::SessionExpired {
+                session: name.to_string(),
+            },
+            CloseReason::Closed => Event::SessionClosed {
+                session: name.to_string(),
+            },
+        });
+    }
+
+    /// Perform the websocket handshake for a connection accepted on a listener and serve the peer
+    ///
+    /// `remote_ip` is the address the connection came from, for unix sockets this should be a loopback address
+    pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        raw_stream: S,
+        remote_ip: IpAddr,
+        listener: &ListenerConfig,
+    ) {
+        let connection_id = generate_connection_id();
+        let span = info_span!(
+            "connection",
+            connection = connection_id,
+            peer = field::Empty,
+            request_id = field::Empty
+        );
+        self.accept_connection(raw_stream, remote_ip, listener, connection_id)
+            .instrument(span)
+            .await
+    }
+
+    async fn accept_connection<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        raw_stream: S,
+        remote_ip: IpAddr,
+        listener: &ListenerConfig,
+        connection_id: String,
+    ) {
+        debug!(listener = listener.name, "incoming connection");
+
+        let peer_ip = remote_ip;
+        let mut remote_ip = remote_ip;
+        let mut info = ConnectionInfo {
+            connection_id,
+            request_id: None,
+            identity: Identity::Anonymous,
+            user_agent: None,
+            client_version: None,
+            rate_limits: listener.rate_limits.clone(),
+        };
+
+        let mut encoding = Encoding::Json;
+        // the error response is dictated by the handshake callback of tungstenite
+        #[allow(clippy::result_large_err)]
+        let handshake = |req: &Request, mut response: Response| {
+            if let Some(ip) = real_ip(req.headers(), peer_ip, &listener.trusted_proxies) {
+                remote_ip = ip;
+            }
+            let origin = req
+                .headers()
+                .get(ORIGIN)
+                .and_then(|value| value.to_str().ok());
+            if !listener.allows_origin(origin) {
+                warn!(ip = %remote_ip, origin, listener = listener.name, "origin not allowed");
+                return Err(error_response(StatusCode::FORBIDDEN, "origin not allowed"));
+            }
+            info.user_agent = req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from);
+            info.client_version =
+                query_param(req.uri().query(), "client_version").map(String::from);
+            info.request_id = req
+                .headers()
+                .get("x-request-id")
+                .and_then(|value| value.to_str().ok())
+                .map(String::from);
+            if let Some(request_id) = &info.request_id {
+                Span::current().record("request_id", request_id.as_str());
+            }
+            if self.is_penalized(&remote_ip) {
+                return Err(error_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "too many invalid or rate limited messages",
+                ));
+            }
+            let handshake = Handshake {
+                headers: req.headers(),
+                query: req.uri().query(),
+            };
+            match self.auth.authenticate(&handshake) {
+                Ok(identity) => info.identity = identity,
+                Err(error) => {
+                    warn!(ip = %remote_ip, %error, "authentication failed");
+                    return Err(error_response(StatusCode::UNAUTHORIZED, &error.to_string()));
+                }
+            }
+            encoding = Encoding::negotiate(
+                req.headers()
+                    .get(SEC_WEBSOCKET_PROTOCOL)
+                    .and_then(|value| value.to_str().ok()),
+            );
+            if encoding == Encoding::MsgPack {
+                response.headers_mut().insert(
+                    SEC_WEBSOCKET_PROTOCOL,
+                    HeaderValue::from_static(MSGPACK_PROTOCOL),
+                );
+            }
+            Ok(response)
+        };
+        let ws_stream_res = tokio_tungstenite::accept_hdr_async(raw_stream, handshake).await;
+        let peer_id = PeerId::new(remote_ip, self.next_peer_id());
+        Span::current().record("peer", field::display(peer_id));
+        let ws_stream = match ws_stream_res {
+            Ok(ws_stream) => ws_stream,
+            Err(error) => {
+                error!(?error, %peer_id, "error while performing websocket handshake");
+                counter!(telemetry::REFUSED_CONNECTIONS).increment(1);
+                return;
+            }
+        };
+
+        info!(
+            peer = %peer_id,
+            identity = %info.identity,
+            user_agent = info.user_agent,
+            client_version = info.client_version,
+            encoding = encoding.as_str(),
+            "connection established"
+        );
+
+        let transport = WebSocketTransport::new(ws_stream).with_encoding(encoding);
+        self.handle_peer(peer_id, info, transport).await;
+    }
+
+    /// Serve a peer over an already established transport until the connection is closed
+    pub async fn handle_peer<T: PeerTransport>(
+        &self,
+        peer_id: PeerId,
+        info: ConnectionInfo,
+        transport: T,
+    ) {
+        // Insert the write part of this peer to the peer map.
+        let (tx, rx) = channel(16);
+        let connection_id = info.connection_id.clone();
+        let peer = Peer::new(tx, info);
+        let queue = peer.queue();
+        self.peers.insert(peer_id, peer);
+        counter!(telemetry::CONNECTIONS).increment(1);
+        gauge!(telemetry::PEERS).increment(1);
+        self.send_command(
+            &peer_id,
+            &ServerEvent::ServerInfo {
+                connection_id: &connection_id,
+                version: env!("CARGO_PKG_VERSION"),
+            },
+        );
+
+        let run = AssertUnwindSafe(self.run_peer(peer_id, transport, rx, &queue));
+        if let Err(panic) = run.catch_unwind().await {
+            let message = panic_message(panic.as_ref());
+            error!(peer = %peer_id, connection = connection_id, panic = message, "connection task panicked");
+            counter!(telemetry::PANICS).increment(1);
+            self.handle_disconnect(&peer_id);
+        }
+        // frames that couldn't be flushed before the connection closed
+        let unsent = queue.swap(0, Ordering::Relaxed);
+        gauge!(telemetry::QUEUED_FRAMES).decrement(unsent as f64);
+        gauge!(telemetry::PEERS).decrement(1);
+    }
+
+    /// Delay a frame if fault injection is enabled, returns whether the frame should be passed on
+    /// or breaks if the connection should be closed
+    async fn inject_fault(&self, peer_id: &PeerId) -> ControlFlow<(), bool> {
+        let Some(faults) = &self.faults else {
+            return ControlFlow::Continue(true);
+        };
+        match faults.decide(peer_id) {
+            Fault::Deliver { delay } => {
+                if !delay.is_zero() {
+                    tokio::time::sleep(delay).await;
+                }
+                ControlFlow::Continue(true)
+            }
+            Fault::Drop => {
+                debug!(%peer_id, "dropping frame by fault injection");
+                ControlFlow::Continue(false)
+            }
+            Fault::Disconnect => {
+                info!(%peer_id, "closing connection by fault injection");
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    /// Pass frames between a connected peer and the server until either side closes the connection
+    async fn run_peer<T: PeerTransport>(
+        &self,
+        peer_id: PeerId,
+        mut transport: T,
+        mut rx: Receiver<Frame>,
+        queue: &AtomicUsize,
+    ) {
+        loop {
+            tokio::select! {
+                incoming = transport.recv() => match incoming {
+                    Some(frame) => {
+                        match self.inject_fault(&peer_id).await {
+                            ControlFlow::Continue(true) => {}
+                            ControlFlow::Continue(false) => continue,
+                            ControlFlow::Break(()) => break,
+                        }
+                        if self.handle_frame(frame, peer_id).is_break() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                outgoing = rx.next() => match outgoing {
+                    Some(frame) => {
+                        queue.fetch_sub(1, Ordering::Relaxed);
+                        gauge!(telemetry::QUEUED_FRAMES).decrement(1);
+                        match self.inject_fault(&peer_id).await {
+                            ControlFlow::Continue(true) => {}
+                            ControlFlow::Continue(false) => {
+                                counter!(telemetry::DROPPED_FRAMES, "reason" => "fault").increment(1);
+                                continue;
+                            }
+                            ControlFlow::Break(()) => break,
+                        }
+                        if let Err(error) = transport.send(frame).await {
+                            debug!(%peer_id, %error, "failed to send frame");
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        self.handle_disconnect(&peer_id);
+
+        // removing the peer closes the channel, flush anything still queued, like a close frame
+        let flush = async {
+            while let Some(frame) = rx.next().await {
+                queue.fetch_sub(1, Ordering::Relaxed);
+                gauge!(telemetry::QUEUED_FRAMES).decrement(1);
+                if transport.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        };
+        if tokio::time::timeout(FLUSH_TIMEOUT, flush).await.is_err() {
+            debug!(%peer_id, "timeout while flushing messages");
+        }
+    }
+}
+
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn error_response(status: StatusCode, message: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(message.into()));
+    *response.status_mut() = status;
+    response
+}
+
+/// The message a panic was started with
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Random owner token for sessions created by the server
+fn generate_token() -> String {
+    let mut rng = rand::thread_rng();
+    (0..32)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+/// Characters of generated session names, without the ones that are easily confused like `0` and `o`
+const SESSION_CODE_ALPHABET: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+const SESSION_CODE_LENGTH: usize = 6;
+
+fn generate_session_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..SESSION_CODE_LENGTH)
+        .map(|_| SESSION_CODE_ALPHABET[rng.gen_range(0..SESSION_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Current unix time in milliseconds
+///
+/// In simulations the wall clock is only read once, after that the time advances with the tokio clock
+/// so it follows `tokio::time::pause` and `advance`
+fn unix_millis() -> u64 {
+    if !sim::is_simulated() {
+        return wall_clock_millis();
+    }
+    static EPOCH: OnceLock<(Instant, u64)> = OnceLock::new();
+    let (start, start_millis) = EPOCH.get_or_init(|| (Instant::now(), wall_clock_millis()));
+    start_millis + start.elapsed().as_millis() as u64
+}
+
+fn wall_clock_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -0,0 +1,186 @@
+use crate::ratelimit::RateLimits;
+use real_ip::IpNet;
+use socket2::{SockRef, TcpKeepalive};
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Proxies that are trusted when no listeners are configured
+pub const DEFAULT_TRUSTED_PROXIES: &[IpNet] = &[IpNet::new_assert(
+    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)),
+    8,
+)];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Parse a listen address as `unix:<path>`, `<ip>:<port>` or only a port to listen on all interfaces
+impl FromStr for ListenAddress {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenAddress::Unix(path.into()));
+        }
+        if let Ok(port) = s.parse::<u16>() {
+            return Ok(ListenAddress::Tcp(SocketAddr::from((
+                Ipv4Addr::UNSPECIFIED,
+                port,
+            ))));
+        }
+        s.parse().map(ListenAddress::Tcp).map_err(|_| ())
+    }
+}
+
+impl ListenAddress {
+    /// Whether both addresses can't be listened on at the same time
+    pub fn overlaps(&self, other: &ListenAddress) -> bool {
+        match (self, other) {
+            (ListenAddress::Tcp(a), ListenAddress::Tcp(b)) => {
+                a.port() == b.port()
+                    && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+            }
+            #[cfg(unix)]
+            (ListenAddress::Unix(a), ListenAddress::Unix(b)) => a == b,
+            #[cfg(unix)]
+            _ => false,
+        }
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Settings for the connections accepted on a single listener
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub name: String,
+    pub address: ListenAddress,
+    /// Proxies that are allowed to set forwarded headers
+    pub trusted_proxies: Vec<IpNet>,
+    /// Rate limits for peers from this listener, the global limits are used if not set
+    pub rate_limits: Option<Arc<RateLimits>>,
+    /// Values of the `Origin` header that are accepted, any origin is accepted if not set
+    pub allowed_origins: Option<Vec<String>>,
+    /// Socket options for accepted tcp connections
+    pub tcp: TcpOptions,
+}
+
+impl ListenerConfig {
+    pub fn new(name: impl Into<String>, address: ListenAddress) -> Self {
+        ListenerConfig {
+            name: name.into(),
+            address,
+            trusted_proxies: DEFAULT_TRUSTED_PROXIES.to_vec(),
+            rate_limits: None,
+            allowed_origins: None,
+            tcp: TcpOptions::default(),
+        }
+    }
+
+    pub fn allows_origin(&self, origin: Option<&str>) -> bool {
+        match (&self.allowed_origins, origin) {
+            (None, _) => true,
+            (Some(allowed), Some(origin)) => allowed.iter().any(|allowed| allowed == origin),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// Socket options set on accepted tcp connections
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TcpOptions {
+    /// Send small messages immediately instead of batching them (`TCP_NODELAY`)
+    pub nodelay: bool,
+    /// Idle time after which keepalive probes are send, keepalive is disabled if not set
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes
+    pub keepalive_interval: Option<Duration>,
+    /// Unanswered keepalive probes before the connection is closed
+    pub keepalive_retries: Option<u32>,
+    /// Time that send data can remain unacknowledged before the connection is closed, only supported on linux
+    pub user_timeout: Option<Duration>,
+    /// Kernel send buffer size in bytes (`SO_SNDBUF`), the system default is used if not set
+    pub send_buffer: Option<usize>,
+    /// Kernel receive buffer size in bytes (`SO_RCVBUF`), the system default is used if not set
+    pub recv_buffer: Option<usize>,
+}
+
+/// Buffer sizes as reported by the kernel, which can differ from the requested sizes
+#[derive(Debug, Clone, Copy)]
+pub struct BufferSizes {
+    pub send: usize,
+    pub recv: usize,
+}
+
+impl TcpOptions {
+    /// Set the buffer sizes on the listening socket, which are inherited by the accepted connections
+    ///
+    /// Returns the effective buffer sizes
+    pub fn apply_listener(&self, listener: &TcpListener) -> std::io::Result<BufferSizes> {
+        let socket = SockRef::from(listener);
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(BufferSizes {
+            send: socket.send_buffer_size()?,
+            recv: socket.recv_buffer_size()?,
+        })
+    }
+
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        let socket = SockRef::from(stream);
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            #[allow(unused_mut)]
+            let mut keepalive = TcpKeepalive::new().with_time(idle);
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "freebsd"
+            ))]
+            {
+                if let Some(interval) = self.keepalive_interval {
+                    keepalive = keepalive.with_interval(interval);
+                }
+                if let Some(retries) = self.keepalive_retries {
+                    keepalive = keepalive.with_retries(retries);
+                }
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.user_timeout.is_some() {
+            socket.set_tcp_user_timeout(self.user_timeout)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a trusted proxy as either a single ip or an ip range
+pub fn parse_proxy(s: &str) -> Option<IpNet> {
+    s.parse()
+        .ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
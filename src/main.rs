@@ -1,247 +1,176 @@
-mod session;
-
-use serde::{Deserialize, Serialize};
-use std::fmt::{Display, Formatter};
-
-use crate::session::Session;
-use dashmap::DashMap;
-use futures_channel::mpsc::{channel, Sender};
-use futures_util::future::select;
-use futures_util::StreamExt;
-use futures_util::TryStreamExt;
 use main_error::MainResult;
-use real_ip::{real_ip, IpNet};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::pin::pin;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
-use tokio_tungstenite::tungstenite::Message;
-use tracing::{debug, error, info, warn};
-
-type Tx = Sender<Message>;
-type PeerMap = DashMap<PeerId, Tx>;
-type Sessions = DashMap<String, Session>;
-
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
-#[serde(tag = "type")]
-#[serde(rename_all = "lowercase")]
-pub enum SyncCommand<'a> {
-    Create { session: &'a str, token: &'a str },
-    Join { session: &'a str },
-    Tick { session: &'a str, tick: u64 },
-    Play { session: &'a str, play: bool },
-    Clients { session: &'a str, count: usize },
-}
-
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
-pub struct PeerId(IpAddr, u64);
-
-impl Display for PeerId {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}#{}", self.0, self.1)
-    }
-}
+use std::time::Duration;
+use sync::capture::LogCapture;
+use sync::config::Config;
+use sync::listener::{ListenAddress, ListenerConfig};
+use sync::recorder::Record;
+use sync::replay::replay;
+use sync::task::spawn_named;
+use sync::transport::Frame;
+use sync::{Server, ServerBuilder};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
 
-pub struct Server {
-    id_counter: AtomicU64,
-    peers: PeerMap,
-    sessions: Sessions,
-}
-
-impl Server {
-    fn new() -> Self {
-        Server {
-            id_counter: AtomicU64::default(),
-            peers: PeerMap::with_capacity(128),
-            sessions: Sessions::with_capacity(64),
+#[tokio::main]
+async fn main() -> MainResult {
+    let log_capture = init_tracing();
+
+    let mut args = std::env::args().skip(1);
+    let mut check_config = false;
+    match args.next().as_deref() {
+        Some("replay") => {
+            let path = args.next().ok_or("usage: sync replay <recorder dump>")?;
+            return run_replay(&path);
         }
+        Some("--check-config") => check_config = true,
+        _ => {}
     }
 
-    fn next_peer_id(&self) -> u64 {
-        self.id_counter.fetch_add(1, Ordering::Relaxed)
+    let config = Config::from_env()?;
+    config.validate()?;
+    if check_config {
+        println!("configuration is valid");
+        return Ok(());
     }
-
-    fn send_text<S: Into<String>>(&self, peer: &PeerId, text: S) {
-        if let Some(mut tx) = self.peers.get_mut(peer) {
-            if let Err(e) = tx.try_send(Message::Text(text.into())) {
-                error!(%peer, ?e, "failed to send message to client")
+    config.metrics.install()?;
+    let listeners = config.listeners.clone();
+
+    let state = Arc::new(
+        ServerBuilder::new(config)
+            .log_capture(log_capture)
+            .build()?,
+    );
+    // reading the store blocks
+    let restoring = state.clone();
+    tokio::task::spawn_blocking(move || restoring.restore_sessions()).await??;
+
+    let mut accept_loops = Vec::new();
+    for listener in listeners {
+        let listener = Arc::new(listener);
+        match &listener.address {
+            ListenAddress::Tcp(address) => {
+                let tcp = TcpListener::bind(address).await?;
+                let buffers = listener.tcp.apply_listener(&tcp)?;
+                info!(
+                    listener = listener.name,
+                    send_buffer = buffers.send,
+                    recv_buffer = buffers.recv,
+                    "listening on: {address}"
+                );
+                let name = format!("accept {}", listener.name);
+                let accept_loop = serve_tcp(state.clone(), tcp, listener);
+                accept_loops.push(spawn_named(&name, accept_loop));
             }
-        }
-    }
-
-    pub fn send_command(&self, peer: &PeerId, command: &SyncCommand) {
-        self.send_text(peer, serde_json::to_string(command).unwrap())
-    }
-
-    pub fn send_to_clients(&self, session: &Session, command: &SyncCommand) {
-        let command_text = serde_json::to_string(command).unwrap();
-        for peer in session.clients() {
-            self.send_text(peer, &command_text);
-        }
-    }
-
-    fn handle_command(&self, command: SyncCommand, sender: PeerId) {
-        match &command {
-            SyncCommand::Create { session, token } => {
-                self.sessions
-                    .entry(session.to_string())
-                    .and_modify(|session| {
-                        if !session.set_owner(sender, token) {
-                            warn!(%sender, token, "invalid owner token");
-                        }
-                    })
-                    .or_insert_with(|| Session::new(sender, (*session).into(), token.to_string()));
-                self.gc_sessions();
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => {
+                // remove the socket left behind by a previous run
+                let _ = std::fs::remove_file(path);
+                let unix = tokio::net::UnixListener::bind(path)?;
+                info!(listener = listener.name, "listening on: {}", path.display());
+                let name = format!("accept {}", listener.name);
+                let accept_loop = serve_unix(state.clone(), unix, listener);
+                accept_loops.push(spawn_named(&name, accept_loop));
             }
-            SyncCommand::Join {
-                session: session_name,
-            } => match self.sessions.get_mut(*session_name) {
-                Some(mut session) => {
-                    for initial_command in session.initial_state() {
-                        self.send_command(&sender, &initial_command);
-                    }
-                    session.join(sender);
-                    self.send_command(
-                        &session.owner,
-                        &SyncCommand::Clients {
-                            session: session_name,
-                            count: session.clients().count(),
-                        },
-                    )
-                }
-                None => error!(session = session_name, "session not found for command"),
-            },
-            session_command @ (SyncCommand::Play { session, .. }
-            | SyncCommand::Tick { session, .. }) => match self.sessions.get_mut(*session) {
-                Some(mut session) => {
-                    if session.owner == sender {
-                        session.handle_command(session_command);
-                        self.send_to_clients(&session, &command);
-                    }
-                }
-                None => {
-                    error!(session, "session not found for command");
-                }
-            },
-            _ => {}
         }
     }
 
-    fn handle_disconnect(&self, peer: &PeerId) {
-        self.peers.remove(peer);
-        for mut session in self.sessions.iter_mut() {
-            session.remove_client(peer);
-            self.send_command(
-                &session.owner,
-                &SyncCommand::Clients {
-                    session: &session.token,
-                    count: session.clients().count(),
-                },
-            )
+    let housekeeping_state = state.clone();
+    spawn_named("housekeeping", async move {
+        let mut interval = tokio::time::interval(HOUSEKEEPING_INTERVAL);
+        loop {
+            interval.tick().await;
+            housekeeping_state.housekeeping();
         }
-    }
-
-    /// cleanup sessions where the owner hasn't reconnected in 15 minutes
-    fn gc_sessions(&self) {
-        let now = Instant::now();
-        self.sessions
-            .retain(|_, session| match session.inactive_time(now) {
-                Some(inactive) => inactive > TIMEOUT,
-                None => true,
-            });
-    }
-
-    async fn handle_connection(&self, raw_stream: TcpStream, addr: SocketAddr) {
-        debug!("incoming connection");
-
-        let mut remote_ip = addr.ip();
-
-        let ws_stream_res =
-            tokio_tungstenite::accept_hdr_async(raw_stream, |req: &Request, response: Response| {
-                if let Some(ip) = real_ip(req.headers(), addr.ip(), TRUSTED_PROXIES) {
-                    remote_ip = ip;
-                }
-                Ok::<_, ErrorResponse>(response)
-            })
-            .await;
-        let peer_id = PeerId(remote_ip, self.next_peer_id());
-        let ws_stream = match ws_stream_res {
-            Ok(ws_stream) => ws_stream,
-            Err(error) => {
-                error!(?error, %peer_id, "error while performing websocket handshake");
-                return;
-            }
-        };
-
-        info!(peer = %peer_id, "connection established");
-
-        // Insert the write part of this peer to the peer map.
-        let (tx, rx) = channel(16);
-        self.peers.insert(peer_id, tx);
-
-        let (outgoing, incoming) = ws_stream.split();
-
-        let handle_messages = incoming.try_for_each(|msg| async move {
-            if let Ok(message) = msg.to_text() {
-                match serde_json::from_str(message) {
-                    Ok(command) => {
-                        debug!(sender = %peer_id, message = ?command, "Received a message");
-                        self.handle_command(command, peer_id);
-                    }
-                    Err(e) => {
-                        warn!(sender = %peer_id, message, error = %e, "Error while decoding message");
-                    }
+    });
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut dump_signal = signal(SignalKind::user_defined1())?;
+        let recorder_state = state.clone();
+        spawn_named("recorder dump", async move {
+            while dump_signal.recv().await.is_some() {
+                match recorder_state.write_recorder_dump() {
+                    Ok(path) => info!(path = %path.display(), "wrote flight recorder"),
+                    Err(error) => error!(%error, "failed to write flight recorder"),
                 }
-            } else {
-                debug!("ignoring non-text message");
             }
-            Ok(())
         });
+    }
 
-        let receive_from_others = rx.map(Ok).forward(outgoing);
+    for accept_loop in accept_loops {
+        accept_loop.await?;
+    }
 
-        let handle_messages = pin!(handle_messages);
-        let receive_from_others = pin!(receive_from_others);
-        select(handle_messages, receive_from_others).await;
+    Ok(())
+}
 
-        info!(%peer_id, "disconnected");
-        self.handle_disconnect(&peer_id);
+async fn serve_tcp(state: Arc<Server>, tcp: TcpListener, listener: Arc<ListenerConfig>) {
+    // Let's spawn the handling of each connection in a separate task.
+    while let Ok((stream, addr)) = tcp.accept().await {
+        if let Err(error) = listener.tcp.apply(&stream) {
+            warn!(listener = listener.name, %error, "failed to set socket options");
+        }
+        let state = state.clone();
+        let listener = listener.clone();
+        spawn_named(&format!("connection {addr}"), async move {
+            state.handle_connection(stream, addr.ip(), &listener).await
+        });
     }
 }
 
-const TIMEOUT: Duration = Duration::from_secs(15 * 60);
-
-#[tokio::main]
-async fn main() -> MainResult {
-    tracing_subscriber::fmt::init();
-
-    let port: u16 = std::env::var("PORT")
-        .unwrap_or_else(|_| "80".to_string())
-        .parse()?;
-    let listen_address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
-
-    let state = Arc::new(Server::new());
-
-    // Create the event loop and TCP listener we'll accept connections on.
-    let listener = TcpListener::bind(&listen_address)
-        .await
-        .expect("Failed to bind");
-
-    info!("listening on: {:?}", listen_address);
-
-    // Let's spawn the handling of each connection in a separate task.
-    while let Ok((stream, addr)) = listener.accept().await {
+/// Connections on unix sockets come from a local proxy and are handled as coming from localhost
+#[cfg(unix)]
+async fn serve_unix(
+    state: Arc<Server>,
+    unix: tokio::net::UnixListener,
+    listener: Arc<ListenerConfig>,
+) {
+    while let Ok((stream, _)) = unix.accept().await {
         let state = state.clone();
-        tokio::spawn(async move { state.handle_connection(stream, addr).await });
+        let listener = listener.clone();
+        spawn_named(&format!("connection {}", listener.name), async move {
+            state
+                .handle_connection(stream, IpAddr::V4(Ipv4Addr::LOCALHOST), &listener)
+                .await
+        });
     }
+}
 
-    Ok(())
+/// Log to stdout and to the capture files of sessions, with the `console` feature tokio-console can also be attached
+fn init_tracing() -> Arc<LogCapture> {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+
+    let capture = LogCapture::new();
+    #[cfg(feature = "console")]
+    let console = Some(console_subscriber::spawn());
+    #[cfg(not(feature = "console"))]
+    let console: Option<tracing_subscriber::layer::Identity> = None;
+    tracing_subscriber::registry()
+        .with(console)
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+        .with(capture.layer())
+        .init();
+    capture
 }
 
-const TRUSTED_PROXIES: &[IpNet] = &[IpNet::new_assert(
-    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)),
-    8,
-)];
+/// Replay a flight recorder dump and print everything the server sends in response
+fn run_replay(path: &str) -> MainResult {
+    let recording: BTreeMap<String, Vec<Record>> = serde_json::from_slice(&std::fs::read(path)?)?;
+    for message in replay(recording) {
+        match message.frame {
+            Frame::Text(text) => println!("{} {text}", message.peer),
+            Frame::Binary(data) => println!("{} <{} bytes>", message.peer, data.len()),
+            Frame::Ping(_) | Frame::Pong(_) => {}
+            Frame::Close { code, reason } => println!("{} <close {code} {reason}>", message.peer),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use crate::peer::PeerId;
+use crate::{ErrorCode, SyncCommand};
+
+/// Reason for refusing a command, send back to the peer as error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejection {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Rejection {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Hooks to observe and veto commands without modifying the server
+///
+/// All hooks are invoked synchronously while handling the command and should not block.
+pub trait Middleware: Send + Sync {
+    /// Called before a command is handled, returning an error rejects the command
+    fn before_command(&self, _peer: &PeerId, _command: &SyncCommand) -> Result<(), Rejection> {
+        Ok(())
+    }
+
+    /// Called after a command was handled
+    fn after_command(&self, _peer: &PeerId, _command: &SyncCommand) {}
+
+    /// Called when a peer joined a session as client
+    fn on_join(&self, _peer: &PeerId, _session: &str) {}
+
+    /// Called when a client left a session
+    fn on_leave(&self, _peer: &PeerId, _session: &str) {}
+}
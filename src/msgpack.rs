@@ -0,0 +1,383 @@
+//! MessagePack encoding of messages, for clients that negotiated the `sync-msgpack` websocket subprotocol
+//!
+//! Messages are transcoded from and to json at the transport, so the MessagePack messages have the same
+//! structure as their json counterparts. Only the types that can be represented in json are supported,
+//! maps need string keys and binary or extension types are rejected.
+
+use serde_json::{Map, Number, Value};
+
+/// Maximum nesting of arrays and maps, the same limit serde_json uses
+const MAX_DEPTH: usize = 128;
+
+/// Encode a json value as MessagePack
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+/// Decode a MessagePack message into a json value, `None` if the data isn't a single valid value
+pub fn decode(data: &[u8]) -> Option<Value> {
+    let mut reader = Reader { data, depth: 0 };
+    let value = reader.read_value()?;
+    reader.data.is_empty().then_some(value)
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => write_number(out, number),
+        Value::String(string) => write_str(out, string),
+        Value::Array(items) => {
+            write_length(out, items.len(), 0x90, 0xdc);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Value::Object(map) => {
+            write_length(out, map.len(), 0x80, 0xde);
+            for (key, value) in map {
+                write_str(out, key);
+                write_value(out, value);
+            }
+        }
+    }
+}
+
+fn write_number(out: &mut Vec<u8>, number: &Number) {
+    if let Some(int) = number.as_u64() {
+        match int {
+            0..=0x7f => out.push(int as u8),
+            0x80..=0xff => out.extend_from_slice(&[0xcc, int as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend_from_slice(&(int as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend_from_slice(&(int as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend_from_slice(&int.to_be_bytes());
+            }
+        }
+    } else if let Some(int) = number.as_i64() {
+        // only negative numbers are left
+        match int {
+            -32..=-1 => out.push(int as u8),
+            -0x80..=-33 => out.extend_from_slice(&[0xd0, int as u8]),
+            -0x8000..=-0x81 => {
+                out.push(0xd1);
+                out.extend_from_slice(&(int as i16).to_be_bytes());
+            }
+            -0x8000_0000..=-0x8001 => {
+                out.push(0xd2);
+                out.extend_from_slice(&(int as i32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xd3);
+                out.extend_from_slice(&int.to_be_bytes());
+            }
+        }
+    } else {
+        out.push(0xcb);
+        out.extend_from_slice(&number.as_f64().unwrap_or_default().to_be_bytes());
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, string: &str) {
+    let length = string.len();
+    if length < 32 {
+        out.push(0xa0 | length as u8);
+    } else if length <= 0xff {
+        out.extend_from_slice(&[0xd9, length as u8]);
+    } else if length <= 0xffff {
+        out.push(0xda);
+        out.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        out.push(0xdb);
+        out.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+    out.extend_from_slice(string.as_bytes());
+}
+
+/// Write the length of an array or map, `marker` is the 16 bit variant which is followed by the 32 bit variant
+fn write_length(out: &mut Vec<u8>, length: usize, fix: u8, marker: u8) {
+    if length < 16 {
+        out.push(fix | length as u8);
+    } else if length <= 0xffff {
+        out.push(marker);
+        out.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        out.push(marker + 1);
+        out.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Option<&'a [u8]> {
+        let (taken, rest) = self.data.split_at_checked(length)?;
+        self.data = rest;
+        Some(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (taken, rest) = self.data.split_first_chunk::<N>()?;
+        self.data = rest;
+        Some(*taken)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        self.take_array::<1>().map(|[byte]| byte)
+    }
+
+    fn read_length(&mut self, bytes: usize) -> Option<usize> {
+        Some(match bytes {
+            1 => self.read_u8()? as usize,
+            2 => u16::from_be_bytes(self.take_array()?) as usize,
+            _ => u32::from_be_bytes(self.take_array()?) as usize,
+        })
+    }
+
+    fn read_value(&mut self) -> Option<Value> {
+        let marker = self.read_u8()?;
+        Some(match marker {
+            0x00..=0x7f => Value::from(marker),
+            0x80..=0x8f => self.read_map((marker & 0x0f) as usize)?,
+            0x90..=0x9f => self.read_array((marker & 0x0f) as usize)?,
+            0xa0..=0xbf => self.read_str((marker & 0x1f) as usize)?,
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => float(f32::from_be_bytes(self.take_array()?) as f64)?,
+            0xcb => float(f64::from_be_bytes(self.take_array()?))?,
+            0xcc => Value::from(self.read_u8()?),
+            0xcd => Value::from(u16::from_be_bytes(self.take_array()?)),
+            0xce => Value::from(u32::from_be_bytes(self.take_array()?)),
+            0xcf => Value::from(u64::from_be_bytes(self.take_array()?)),
+            0xd0 => Value::from(i8::from_be_bytes(self.take_array()?)),
+            0xd1 => Value::from(i16::from_be_bytes(self.take_array()?)),
+            0xd2 => Value::from(i32::from_be_bytes(self.take_array()?)),
+            0xd3 => Value::from(i64::from_be_bytes(self.take_array()?)),
+            0xd9 => {
+                let length = self.read_length(1)?;
+                self.read_str(length)?
+            }
+            0xda => {
+                let length = self.read_length(2)?;
+                self.read_str(length)?
+            }
+            0xdb => {
+                let length = self.read_length(4)?;
+                self.read_str(length)?
+            }
+            0xdc => {
+                let length = self.read_length(2)?;
+                self.read_array(length)?
+            }
+            0xdd => {
+                let length = self.read_length(4)?;
+                self.read_array(length)?
+            }
+            0xde => {
+                let length = self.read_length(2)?;
+                self.read_map(length)?
+            }
+            0xdf => {
+                let length = self.read_length(4)?;
+                self.read_map(length)?
+            }
+            0xe0..=0xff => Value::from(marker as i8),
+            // binary, extension types and the unused marker
+            _ => return None,
+        })
+    }
+
+    fn read_str(&mut self, length: usize) -> Option<Value> {
+        let bytes = self.take(length)?;
+        Some(Value::String(std::str::from_utf8(bytes).ok()?.to_string()))
+    }
+
+    fn read_array(&mut self, length: usize) -> Option<Value> {
+        self.nested(|reader| {
+            // every item takes at least a byte, don't trust the length for the allocation
+            let mut items = Vec::with_capacity(length.min(reader.data.len()));
+            for _ in 0..length {
+                items.push(reader.read_value()?);
+            }
+            Some(Value::Array(items))
+        })
+    }
+
+    fn read_map(&mut self, length: usize) -> Option<Value> {
+        self.nested(|reader| {
+            let mut map = Map::new();
+            for _ in 0..length {
+                let Value::String(key) = reader.read_value()? else {
+                    return None;
+                };
+                let value = reader.read_value()?;
+                map.insert(key, value);
+            }
+            Some(Value::Object(map))
+        })
+    }
+
+    fn nested(&mut self, read: impl FnOnce(&mut Self) -> Option<Value>) -> Option<Value> {
+        if self.depth >= MAX_DEPTH {
+            return None;
+        }
+        self.depth += 1;
+        let value = read(self);
+        self.depth -= 1;
+        value
+    }
+}
+
+/// Json can't represent infinite or NaN floats
+fn float(float: f64) -> Option<Value> {
+    Number::from_f64(float).map(Value::Number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, MAX_DEPTH};
+    use serde_json::{json, Map, Value};
+
+    /// Encode the value, check the marker it's encoded with and that it decodes to the same value
+    fn round_trip(value: Value, marker: u8, length: usize) {
+        let encoded = encode(&value);
+        assert_eq!(encoded[0], marker, "marker of {value}");
+        assert_eq!(encoded.len(), length, "length of {value}");
+        assert_eq!(decode(&encoded), Some(value));
+    }
+
+    #[test]
+    fn unsigned_integers() {
+        round_trip(json!(0), 0x00, 1);
+        round_trip(json!(0x7f), 0x7f, 1);
+        round_trip(json!(0x80), 0xcc, 2);
+        round_trip(json!(0xff), 0xcc, 2);
+        round_trip(json!(0x100), 0xcd, 3);
+        round_trip(json!(0xffff), 0xcd, 3);
+        round_trip(json!(0x1_0000), 0xce, 5);
+        round_trip(json!(0xffff_ffff_u64), 0xce, 5);
+        round_trip(json!(0x1_0000_0000_u64), 0xcf, 9);
+        round_trip(json!(u64::MAX), 0xcf, 9);
+    }
+
+    #[test]
+    fn negative_integers() {
+        round_trip(json!(-1), 0xff, 1);
+        round_trip(json!(-32), 0xe0, 1);
+        round_trip(json!(-33), 0xd0, 2);
+        round_trip(json!(-0x80), 0xd0, 2);
+        round_trip(json!(-0x81), 0xd1, 3);
+        round_trip(json!(-0x8000), 0xd1, 3);
+        round_trip(json!(-0x8001), 0xd2, 5);
+        round_trip(json!(i32::MIN), 0xd2, 5);
+        round_trip(json!(i32::MIN as i64 - 1), 0xd3, 9);
+        round_trip(json!(i64::MIN), 0xd3, 9);
+    }
+
+    #[test]
+    fn other_scalars() {
+        round_trip(json!(null), 0xc0, 1);
+        round_trip(json!(false), 0xc2, 1);
+        round_trip(json!(true), 0xc3, 1);
+        round_trip(json!(1.5), 0xcb, 9);
+        // f32 is only decoded
+        assert_eq!(decode(&[0xca, 0x3f, 0xc0, 0, 0]), Some(json!(1.5)));
+    }
+
+    #[test]
+    fn strings() {
+        round_trip(json!(""), 0xa0, 1);
+        round_trip(json!("a".repeat(31)), 0xbf, 32);
+        round_trip(json!("a".repeat(32)), 0xd9, 34);
+        round_trip(json!("a".repeat(0xff)), 0xd9, 0x101);
+        round_trip(json!("a".repeat(0x100)), 0xda, 0x103);
+        round_trip(json!("a".repeat(0xffff)), 0xda, 0x1_0002);
+        round_trip(json!("a".repeat(0x1_0000)), 0xdb, 0x1_0005);
+        round_trip(json!("ü"), 0xa2, 3);
+    }
+
+    #[test]
+    fn arrays_and_maps() {
+        round_trip(json!([]), 0x90, 1);
+        round_trip(Value::Array(vec![json!(0); 15]), 0x9f, 16);
+        round_trip(Value::Array(vec![json!(0); 16]), 0xdc, 19);
+        round_trip(Value::Array(vec![json!(0); 0x1_0000]), 0xdd, 0x1_0005);
+        round_trip(json!({}), 0x80, 1);
+
+        let map = |length: usize| {
+            (0..length)
+                .map(|i| (format!("{i:05}"), json!(i % 2 == 0)))
+                .collect::<Map<_, _>>()
+        };
+        round_trip(Value::Object(map(15)), 0x8f, 1 + 15 * 7);
+        round_trip(Value::Object(map(16)), 0xde, 3 + 16 * 7);
+        let message =
+            json!({"type": "tick", "session": "demo", "tick": 1234, "nested": [{"a": null}]});
+        assert_eq!(decode(&encode(&message)), Some(message));
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let encoded = encode(&json!({"type": "tick", "tick": 0x1_0000, "name": "a".repeat(40)}));
+        for length in 0..encoded.len() {
+            assert_eq!(decode(&encoded[..length]), None, "truncated to {length}");
+        }
+        assert_eq!(decode(&[0xcd, 0x01]), None);
+        assert_eq!(decode(&[0xdc, 0x00, 0x02, 0x01]), None);
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        assert_eq!(decode(&[0x01, 0x02]), None);
+        assert_eq!(decode(&[0x90, 0xc0]), None);
+    }
+
+    #[test]
+    fn non_string_keys_are_rejected() {
+        assert_eq!(decode(&[0x81, 0x01, 0x02]), None);
+        assert_eq!(decode(&[0x81, 0xc0, 0x02]), None);
+    }
+
+    #[test]
+    fn binary_and_extension_types_are_rejected() {
+        for marker in [
+            0xc1, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8,
+        ] {
+            assert_eq!(
+                decode(&[marker, 0x01, 0x00, 0x00, 0x00]),
+                None,
+                "marker {marker:#x}"
+            );
+        }
+        // binary data with a valid length
+        assert_eq!(decode(&[0xc4, 0x01, 0x00]), None);
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| {
+            let mut data = vec![0x91; depth];
+            data.push(0xc0);
+            data
+        };
+        assert!(decode(&nested(MAX_DEPTH)).is_some());
+        assert_eq!(decode(&nested(MAX_DEPTH + 1)), None);
+        let mut maps = [0x81, 0xa1, b'a'].repeat(MAX_DEPTH + 1);
+        maps.push(0xc0);
+        assert_eq!(decode(&maps), None);
+    }
+}
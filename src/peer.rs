@@ -0,0 +1,372 @@
+use crate::auth::Identity;
+use crate::contention::{Contention, InstrumentedMap};
+use crate::ratelimit::{
+    FloodPolicy, RateLimit, RateLimitResult, RateLimiter, RateLimits, Violation, ViolationCounter,
+};
+use crate::transport::Frame;
+use futures_channel::mpsc::{Sender, TrySendError};
+use rand::Rng;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub type Tx = Sender<Frame>;
+
+/// Maximum number of sessions a single connection can join at the same time
+pub const MAX_JOINED_SESSIONS: usize = 16;
+
+/// Time between the websocket pings used to measure the round trip time of clients and detect idle peers
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub struct PeerId(IpAddr, u64);
+
+impl PeerId {
+    pub fn new(ip: IpAddr, id: u64) -> Self {
+        PeerId(ip, id)
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.0
+    }
+
+    /// Parse a peer id in the `<ip>#<id>` form it is displayed as
+    pub fn parse(s: &str) -> Option<Self> {
+        let (ip, id) = s.rsplit_once('#')?;
+        Some(PeerId(ip.parse().ok()?, id.parse().ok()?))
+    }
+}
+
+impl Display for PeerId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}#{}", self.0, self.1)
+    }
+}
+
+/// Short random id for a connection, included in all log events of the connection and send to the client
+/// so it can be referenced in support requests
+pub fn generate_connection_id() -> String {
+    let mut rng = rand::thread_rng();
+    (0..8)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+/// Information about a peer gathered during the handshake
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    pub connection_id: String,
+    /// Request id set by a proxy using the `X-Request-Id` header
+    pub request_id: Option<String>,
+    pub identity: Identity,
+    pub user_agent: Option<String>,
+    /// Version reported by the client using the `client_version` query parameter
+    pub client_version: Option<String>,
+    /// Rate limits of the listener the peer connected to, instead of the global limits
+    pub rate_limits: Option<Arc<RateLimits>>,
+}
+
+#[derive(Debug)]
+pub struct Peer {
+    tx: Tx,
+    info: ConnectionInfo,
+    connected_at: Instant,
+    last_activity: Instant,
+    /// Protocol version negotiated in the hello handshake, peers that skip it speak the first version
+    protocol_version: u32,
+    /// Optional features negotiated in the hello handshake
+    capabilities: Vec<String>,
+    /// Names of the sessions this peer has joined as a client
+    sessions: Vec<String>,
+    rate_limiter: RateLimiter,
+    violations: ViolationCounter,
+    /// Number of frames waiting to be send, decremented by the connection task
+    queued: Arc<AtomicUsize>,
+    /// Highest number of frames that were waiting to be send at once
+    high_water: usize,
+    /// Number of pings send, the payload of a ping is its number so the pong can be matched to it
+    pings: u64,
+    /// When the last ping was send, cleared once it's answered
+    ping_sent: Option<Instant>,
+    last_ping: Option<Instant>,
+}
+
+impl Peer {
+    pub fn new(tx: Tx, info: ConnectionInfo) -> Self {
+        let now = Instant::now();
+        Peer {
+            tx,
+            info,
+            connected_at: now,
+            last_activity: now,
+            protocol_version: 1,
+            capabilities: Vec::new(),
+            sessions: Vec::new(),
+            rate_limiter: RateLimiter::default(),
+            violations: ViolationCounter::new(now),
+            queued: Arc::default(),
+            high_water: 0,
+            pings: 0,
+            ping_sent: None,
+            last_ping: None,
+        }
+    }
+
+    pub fn queue(&self) -> Arc<AtomicUsize> {
+        self.queued.clone()
+    }
+
+    pub fn high_water(&self) -> usize {
+        self.high_water
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.info.identity
+    }
+
+    pub fn connection_id(&self) -> &str {
+        &self.info.connection_id
+    }
+
+    pub fn user_agent(&self) -> Option<&str> {
+        self.info.user_agent.as_deref()
+    }
+
+    pub fn client_version(&self) -> Option<&str> {
+        self.info.client_version.as_deref()
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|negotiated| negotiated == capability)
+    }
+
+    pub fn connected_for(&self, now: Instant) -> Duration {
+        now.duration_since(self.connected_at)
+    }
+
+    pub fn idle_for(&self, now: Instant) -> Duration {
+        now.duration_since(self.last_activity)
+    }
+
+    pub fn sessions(&self) -> impl Iterator<Item = &str> {
+        self.sessions.iter().map(String::as_str)
+    }
+}
+
+/// Registry of all connected peers
+#[derive(Debug)]
+pub struct Peers {
+    peers: InstrumentedMap<PeerId, Peer>,
+}
+
+impl Peers {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Peers {
+            peers: InstrumentedMap::with_capacity("peers", capacity),
+        }
+    }
+
+    pub fn insert(&self, id: PeerId, peer: Peer) {
+        self.peers.insert(id, peer);
+    }
+
+    pub fn contains(&self, id: &PeerId) -> bool {
+        self.peers.contains_key(id)
+    }
+
+    pub fn identity(&self, id: &PeerId) -> Option<Identity> {
+        self.peers.get(id).map(|peer| peer.identity().clone())
+    }
+
+    pub fn connection_id(&self, id: &PeerId) -> Option<String> {
+        self.peers
+            .get(id)
+            .map(|peer| peer.connection_id().to_string())
+    }
+
+    pub fn remove(&self, id: &PeerId) -> Option<Peer> {
+        self.peers.remove(id).map(|(_, peer)| peer)
+    }
+
+    /// The peers that waited the longest for their lock
+    pub fn hottest(&self, limit: usize) -> Vec<Contention> {
+        self.peers.hottest(limit)
+    }
+
+    /// Queue a message for a peer, returning the number of queued frames
+    ///
+    /// Sending to a peer that isn't connected (anymore) is a no-op and returns `None`
+    pub fn send(&self, id: &PeerId, frame: Frame) -> Result<Option<usize>, TrySendError<Frame>> {
+        let Some(mut peer) = self.peers.get_mut(id) else {
+            return Ok(None);
+        };
+        peer.tx.try_send(frame)?;
+        let queued = peer.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        peer.high_water = peer.high_water.max(queued);
+        Ok(Some(queued))
+    }
+
+    pub fn has_capability(&self, id: &PeerId, capability: &str) -> bool {
+        self.peers
+            .get(id)
+            .is_some_and(|peer| peer.has_capability(capability))
+    }
+
+    /// Store the result of the hello handshake
+    pub fn set_protocol(&self, id: &PeerId, version: u32, capabilities: Vec<String>) {
+        if let Some(mut peer) = self.peers.get_mut(id) {
+            peer.protocol_version = version;
+            peer.capabilities = capabilities;
+        }
+    }
+
+    /// Add capabilities declared after the hello handshake
+    pub fn add_capabilities(&self, id: &PeerId, capabilities: impl IntoIterator<Item = String>) {
+        if let Some(mut peer) = self.peers.get_mut(id) {
+            for capability in capabilities {
+                if !peer.capabilities.contains(&capability) {
+                    peer.capabilities.push(capability);
+                }
+            }
+        }
+    }
+
+    /// Mark the peer as active
+    pub fn touch(&self, id: &PeerId, now: Instant) {
+        if let Some(mut peer) = self.peers.get_mut(id) {
+            peer.last_activity = now;
+        }
+    }
+
+    /// The rate limit for a command type if the peer has its own limits
+    pub fn rate_limit(&self, id: &PeerId, kind: &str) -> Option<RateLimit> {
+        let peer = self.peers.get(id)?;
+        peer.info
+            .rate_limits
+            .as_ref()
+            .map(|limits| limits.get(kind))
+    }
+
+    /// Check the command rate limit for a peer, unknown peers are always limited
+    pub fn check_rate(
+        &self,
+        id: &PeerId,
+        kind: &'static str,
+        limit: RateLimit,
+        now: Instant,
+    ) -> RateLimitResult {
+        match self.peers.get_mut(id) {
+            Some(mut peer) => peer.rate_limiter.check(kind, limit, now),
+            None => RateLimitResult::StillLimited,
+        }
+    }
+
+    /// Record a protocol violation, returns true if the peer should be disconnected
+    pub fn record_violation(
+        &self,
+        id: &PeerId,
+        violation: Violation,
+        policy: &FloodPolicy,
+        now: Instant,
+    ) -> bool {
+        match self.peers.get_mut(id) {
+            Some(mut peer) => peer.violations.record(violation, policy, now),
+            None => false,
+        }
+    }
+
+    /// Start a ping for every peer that wasn't pinged within the ping interval, returns the payload for each ping
+    pub fn due_pings(&self, now: Instant) -> Vec<(PeerId, Vec<u8>)> {
+        self.peers
+            .iter_mut()
+            .filter(|peer| {
+                peer.last_ping
+                    .is_none_or(|last| now.duration_since(last) >= PING_INTERVAL)
+            })
+            .map(|mut peer| {
+                peer.pings += 1;
+                peer.ping_sent = Some(now);
+                peer.last_ping = Some(now);
+                (*peer.key(), peer.pings.to_be_bytes().to_vec())
+            })
+            .collect()
+    }
+
+    /// Match a pong to the last ping, returns the round trip time and the sessions the peer joined
+    ///
+    /// Every pong marks the peer as active, but unsolicited pongs and answers to older pings aren't measured
+    pub fn pong(
+        &self,
+        id: &PeerId,
+        payload: &[u8],
+        now: Instant,
+    ) -> Option<(Duration, Vec<String>)> {
+        let mut peer = self.peers.get_mut(id)?;
+        peer.last_activity = now;
+        if payload != peer.pings.to_be_bytes() {
+            return None;
+        }
+        let sent = peer.ping_sent.take()?;
+        Some((now.duration_since(sent), peer.sessions.clone()))
+    }
+
+    /// The peers that neither send a message nor answered a ping for longer than `timeout`
+    pub fn idle_peers(&self, now: Instant, timeout: Duration) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|peer| peer.idle_for(now) > timeout)
+            .map(|peer| *peer.key())
+            .collect()
+    }
+
+    /// Record that a peer joined a session, returns false if the peer already joined the maximum number of sessions
+    pub fn join_session(&self, id: &PeerId, session: &str) -> bool {
+        let Some(mut peer) = self.peers.get_mut(id) else {
+            return true;
+        };
+        if peer.sessions.iter().any(|joined| joined == session) {
+            return true;
+        }
+        if peer.sessions.len() >= MAX_JOINED_SESSIONS {
+            return false;
+        }
+        peer.sessions.push(session.to_string());
+        true
+    }
+
+    /// Record that a peer left a session, returns false if the peer didn't join the session
+    pub fn leave_session(&self, id: &PeerId, session: &str) -> bool {
+        let Some(mut peer) = self.peers.get_mut(id) else {
+            return false;
+        };
+        let count = peer.sessions.len();
+        peer.sessions.retain(|joined| joined != session);
+        peer.sessions.len() != count
+    }
+
+    pub fn rename_session(&self, id: &PeerId, session: &str, new_name: &str) {
+        if let Some(mut peer) = self.peers.get_mut(id) {
+            for joined in peer.sessions.iter_mut().filter(|joined| *joined == session) {
+                *joined = new_name.to_string();
+            }
+        }
+    }
+}
+
+/// Get the value of a query parameter from the handshake request
+pub fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
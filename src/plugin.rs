@@ -0,0 +1,188 @@
+//! Sandboxed wasm plugins that can react to session events
+//!
+//! A plugin is a wasm module exporting its `memory`, an `alloc(len) -> ptr` function and
+//! `on_event(ptr, len)` which is called with a json encoded [`PluginEvent`].
+//! Plugins can call the imported `env.emit(ptr, len)` with a json encoded command to send it
+//! into a session as if it was send by the session owner.
+
+use crate::config::PluginConfig;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+use wasmtime::{Caller, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum PluginEvent<'a> {
+    Command {
+        peer: String,
+        command: &'a crate::SyncCommand<'a>,
+    },
+    Join {
+        peer: String,
+        session: &'a str,
+    },
+    Leave {
+        peer: String,
+        session: &'a str,
+    },
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    Io(std::io::Error),
+    Wasm(wasmtime::Error),
+}
+
+impl Display for PluginError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginError::Io(e) => write!(f, "failed to read plugin: {e}"),
+            PluginError::Wasm(e) => write!(f, "invalid plugin: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+impl From<std::io::Error> for PluginError {
+    fn from(value: std::io::Error) -> Self {
+        PluginError::Io(value)
+    }
+}
+
+impl From<wasmtime::Error> for PluginError {
+    fn from(value: wasmtime::Error) -> Self {
+        PluginError::Wasm(value)
+    }
+}
+
+struct PluginState {
+    limits: StoreLimits,
+    /// Commands emitted during the current event
+    emitted: Vec<String>,
+}
+
+struct Plugin {
+    name: String,
+    store: Store<PluginState>,
+    instance: Instance,
+}
+
+impl Plugin {
+    fn call(&mut self, event: &str, fuel: u64) -> wasmtime::Result<Vec<String>> {
+        self.store.set_fuel(fuel)?;
+        self.store.data_mut().emitted.clear();
+        let memory = self
+            .instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("plugin doesn't export memory"))?;
+        let alloc = self
+            .instance
+            .get_typed_func::<i32, i32>(&mut self.store, "alloc")?;
+        let on_event = self
+            .instance
+            .get_typed_func::<(i32, i32), ()>(&mut self.store, "on_event")?;
+
+        let len = i32::try_from(event.len())?;
+        let ptr = alloc.call(&mut self.store, len)?;
+        memory.write(&mut self.store, ptr as usize, event.as_bytes())?;
+        on_event.call(&mut self.store, (ptr, len))?;
+        Ok(std::mem::take(&mut self.store.data_mut().emitted))
+    }
+}
+
+/// Loads all plugins from a directory and dispatches events to them
+pub struct PluginHost {
+    plugins: Vec<Mutex<Plugin>>,
+    fuel: u64,
+}
+
+impl PluginHost {
+    pub fn load(dir: &Path, config: &PluginConfig) -> Result<Self, PluginError> {
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config)?;
+
+        let mut linker = Linker::new(&engine);
+        linker.func_wrap(
+            "env",
+            "emit",
+            |mut caller: Caller<'_, PluginState>, ptr: i32, len: i32| -> wasmtime::Result<()> {
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|export| export.into_memory())
+                    .ok_or_else(|| wasmtime::Error::msg("plugin doesn't export memory"))?;
+                let mut buffer = vec![0; usize::try_from(len)?];
+                memory.read(&caller, usize::try_from(ptr)?, &mut buffer)?;
+                let command = String::from_utf8(buffer)?;
+                caller.data_mut().emitted.push(command);
+                Ok(())
+            },
+        )?;
+
+        let mut plugins = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("wasm" | "wat")
+            ) {
+                continue;
+            }
+            let name = path.display().to_string();
+            let module = Module::from_file(&engine, &path)?;
+            let mut store = Store::new(
+                &engine,
+                PluginState {
+                    limits: StoreLimitsBuilder::new()
+                        .memory_size(config.memory_limit)
+                        .instances(1)
+                        .build(),
+                    emitted: Vec::new(),
+                },
+            );
+            store.limiter(|state| &mut state.limits);
+            store.set_fuel(config.fuel)?;
+            let instance = linker.instantiate(&mut store, &module)?;
+            info!(plugin = name, "loaded plugin");
+            plugins.push(Mutex::new(Plugin {
+                name,
+                store,
+                instance,
+            }));
+        }
+
+        Ok(PluginHost {
+            plugins,
+            fuel: config.fuel,
+        })
+    }
+
+    /// Send an event to all plugins, returning the commands they emitted
+    pub fn dispatch(&self, event: &PluginEvent) -> Vec<String> {
+        if self.plugins.is_empty() {
+            return Vec::new();
+        }
+        let event = match serde_json::to_string(event) {
+            Ok(event) => event,
+            Err(error) => {
+                error!(%error, "failed to encode plugin event");
+                return Vec::new();
+            }
+        };
+
+        let mut emitted = Vec::new();
+        for plugin in &self.plugins {
+            let mut plugin = plugin.lock().unwrap();
+            match plugin.call(&event, self.fuel) {
+                Ok(commands) => emitted.extend(commands),
+                Err(error) => warn!(plugin = plugin.name, %error, "plugin failed to handle event"),
+            }
+        }
+        emitted
+    }
+}
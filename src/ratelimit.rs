@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::num::ParseFloatError;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Sustained number of commands allowed per second
+    pub rate: f64,
+    /// Number of commands that can be sent in a burst
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub const fn new(rate: f64, burst: f64) -> Self {
+        RateLimit { rate, burst }
+    }
+}
+
+/// Parse a rate limit in the form of `<rate>[:<burst>]`, the burst defaults to the rate
+impl FromStr for RateLimit {
+    type Err = ParseFloatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((rate, burst)) => Ok(RateLimit::new(rate.trim().parse()?, burst.trim().parse()?)),
+            None => {
+                let rate = s.trim().parse()?;
+                Ok(RateLimit::new(rate, rate))
+            }
+        }
+    }
+}
+
+/// Rate limits per command type
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    limits: HashMap<String, RateLimit>,
+    default: RateLimit,
+}
+
+impl RateLimits {
+    pub fn get(&self, kind: &str) -> RateLimit {
+        self.limits.get(kind).copied().unwrap_or(self.default)
+    }
+
+    /// Override the limit for a command type, `default` sets the limit for all unlisted types
+    pub fn set(&mut self, kind: &str, limit: RateLimit) {
+        if kind == "default" {
+            self.default = limit;
+        } else {
+            self.limits.insert(kind.to_string(), limit);
+        }
+    }
+
+    /// No limits for any command type
+    pub fn unlimited() -> Self {
+        RateLimits {
+            limits: HashMap::new(),
+            default: RateLimit::new(f64::INFINITY, f64::INFINITY),
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            limits: [
+                ("create", RateLimit::new(0.2, 5.0)),
+                ("join", RateLimit::new(2.0, 10.0)),
+                ("list", RateLimit::new(1.0, 5.0)),
+                ("exists", RateLimit::new(1.0, 5.0)),
+                ("stats", RateLimit::new(1.0, 5.0)),
+                ("history", RateLimit::new(0.2, 2.0)),
+                ("time", RateLimit::new(2.0, 10.0)),
+                ("tick", RateLimit::new(100.0, 200.0)),
+                ("play", RateLimit::new(10.0, 20.0)),
+                ("schedule", RateLimit::new(1.0, 5.0)),
+                ("setmeta", RateLimit::new(1.0, 5.0)),
+                ("react", RateLimit::new(2.0, 5.0)),
+                ("presence", RateLimit::new(1.0, 5.0)),
+                ("resync", RateLimit::new(0.5, 3.0)),
+                ("progress", RateLimit::new(2.0, 5.0)),
+                ("ext", RateLimit::new(5.0, 10.0)),
+                ("signal", RateLimit::new(10.0, 50.0)),
+                ("annotate", RateLimit::new(20.0, 40.0)),
+                ("listen", RateLimit::new(2.0, 10.0)),
+                ("relay", RateLimit::new(50.0, 100.0)),
+            ]
+            .into_iter()
+            .map(|(kind, limit)| (kind.to_string(), limit))
+            .collect(),
+            default: RateLimit::new(10.0, 20.0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the previous command was rejected
+    limited: bool,
+}
+
+pub enum RateLimitResult {
+    Allowed,
+    /// The command was rejected and is the first rejection since the last accepted command
+    Limited,
+    /// The command was rejected and the peer was already notified about being limited
+    StillLimited,
+}
+
+/// Per peer token buckets for every command type
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: HashMap<&'static str, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn check(&mut self, kind: &'static str, limit: RateLimit, now: Instant) -> RateLimitResult {
+        self.check_cost(kind, limit, 1.0, now)
+    }
+
+    /// Check the limit for an action that takes `cost` tokens, like sending a number of bytes
+    pub fn check_cost(
+        &mut self,
+        kind: &'static str,
+        limit: RateLimit,
+        cost: f64,
+        now: Instant,
+    ) -> RateLimitResult {
+        let bucket = self.buckets.entry(kind).or_insert(TokenBucket {
+            tokens: limit.burst,
+            updated: now,
+            limited: false,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            bucket.limited = false;
+            RateLimitResult::Allowed
+        } else if bucket.limited {
+            RateLimitResult::StillLimited
+        } else {
+            bucket.limited = true;
+            RateLimitResult::Limited
+        }
+    }
+}
+
+/// Thresholds after which a misbehaving connection is closed
+#[derive(Debug, Clone)]
+pub struct FloodPolicy {
+    /// Rate limited commands allowed within the window
+    pub max_violations: u32,
+    /// Messages that failed to decode allowed within the window
+    pub max_decode_errors: u32,
+    pub window: Duration,
+    /// How long the ip of a disconnected peer is refused new connections
+    pub penalty: Duration,
+}
+
+impl Default for FloodPolicy {
+    fn default() -> Self {
+        FloodPolicy {
+            max_violations: 200,
+            max_decode_errors: 20,
+            window: Duration::from_secs(60),
+            penalty: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Limit on the number of sessions created from a single ip
+#[derive(Debug, Clone)]
+pub struct CreatePolicy {
+    /// Sessions that can be created within the window, zero for no limit
+    pub max_sessions: u32,
+    pub window: Duration,
+    /// How long an ip that reached the limit is refused new sessions
+    pub cooldown: Duration,
+}
+
+impl Default for CreatePolicy {
+    fn default() -> Self {
+        CreatePolicy {
+            max_sessions: 0,
+            window: Duration::from_secs(60 * 60),
+            cooldown: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Sessions created from a single ip within the current window
+#[derive(Debug)]
+pub struct CreateCounter {
+    window_start: Instant,
+    created: u32,
+    cooldown_until: Option<Instant>,
+}
+
+impl CreateCounter {
+    pub fn new(now: Instant) -> Self {
+        CreateCounter {
+            window_start: now,
+            created: 0,
+            cooldown_until: None,
+        }
+    }
+
+    /// Count a new session, returns the end of the cooldown if the session can't be created
+    pub fn record(&mut self, policy: &CreatePolicy, now: Instant) -> Result<(), Instant> {
+        match self.cooldown_until {
+            Some(until) if until > now => return Err(until),
+            Some(_) => *self = CreateCounter::new(now),
+            None if now.duration_since(self.window_start) > policy.window => {
+                *self = CreateCounter::new(now)
+            }
+            None => {}
+        }
+        if self.created >= policy.max_sessions {
+            let until = now + policy.cooldown;
+            self.cooldown_until = Some(until);
+            return Err(until);
+        }
+        self.created += 1;
+        Ok(())
+    }
+
+    /// Whether neither the window nor the cooldown are running anymore
+    pub fn is_expired(&self, policy: &CreatePolicy, now: Instant) -> bool {
+        match self.cooldown_until {
+            Some(until) => until <= now,
+            None => now.duration_since(self.window_start) > policy.window,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Violation {
+    RateLimited,
+    DecodeError,
+}
+
+#[derive(Debug)]
+pub struct ViolationCounter {
+    window_start: Instant,
+    rate_limited: u32,
+    decode_errors: u32,
+}
+
+impl ViolationCounter {
+    pub fn new(now: Instant) -> Self {
+        ViolationCounter {
+            window_start: now,
+            rate_limited: 0,
+            decode_errors: 0,
+        }
+    }
+
+    /// Record a violation, returns true if the peer crossed one of the thresholds of the policy
+    pub fn record(&mut self, violation: Violation, policy: &FloodPolicy, now: Instant) -> bool {
+        if now.duration_since(self.window_start) > policy.window {
+            *self = ViolationCounter::new(now);
+        }
+        match violation {
+            Violation::RateLimited => {
+                self.rate_limited += 1;
+                self.rate_limited > policy.max_violations
+            }
+            Violation::DecodeError => {
+                self.decode_errors += 1;
+                self.decode_errors > policy.max_decode_errors
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    fn allowed(limiter: &mut RateLimiter, limit: RateLimit) -> bool {
+        matches!(
+            limiter.check("play", limit, Instant::now()),
+            RateLimitResult::Allowed
+        )
+    }
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!("5".parse(), Ok(RateLimit::new(5.0, 5.0)));
+        assert_eq!(" 0.5 : 3 ".parse(), Ok(RateLimit::new(0.5, 3.0)));
+        assert!("fast".parse::<RateLimit>().is_err());
+        assert!("1:".parse::<RateLimit>().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_then_refill() {
+        let limit = RateLimit::new(2.0, 5.0);
+        let mut limiter = RateLimiter::default();
+        for _ in 0..5 {
+            assert!(allowed(&mut limiter, limit));
+        }
+        assert!(matches!(
+            limiter.check("play", limit, Instant::now()),
+            RateLimitResult::Limited
+        ));
+        // other command types have their own bucket
+        assert!(matches!(
+            limiter.check("tick", limit, Instant::now()),
+            RateLimitResult::Allowed
+        ));
+
+        advance(Duration::from_millis(500)).await;
+        assert!(allowed(&mut limiter, limit));
+        assert!(!allowed(&mut limiter, limit));
+
+        // refilling never exceeds the burst
+        advance(Duration::from_secs(60)).await;
+        for _ in 0..5 {
+            assert!(allowed(&mut limiter, limit));
+        }
+        assert!(!allowed(&mut limiter, limit));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_the_first_rejection_is_reported() {
+        let limit = RateLimit::new(1.0, 1.0);
+        let mut limiter = RateLimiter::default();
+        assert!(allowed(&mut limiter, limit));
+        assert!(matches!(
+            limiter.check("play", limit, Instant::now()),
+            RateLimitResult::Limited
+        ));
+        for _ in 0..3 {
+            advance(Duration::from_millis(100)).await;
+            assert!(matches!(
+                limiter.check("play", limit, Instant::now()),
+                RateLimitResult::StillLimited
+            ));
+        }
+
+        advance(Duration::from_secs(1)).await;
+        assert!(allowed(&mut limiter, limit));
+        assert!(matches!(
+            limiter.check("play", limit, Instant::now()),
+            RateLimitResult::Limited
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cost_is_taken_from_the_bucket() {
+        let limit = RateLimit::new(100.0, 1000.0);
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+        assert!(matches!(
+            limiter.check_cost("bytes", limit, 800.0, now),
+            RateLimitResult::Allowed
+        ));
+        assert!(matches!(
+            limiter.check_cost("bytes", limit, 300.0, now),
+            RateLimitResult::Limited
+        ));
+        advance(Duration::from_secs(1)).await;
+        assert!(matches!(
+            limiter.check_cost("bytes", limit, 300.0, Instant::now()),
+            RateLimitResult::Allowed
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn violations_reset_after_the_window() {
+        let policy = FloodPolicy {
+            max_violations: 2,
+            max_decode_errors: 1,
+            window: Duration::from_secs(10),
+            ..FloodPolicy::default()
+        };
+        let mut counter = ViolationCounter::new(Instant::now());
+        assert!(!counter.record(Violation::RateLimited, &policy, Instant::now()));
+        assert!(!counter.record(Violation::RateLimited, &policy, Instant::now()));
+        assert!(!counter.record(Violation::DecodeError, &policy, Instant::now()));
+        assert!(counter.record(Violation::RateLimited, &policy, Instant::now()));
+
+        advance(Duration::from_secs(11)).await;
+        assert!(!counter.record(Violation::RateLimited, &policy, Instant::now()));
+        assert!(!counter.record(Violation::DecodeError, &policy, Instant::now()));
+        assert!(counter.record(Violation::DecodeError, &policy, Instant::now()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn create_cooldown() {
+        let policy = CreatePolicy {
+            max_sessions: 2,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(30),
+        };
+        let start = Instant::now();
+        let mut counter = CreateCounter::new(start);
+        assert!(counter.record(&policy, start).is_ok());
+        assert!(counter.record(&policy, start).is_ok());
+        let until = start + policy.cooldown;
+        assert_eq!(counter.record(&policy, start), Err(until));
+
+        advance(Duration::from_secs(10)).await;
+        assert_eq!(counter.record(&policy, Instant::now()), Err(until));
+        assert!(!counter.is_expired(&policy, Instant::now()));
+
+        advance(Duration::from_secs(20)).await;
+        assert!(counter.is_expired(&policy, Instant::now()));
+        assert!(counter.record(&policy, Instant::now()).is_ok());
+    }
+}
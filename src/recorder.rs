@@ -0,0 +1,98 @@
+use crate::peer::PeerId;
+use crate::unix_millis;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Fields that are never recorded
+const REDACTED_FIELDS: &[&str] = &["token", "new_token", "owner_token", "password"];
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Record {
+    /// Unix timestamp in milliseconds
+    pub at: u64,
+    pub direction: Direction,
+    /// The sender or receiver, `None` for messages broadcast to all clients
+    pub peer: Option<String>,
+    /// The connection id of the peer, which other commands use to refer to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection: Option<String>,
+    pub message: Value,
+}
+
+/// The recent messages of every session, for debugging desyncs after the fact
+pub struct FlightRecorder {
+    capacity: usize,
+    sessions: DashMap<String, VecDeque<Record>>,
+}
+
+impl FlightRecorder {
+    /// Keep the last `capacity` messages of every session, zero disables recording
+    pub fn new(capacity: usize) -> Self {
+        FlightRecorder {
+            capacity,
+            sessions: DashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record(
+        &self,
+        session: &str,
+        direction: Direction,
+        peer: Option<(&PeerId, Option<String>)>,
+        command: &impl Serialize,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Ok(mut message) = serde_json::to_value(command) else {
+            return;
+        };
+        if let Value::Object(fields) = &mut message {
+            for field in REDACTED_FIELDS {
+                if let Some(value) = fields.get_mut(*field) {
+                    *value = Value::String("[redacted]".into());
+                }
+            }
+        }
+        let (peer, connection) = peer.unzip();
+        let record = Record {
+            at: unix_millis(),
+            direction,
+            peer: peer.map(PeerId::to_string),
+            connection: connection.flatten(),
+            message,
+        };
+
+        let mut records = self.sessions.entry(session.to_string()).or_default();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+
+    /// Get the recorded messages, for a single session or all sessions
+    pub fn dump(&self, session: Option<&str>) -> BTreeMap<String, Vec<Record>> {
+        self.sessions
+            .iter()
+            .filter(|entry| session.is_none_or(|session| session == entry.key()))
+            .map(|entry| (entry.key().clone(), entry.value().iter().cloned().collect()))
+            .collect()
+    }
+
+    pub fn remove(&self, session: &str) {
+        self.sessions.remove(session);
+    }
+}
@@ -0,0 +1,43 @@
+//! Binary frames relayed from the session owner to the clients listening on a channel
+//!
+//! A relay frame is laid out as
+//!
+//! | bytes | content                          |
+//! |-------|----------------------------------|
+//! | 1     | `0x01`, the relay tag            |
+//! | 1     | length of the session name       |
+//! | n     | session name as utf8             |
+//! | 2     | channel id, big endian           |
+//! | rest  | payload, not interpreted         |
+//!
+//! The frame is forwarded to the listening clients unchanged.
+//!
+//! Frames on the [`VOICE_CHANNEL`] are send to all clients once the owner enabled voice for the session
+//! and are subject to a separate, stricter bandwidth limit.
+
+pub const RELAY_TAG: u8 = 0x01;
+/// Channel reserved for the voice relay
+pub const VOICE_CHANNEL: u16 = 0xffff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayFrame<'a> {
+    pub session: &'a str,
+    pub channel: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> RelayFrame<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let (&RELAY_TAG, rest) = data.split_first()? else {
+            return None;
+        };
+        let (&name_length, rest) = rest.split_first()?;
+        let (name, rest) = rest.split_at_checked(name_length as usize)?;
+        let (channel, payload) = rest.split_first_chunk::<2>()?;
+        Some(RelayFrame {
+            session: std::str::from_utf8(name).ok()?,
+            channel: u16::from_be_bytes(*channel),
+            payload,
+        })
+    }
+}
@@ -0,0 +1,98 @@
+//! Re-run the commands captured by the flight recorder against a fresh server
+
+use crate::auth::{Identity, NoAuth};
+use crate::config::Config;
+use crate::peer::{generate_connection_id, ConnectionInfo, Peer, PeerId};
+use crate::ratelimit::RateLimits;
+use crate::recorder::{Direction, Record};
+use crate::store::MemoryStore;
+use crate::transport::Frame;
+use crate::{Server, SyncCommand};
+use futures_channel::mpsc::{channel, Receiver};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// A message send by the server during the replay
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayedMessage {
+    /// The peer as named in the recording
+    pub peer: String,
+    pub frame: Frame,
+}
+
+/// Feed the recorded incoming messages of all sessions, in the order they were received, into a new server
+///
+/// Rate limits are disabled since the messages are replayed without delay. Replayed peers keep their recorded
+/// connection ids so commands like `kick` still find them, and ticks and countdowns are stamped with the time they
+/// were recorded at. Anything else that depends on time, like timeouts, follows the clock of the replay.
+pub fn replay(recording: BTreeMap<String, Vec<Record>>) -> Vec<ReplayedMessage> {
+    let mut incoming: Vec<Record> = recording
+        .into_values()
+        .flatten()
+        .filter(|record| record.direction == Direction::In)
+        .collect();
+    incoming.sort_by_key(|record| record.at);
+
+    let config = Config {
+        rate_limits: RateLimits::unlimited(),
+        recorder_size: 0,
+        ..Config::default()
+    };
+    let server = Server::new(
+        config,
+        Box::new(NoAuth),
+        Box::new(MemoryStore::default()),
+        Vec::new(),
+    );
+
+    let mut peers: HashMap<String, (PeerId, Receiver<Frame>)> = HashMap::new();
+    let mut output = Vec::new();
+    for record in incoming {
+        let name = record.peer.unwrap_or_default();
+        let connection = record.connection;
+        let (peer_id, _) = peers.entry(name.clone()).or_insert_with(|| {
+            let id = PeerId::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.next_peer_id());
+            let (tx, rx) = channel(1024);
+            let info = ConnectionInfo {
+                connection_id: connection.unwrap_or_else(generate_connection_id),
+                request_id: None,
+                identity: Identity::Anonymous,
+                user_agent: None,
+                client_version: None,
+                rate_limits: None,
+            };
+            server.peers.insert(id, Peer::new(tx, info));
+            (id, rx)
+        });
+        let peer_id = *peer_id;
+
+        let message = record.message.to_string();
+        let disconnected = match serde_json::from_str::<SyncCommand>(&message) {
+            Ok(command) => server
+                .handle_command_at(command, peer_id, record.at)
+                .is_break(),
+            Err(error) => {
+                eprintln!("skipping invalid recorded message {message}: {error}");
+                false
+            }
+        };
+        if disconnected {
+            server.handle_disconnect(&peer_id);
+        }
+
+        // collect everything send in response before handling the next message
+        for (name, (_, rx)) in peers.iter_mut() {
+            while let Ok(Some(frame)) = rx.try_next() {
+                output.push(ReplayedMessage {
+                    peer: name.clone(),
+                    frame,
+                });
+            }
+        }
+        // later messages from a disconnected peer are handled as a new connection
+        if disconnected {
+            peers.remove(&name);
+        }
+    }
+    output
+}
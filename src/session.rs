@@ -1,17 +1,236 @@
-use crate::{PeerId, SyncCommand};
-use std::time::{Duration, Instant};
+use crate::archive::ArchivedSession;
+use crate::peer::PeerId;
+use crate::ratelimit::{RateLimit, RateLimitResult, RateLimiter};
+use crate::store::SessionSnapshot;
+use crate::{
+    unix_millis, ClientDetails, DemoInfo, EncryptionMode, LoggedCommand, Marker, PublicSession,
+    SessionStats, SyncCommand,
+};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
 
 #[derive(Debug)]
 pub struct Session {
-    pub owner: PeerId,
+    /// The connected owner, sessions restored from storage have no owner until it is reclaimed
+    owner: Option<PeerId>,
     owner_token: String,
-    clients: Vec<PeerId>,
+    /// Token that was replaced by a rotation, accepted until the given time
+    previous_token: Option<(String, Instant)>,
+    clients: Vec<Client>,
+    /// Clients that can send playback commands like the owner
+    co_owners: Vec<PeerId>,
+    /// Connections that were kicked by the owner and can't join again
+    banned: HashSet<PeerId>,
+    reported_idle: usize,
     tick: u64,
+    /// The position of the last explicit seek
+    last_seek: Option<u64>,
+    /// Unix timestamp in milliseconds at which the current tick was received
+    tick_at: Option<u64>,
+    tickrate: Option<f64>,
     playing: bool,
+    demo: Option<DemoInfo>,
+    /// Demos to play after the current one
+    queue: Vec<DemoInfo>,
+    title: Option<String>,
+    description: Option<String>,
+    /// The entity of the player the owner is spectating
+    pov: Option<u32>,
+    /// The first and last tick of the region that is repeated
+    loop_region: Option<(u64, u64)>,
+    /// Moments flagged by the owner, oldest first
+    markers: Vec<Marker>,
+    /// The last annotation the owner asked to retain for clients joining later
+    annotation: Option<serde_json::Value>,
+    /// Annotations and extension messages that weren't retained, oldest first
+    history: Vec<HistoryEntry>,
     owner_left: Option<Instant>,
+    /// The last expiry warning send to the clients since the owner left
+    expiry_warning: Option<u64>,
+    scheduled_start: Option<u64>,
+    last_reminder: Option<u64>,
+    reactions: BTreeMap<String, u32>,
+    /// Whether a client reported its progress, or left, since the last progress report
+    progress_changed: bool,
+    /// Whether a client joined, left or changed its name since the last roster was send
+    roster_changed: bool,
+    last_reaction: HashMap<PeerId, Instant>,
+    /// The owner that was replaced by the last takeover, which can contest it within the grace period
+    takeover: Option<(PeerId, Instant)>,
+    /// Takeovers are refused after a successful contest until this time
+    takeover_locked_until: Option<Instant>,
+    /// Bytes relayed over binary channels
+    relay_limiter: RateLimiter,
+    /// Whether the owner enabled the voice relay
+    voice: bool,
+    encryption: EncryptionMode,
+    /// Whether the session is included in the session list
+    public: bool,
+    /// Whether ticks that go backwards are ignored
+    monotonic: bool,
+    /// Whether the next demo of the queue is played once the current demo ended
+    auto_advance: bool,
+    /// Time the session is kept after the owner left, instead of the configured timeout
+    timeout: Option<Duration>,
+    /// Maximum number of clients chosen by the owner
+    max_clients: Option<usize>,
+    /// Owner commands with the time they were applied, oldest first, `None` unless the owner enabled the log
+    command_log: Option<VecDeque<LoggedCommand>>,
+    /// Number of commands dropped from the start of the log because it was full
+    log_dropped: u64,
+    /// Set when playback reached the end of the demo, until the server announced it
+    ended: Option<Ended>,
+    /// Set when the last command ended the demo in a session with auto advance and a queued demo
+    advance_pending: bool,
+    /// Password clients need to join the session
+    password: Option<String>,
+    /// The user or ip that created the session, unknown for restored sessions
+    creator: Option<String>,
+    /// Unix timestamp in milliseconds, for restored sessions the time it was restored
+    created_at: u64,
+    joins: u64,
+    peak_clients: usize,
+    total_reactions: u64,
+    /// Messages send to the clients, counted while only holding a shared reference to the session
+    broadcasts: AtomicU64,
+    /// Sessions that owner commands of this session are repeated in
+    mirrors: Vec<String>,
+    /// Whether the state changed since the last time it was persisted
+    dirty: bool,
     pub token: String,
 }
 
+/// The ticks reported by the clients of a session
+pub struct ClientProgress {
+    /// Number of clients that reported their progress
+    pub clients: usize,
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+    pub median: Option<u64>,
+}
+
+pub enum SetOwner {
+    Rejected,
+    Locked,
+    Unchanged,
+    /// The session had no owner
+    Claimed,
+    Changed {
+        previous: PeerId,
+    },
+}
+
+/// Maximum number of command kinds a client can subscribe to
+pub const MAX_SUBSCRIBED_KINDS: usize = 32;
+/// Commands that are send to clients regardless of their subscription
+const ALWAYS_DELIVERED: &[&str] = &["renamed", "sessionexpiring", "sessionclosed", "error"];
+
+/// Maximum number of sessions a session can be mirrored into
+pub const MAX_MIRRORS: usize = 16;
+
+/// Minimum time between two reactions from the same peer
+const REACTION_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of distinct emotes tracked per aggregation window
+const MAX_REACTION_KINDS: usize = 32;
+const MAX_EMOTE_LENGTH: usize = 32;
+/// Maximum number of demos in the queue of a session
+const MAX_QUEUE_LENGTH: usize = 64;
+
+/// Change in the latency of a client after which the clients are reported to the owner again
+const LATENCY_REPORT_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// Maximum number of markers stored per session, the oldest markers are dropped first
+const MAX_MARKERS: usize = 256;
+
+/// Maximum number of owner messages replayed to clients joining later, the oldest messages are dropped first
+const MAX_HISTORY: usize = 64;
+
+/// Maximum length of a session name in bytes
+pub const MAX_NAME_LENGTH: usize = 64;
+
+/// Check that a session name isn't empty, too long or contains control characters
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("session names can't be empty".into());
+    }
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "session names can be at most {MAX_NAME_LENGTH} bytes long"
+        ));
+    }
+    if name.trim() != name {
+        return Err("session names can't start or end with whitespace".into());
+    }
+    if name.chars().any(char::is_control) {
+        return Err("session names can't contain control characters".into());
+    }
+    Ok(())
+}
+
+/// An owner message that doesn't change the state of the session but is replayed to clients joining later
+#[derive(Debug)]
+enum HistoryEntry {
+    Annotate(serde_json::Value),
+    Ext {
+        kind: String,
+        payload: serde_json::Value,
+    },
+}
+
+#[derive(Debug)]
+struct Client {
+    peer: PeerId,
+    /// Last presence ping, clients that never send one are always considered active
+    last_presence: Option<Instant>,
+    /// Binary relay channels the client listens to
+    channels: Vec<u16>,
+    /// Kinds of commands the client receives, all commands if not set
+    kinds: Option<Vec<String>>,
+    /// The tick the client last reported to be at
+    progress: Option<u64>,
+    /// Display name chosen when joining
+    name: Option<String>,
+    /// Unix timestamp in milliseconds at which the client joined
+    joined_at: u64,
+    /// Round trip time of the last answered websocket ping
+    latency: Option<Duration>,
+}
+
+impl Client {
+    fn is_subscribed(&self, kind: &str) -> bool {
+        ALWAYS_DELIVERED.contains(&kind)
+            || self
+                .kinds
+                .as_ref()
+                .is_none_or(|kinds| kinds.iter().any(|subscribed| subscribed == kind))
+    }
+
+    fn is_idle(&self, now: Instant, timeout: Duration) -> bool {
+        self.last_presence
+            .is_some_and(|last| now.duration_since(last) > timeout)
+    }
+}
+
+/// Seconds before a scheduled start at which clients are reminded
+const SCHEDULE_REMINDERS: &[u64] = &[1, 2, 3, 4, 5, 10, 30, 60, 300];
+/// Seconds before an abandoned session expires at which clients are warned
+const EXPIRY_WARNINGS: &[u64] = &[10, 60, 300];
+
+pub enum ScheduleEvent {
+    Reminder { start_at: u64, in_seconds: u64 },
+    Start,
+}
+
+/// Playback reached the last tick of the demo
+#[derive(Debug, Clone, Copy)]
+pub struct Ended {
+    pub tick: u64,
+    /// Whether the session was paused because the demo asks to pause at the end
+    pub paused: bool,
+}
+
 impl PartialEq for Session {
     fn eq(&self, other: &Self) -> bool {
         self.token.eq(&other.token)
@@ -21,61 +240,1017 @@ impl PartialEq for Session {
 impl Session {
     pub fn new(owner: PeerId, token: String, owner_token: String) -> Self {
         Session {
-            owner,
+            owner: Some(owner),
             owner_token,
+            previous_token: None,
             clients: Vec::new(),
+            co_owners: Vec::new(),
+            banned: HashSet::new(),
+            reported_idle: 0,
             playing: false,
             tick: 0,
+            last_seek: None,
+            tick_at: None,
+            tickrate: None,
+            demo: None,
+            queue: Vec::new(),
+            title: None,
+            description: None,
+            pov: None,
+            loop_region: None,
+            markers: Vec::new(),
+            annotation: None,
+            history: Vec::new(),
             owner_left: None,
+            expiry_warning: None,
+            scheduled_start: None,
+            last_reminder: None,
+            reactions: BTreeMap::new(),
+            progress_changed: false,
+            roster_changed: false,
+            last_reaction: HashMap::new(),
+            takeover: None,
+            takeover_locked_until: None,
+            relay_limiter: RateLimiter::default(),
+            voice: false,
+            encryption: EncryptionMode::Off,
+            public: false,
+            monotonic: false,
+            auto_advance: false,
+            timeout: None,
+            max_clients: None,
+            command_log: None,
+            log_dropped: 0,
+            ended: None,
+            advance_pending: false,
+            password: None,
+            creator: None,
+            created_at: unix_millis(),
+            joins: 0,
+            peak_clients: 0,
+            total_reactions: 0,
+            broadcasts: AtomicU64::new(0),
+            mirrors: Vec::new(),
+            dirty: true,
             token,
         }
     }
 
-    pub fn join(&mut self, client: PeerId) {
-        self.clients.push(client);
+    /// Recreate a session from storage, the owner has to reclaim it using its token
+    pub fn restore(snapshot: SessionSnapshot, now: Instant) -> Self {
+        Session {
+            owner: None,
+            owner_token: snapshot.owner_token,
+            previous_token: None,
+            clients: Vec::new(),
+            co_owners: Vec::new(),
+            banned: HashSet::new(),
+            reported_idle: 0,
+            playing: snapshot.playing,
+            tick: snapshot.tick,
+            last_seek: snapshot.last_seek,
+            tick_at: None,
+            tickrate: None,
+            demo: snapshot.demo,
+            queue: snapshot.queue,
+            title: snapshot.title,
+            description: snapshot.description,
+            pov: snapshot.pov,
+            loop_region: snapshot.loop_region,
+            markers: snapshot.markers,
+            annotation: None,
+            history: Vec::new(),
+            owner_left: Some(now),
+            expiry_warning: None,
+            scheduled_start: snapshot.scheduled_start,
+            last_reminder: None,
+            reactions: BTreeMap::new(),
+            progress_changed: false,
+            roster_changed: false,
+            last_reaction: HashMap::new(),
+            takeover: None,
+            takeover_locked_until: None,
+            relay_limiter: RateLimiter::default(),
+            voice: snapshot.voice,
+            encryption: snapshot.encryption,
+            public: snapshot.public,
+            monotonic: snapshot.monotonic,
+            auto_advance: snapshot.auto_advance,
+            timeout: snapshot.timeout.map(Duration::from_secs),
+            max_clients: snapshot.max_clients,
+            // the logged commands themselves aren't persisted
+            command_log: snapshot.log.then(VecDeque::new),
+            log_dropped: 0,
+            ended: None,
+            advance_pending: false,
+            password: snapshot.password,
+            creator: None,
+            created_at: unix_millis(),
+            joins: 0,
+            peak_clients: 0,
+            total_reactions: 0,
+            broadcasts: AtomicU64::new(0),
+            mirrors: Vec::new(),
+            dirty: false,
+            token: snapshot.name,
+        }
+    }
+
+    pub fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            name: self.token.clone(),
+            owner_token: self.owner_token.clone(),
+            tick: self.tick,
+            playing: self.playing,
+            scheduled_start: self.scheduled_start,
+            last_seek: self.last_seek,
+            public: self.public,
+            monotonic: self.monotonic,
+            auto_advance: self.auto_advance,
+            timeout: self.timeout.map(|timeout| timeout.as_secs()),
+            max_clients: self.max_clients,
+            log: self.command_log.is_some(),
+            pov: self.pov,
+            loop_region: self.loop_region,
+            voice: self.voice,
+            encryption: self.encryption,
+            demo: self.demo.clone(),
+            password: self.password.clone(),
+            markers: self.markers.clone(),
+            queue: self.queue.clone(),
+            title: self.title.clone(),
+            description: self.description.clone(),
+        }
+    }
+
+    pub fn with_creator(self, creator: String) -> Self {
+        Session {
+            creator: Some(creator),
+            ..self
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        self.public
+    }
+
+    /// Whether clients need a password to join
+    pub fn is_protected(&self) -> bool {
+        self.password.is_some()
+    }
+
+    pub fn with_public(self, public: bool) -> Self {
+        Session { public, ..self }
+    }
+
+    pub fn set_public(&mut self, public: bool) {
+        self.dirty |= std::mem::replace(&mut self.public, public) != public;
+    }
+
+    pub fn with_monotonic(self, monotonic: bool) -> Self {
+        Session { monotonic, ..self }
+    }
+
+    pub fn set_monotonic(&mut self, monotonic: bool) {
+        self.dirty |= std::mem::replace(&mut self.monotonic, monotonic) != monotonic;
+    }
+
+    pub fn with_auto_advance(self, auto_advance: bool) -> Self {
+        Session {
+            auto_advance,
+            ..self
+        }
+    }
+
+    pub fn set_auto_advance(&mut self, auto_advance: bool) {
+        self.dirty |= std::mem::replace(&mut self.auto_advance, auto_advance) != auto_advance;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Session { timeout, ..self }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.dirty |= std::mem::replace(&mut self.timeout, timeout) != timeout;
+    }
+
+    pub fn max_clients(&self) -> Option<usize> {
+        self.max_clients
+    }
+
+    pub fn with_max_clients(self, max_clients: Option<usize>) -> Self {
+        Session {
+            max_clients,
+            ..self
+        }
+    }
+
+    pub fn set_max_clients(&mut self, max_clients: Option<usize>) {
+        self.dirty |= std::mem::replace(&mut self.max_clients, max_clients) != max_clients;
+    }
+
+    pub fn with_log(self, log: bool) -> Self {
+        Session {
+            command_log: log.then(VecDeque::new),
+            ..self
+        }
+    }
+
+    /// Enable or disable the command log, enabling an already enabled log keeps the logged commands
+    pub fn set_log(&mut self, log: bool) {
+        if log == self.command_log.is_some() {
+            return;
+        }
+        self.command_log = log.then(VecDeque::new);
+        self.log_dropped = 0;
+        self.dirty = true;
+    }
+
+    /// Add an applied owner command to the log if it's enabled, keeping at most `capacity` commands
+    pub fn log_command(&mut self, command: &SyncCommand, capacity: usize) {
+        let Some(log) = &mut self.command_log else {
+            return;
+        };
+        if log.len() >= capacity {
+            log.pop_front();
+            self.log_dropped += 1;
+        }
+        log.push_back(LoggedCommand {
+            at: unix_millis(),
+            command: serde_json::to_value(command).unwrap(),
+        });
+    }
+
+    /// The logged commands and the number of commands dropped from the start, `None` if the log isn't enabled
+    pub fn command_log(&self) -> Option<(Vec<LoggedCommand>, u64)> {
+        let log = self.command_log.as_ref()?;
+        Some((log.iter().cloned().collect(), self.log_dropped))
     }
 
-    pub fn set_owner(&mut self, owner: PeerId, owner_token: &str) -> bool {
-        if owner_token == self.owner_token {
-            self.owner = owner;
-            self.owner_left = None;
+    /// Whether the peer joined the session as a client
+    pub fn has_client(&self, peer: &PeerId) -> bool {
+        self.clients().any(|client| client == peer)
+    }
+
+    pub fn with_password(self, password: Option<String>) -> Self {
+        Session { password, ..self }
+    }
+
+    pub fn set_password(&mut self, password: Option<String>) {
+        self.dirty |= std::mem::replace(&mut self.password, password.clone()) != password;
+    }
+
+    /// Whether a client can join with the password, sessions without password can be joined by anyone
+    pub fn accepts_password(&self, password: Option<&str>) -> bool {
+        match &self.password {
+            Some(expected) => password == Some(expected.as_str()),
+            None => true,
         }
-        owner_token == self.owner_token
+    }
+
+    /// The entry of the session in the session list, `None` if the session isn't public
+    pub fn listing(&self) -> Option<PublicSession> {
+        self.public.then(|| PublicSession {
+            name: self.token.clone(),
+            clients: self.clients.len(),
+            protected: self.is_protected(),
+            tick: (self.encryption != EncryptionMode::Full).then_some(self.tick),
+            title: self.title.clone(),
+        })
+    }
+
+    pub fn creator(&self) -> Option<&str> {
+        self.creator.as_deref()
+    }
+
+    /// Count a message send to the clients of the session
+    pub fn count_broadcast(&self) {
+        self.broadcasts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        SessionStats {
+            created_at: self.created_at,
+            clients: self.clients.len(),
+            peak_clients: self.peak_clients,
+            joins: self.joins,
+            broadcasts: self.broadcasts.load(Ordering::Relaxed),
+            reactions: self.total_reactions,
+        }
+    }
+
+    /// The final state and statistics of the session, for the archive
+    pub fn archive(&self, removed_at: u64) -> ArchivedSession {
+        ArchivedSession {
+            name: self.token.clone(),
+            tick: self.tick,
+            playing: self.playing,
+            created_at: self.created_at,
+            removed_at,
+            joins: self.joins,
+            peak_clients: self.peak_clients,
+            broadcasts: self.broadcasts.load(Ordering::Relaxed),
+            reactions: self.total_reactions,
+        }
+    }
+
+    /// Check if the session changed since the last call
+    pub fn take_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.dirty)
+    }
+
+    pub fn owner(&self) -> Option<PeerId> {
+        self.owner
+    }
+
+    pub fn is_owner(&self, peer: &PeerId) -> bool {
+        self.owner.as_ref() == Some(peer)
+    }
+
+    /// Add a client to the session, returns false if it already joined
+    ///
+    /// Joining again only updates the name of the client
+    pub fn join(&mut self, client: PeerId, name: Option<String>) -> bool {
+        if let Some(joined) = self.clients.iter_mut().find(|joined| joined.peer == client) {
+            self.roster_changed |= joined.name != name;
+            joined.name = name;
+            return false;
+        }
+        self.clients.push(Client {
+            peer: client,
+            last_presence: None,
+            channels: Vec::new(),
+            kinds: None,
+            progress: None,
+            name,
+            joined_at: unix_millis(),
+            latency: None,
+        });
+        self.roster_changed = true;
+        self.joins += 1;
+        self.peak_clients = self.peak_clients.max(self.clients.len());
+        true
+    }
+
+    fn accepts_token(&self, token: &str, now: Instant) -> bool {
+        token == self.owner_token
+            || self
+                .previous_token
+                .as_ref()
+                .is_some_and(|(previous, until)| previous == token && *until > now)
+    }
+
+    pub fn is_owner_token(&self, token: &str) -> bool {
+        token == self.owner_token
+    }
+
+    /// Replace the owner token, the old token stays valid for `grace`
+    pub fn rotate_token(&mut self, new_token: String, now: Instant, grace: Duration) {
+        let old_token = std::mem::replace(&mut self.owner_token, new_token);
+        self.previous_token = Some((old_token, now + grace));
+        self.dirty = true;
+    }
+
+    /// Hand the session to another peer with a new owner token, the old tokens are no longer accepted
+    pub fn transfer(&mut self, owner: PeerId, owner_token: String) {
+        self.owner = Some(owner);
+        self.owner_token = owner_token;
+        self.previous_token = None;
+        self.takeover = None;
+        self.dirty = true;
+    }
+
+    pub fn set_owner(&mut self, owner: PeerId, owner_token: &str, now: Instant) -> SetOwner {
+        if !self.accepts_token(owner_token, now) {
+            return SetOwner::Rejected;
+        }
+        self.owner_left = None;
+        self.expiry_warning = None;
+        let Some(previous) = self.owner else {
+            self.owner = Some(owner);
+            return SetOwner::Claimed;
+        };
+        if owner == previous {
+            return SetOwner::Unchanged;
+        }
+        if self.takeover_locked_until.is_some_and(|until| until > now) {
+            return SetOwner::Locked;
+        }
+        self.owner = Some(owner);
+        self.takeover = Some((previous, now));
+        SetOwner::Changed { previous }
+    }
+
+    /// Let the owner replaced by a takeover reclaim the session
+    pub fn contest(&mut self, peer: PeerId, now: Instant, grace: Duration) -> bool {
+        match self.takeover {
+            Some((previous, at)) if previous == peer && now.duration_since(at) <= grace => {
+                self.takeover = None;
+                self.takeover_locked_until = Some(now + grace);
+                self.owner = Some(peer);
+                self.owner_left = None;
+                self.expiry_warning = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Mark the owner as gone after its connection closed, returns false if the peer doesn't own the session
+    ///
+    /// The session expires unless the owner reclaims it in time
+    pub fn owner_disconnected(&mut self, peer: &PeerId, now: Instant) -> bool {
+        if !self.is_owner(peer) {
+            return false;
+        }
+        self.owner = None;
+        self.owner_left = Some(now);
+        self.expiry_warning = None;
+        true
     }
 
     pub fn inactive_time(&self, now: Instant) -> Option<Duration> {
-        self.owner_left.map(|left| left.duration_since(now))
+        self.owner_left.map(|left| now.duration_since(left))
     }
 
-    pub fn initial_state(&self) -> impl Iterator<Item = SyncCommand> {
-        [
-            SyncCommand::Tick {
+    /// The seconds until the session expires, if the clients should be warned about it
+    pub fn poll_expiry(&mut self, now: Instant, timeout: Duration) -> Option<u64> {
+        let remaining = timeout.checked_sub(self.inactive_time(now)?)?;
+        let in_seconds = remaining.as_millis().div_ceil(1000) as u64;
+        let warning = EXPIRY_WARNINGS
+            .iter()
+            .copied()
+            .find(|threshold| in_seconds <= *threshold)?;
+        if self.expiry_warning.is_some_and(|last| last <= warning) {
+            return None;
+        }
+        self.expiry_warning = Some(warning);
+        Some(in_seconds)
+    }
+
+    pub fn initial_state(&self) -> impl Iterator<Item = SyncCommand<'_>> {
+        // with end-to-end encrypted state the owner has to send the state to new clients
+        let state = (self.encryption != EncryptionMode::Full).then(|| {
+            let schedule = self.scheduled_start.map(|start_at| SyncCommand::Schedule {
                 session: &self.token,
-                tick: self.tick,
-            },
-            SyncCommand::Play {
+                start_at: Some(start_at),
+            });
+            let demo = self.demo.clone().map(|demo| SyncCommand::Demo {
                 session: &self.token,
-                play: self.playing,
-            },
-        ]
-        .into_iter()
+                demo: Some(demo),
+            });
+            let pov = self.pov.map(|entity_id| SyncCommand::Pov {
+                session: &self.token,
+                entity_id: Some(entity_id),
+            });
+            demo.into_iter()
+                .chain([
+                    SyncCommand::Tick {
+                        session: &self.token,
+                        tick: self.tick,
+                        timestamp: self.tick_at,
+                        tickrate: self.tickrate,
+                    },
+                    SyncCommand::Play {
+                        session: &self.token,
+                        play: self.playing,
+                    },
+                ])
+                .chain(self.queue.iter().map(|demo| SyncCommand::QueueAdd {
+                    session: &self.token,
+                    demo: demo.clone(),
+                }))
+                .chain(schedule)
+                .chain(pov)
+                .chain(
+                    self.loop_region
+                        .map(|(from_tick, to_tick)| SyncCommand::Loop {
+                            session: &self.token,
+                            from_tick,
+                            to_tick,
+                            enabled: true,
+                        }),
+                )
+                .chain(self.markers.iter().map(|marker| SyncCommand::Marker {
+                    session: &self.token,
+                    tick: marker.tick,
+                    label: marker.label.clone(),
+                }))
+                .chain(
+                    self.annotation
+                        .clone()
+                        .map(|payload| SyncCommand::Annotate {
+                            session: &self.token,
+                            payload,
+                            retain: true,
+                        }),
+                )
+                .chain(self.history.iter().map(|entry| match entry {
+                    HistoryEntry::Annotate(payload) => SyncCommand::Annotate {
+                        session: &self.token,
+                        payload: payload.clone(),
+                        retain: false,
+                    },
+                    HistoryEntry::Ext { kind, payload } => SyncCommand::Ext {
+                        session: &self.token,
+                        kind,
+                        payload: payload.clone(),
+                    },
+                }))
+        });
+        let voice = self.voice.then_some(SyncCommand::Voice {
+            session: &self.token,
+            enabled: true,
+        });
+        // the title and description are shown in listings, so they are never encrypted
+        let meta =
+            (self.title.is_some() || self.description.is_some()).then(|| SyncCommand::SetMeta {
+                session: &self.token,
+                title: self.title.clone(),
+                description: self.description.clone(),
+            });
+        let encryption =
+            (self.encryption != EncryptionMode::Off).then_some(SyncCommand::Encryption {
+                session: &self.token,
+                mode: self.encryption,
+            });
+        encryption
+            .into_iter()
+            .chain(meta)
+            .chain(state.into_iter().flatten())
+            .chain(voice)
     }
 
     pub fn clients(&self) -> impl Iterator<Item = &PeerId> {
-        self.clients.iter()
+        self.clients.iter().map(|client| &client.peer)
+    }
+
+    /// The clients that receive commands of the kind
+    pub fn subscribers<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a PeerId> {
+        self.clients
+            .iter()
+            .filter(move |client| client.is_subscribed(kind))
+            .map(|client| &client.peer)
+    }
+
+    /// Limit the kinds of commands a client receives, returns false if the peer isn't a client of the session
+    pub fn subscribe(&mut self, peer: &PeerId, kinds: Option<Vec<String>>) -> bool {
+        let Some(client) = self.clients.iter_mut().find(|client| client.peer == *peer) else {
+            return false;
+        };
+        client.kinds = kinds;
+        true
+    }
+
+    /// Remove a client from the session, returns false if it wasn't a client
+    pub fn remove_client(&mut self, peer: &PeerId) -> bool {
+        let count = self.clients.len();
+        self.progress_changed |= self
+            .clients
+            .iter()
+            .any(|client| client.peer == *peer && client.progress.is_some());
+        self.clients.retain(|client| client.peer != *peer);
+        self.co_owners.retain(|co_owner| co_owner != peer);
+        self.last_reaction.remove(peer);
+        let removed = self.clients.len() != count;
+        self.roster_changed |= removed;
+        removed
+    }
+
+    /// Promote a client to co-owner or demote it, returns false if nothing changed
+    pub fn set_co_owner(&mut self, peer: PeerId, co_owner: bool) -> bool {
+        if co_owner == self.is_co_owner(&peer) {
+            return false;
+        }
+        if co_owner {
+            self.co_owners.push(peer);
+        } else {
+            self.co_owners.retain(|promoted| *promoted != peer);
+        }
+        true
+    }
+
+    pub fn is_co_owner(&self, peer: &PeerId) -> bool {
+        self.co_owners.contains(peer)
+    }
+
+    /// Prevent a peer from joining the session again
+    pub fn ban(&mut self, peer: PeerId) {
+        self.banned.insert(peer);
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.banned.contains(peer)
     }
 
-    pub fn remove_client(&mut self, peer: &PeerId) {
-        self.clients.retain(|client| client != peer)
+    pub fn is_member(&self, peer: &PeerId) -> bool {
+        self.is_owner(peer) || self.has_client(peer)
     }
 
-    pub fn handle_command(&mut self, command: &SyncCommand) {
-        match command {
-            SyncCommand::Tick { tick, .. } => {
-                self.tick = *tick;
+    /// Start or stop listening to a relay channel, returns false if the peer isn't a client of the session
+    pub fn listen(&mut self, peer: &PeerId, channel: u16, listen: bool) -> bool {
+        let Some(client) = self.clients.iter_mut().find(|client| client.peer == *peer) else {
+            return false;
+        };
+        client.channels.retain(|listening| *listening != channel);
+        if listen {
+            client.channels.push(channel);
+        }
+        true
+    }
+
+    pub fn listeners(&self, channel: u16) -> impl Iterator<Item = &PeerId> {
+        self.clients
+            .iter()
+            .filter(move |client| client.channels.contains(&channel))
+            .map(|client| &client.peer)
+    }
+
+    /// Take `bytes` from the relay quota of the session
+    pub fn check_relay(&mut self, bytes: usize, limit: RateLimit, now: Instant) -> RateLimitResult {
+        self.relay_limiter
+            .check_cost("relay", limit, bytes as f64, now)
+    }
+
+    pub fn rename(&mut self, new_name: String) {
+        self.token = new_name;
+        self.dirty = true;
+    }
+
+    /// Follow a rename of a session this session is mirrored into
+    pub fn rename_mirror(&mut self, session: &str, new_name: &str) {
+        for mirror in self.mirrors.iter_mut().filter(|mirror| *mirror == session) {
+            *mirror = new_name.to_string();
+        }
+    }
+
+    pub fn mirrors(&self) -> &[String] {
+        &self.mirrors
+    }
+
+    /// Start or stop mirroring into another session, returns false if too many sessions are mirrored
+    pub fn set_mirror(&mut self, target: &str, enabled: bool) -> bool {
+        self.mirrors.retain(|mirror| mirror != target);
+        if enabled {
+            if self.mirrors.len() >= MAX_MIRRORS {
+                return false;
             }
-            SyncCommand::Play { play, .. } => self.playing = *play,
-            _ => {}
+            self.mirrors.push(target.to_string());
+        }
+        true
+    }
+
+    pub fn encryption(&self) -> EncryptionMode {
+        self.encryption
+    }
+
+    pub fn voice_enabled(&self) -> bool {
+        self.voice
+    }
+
+    /// Take `bytes` from the voice quota of the session, which is separate from the relay quota
+    pub fn check_voice(&mut self, bytes: usize, limit: RateLimit, now: Instant) -> RateLimitResult {
+        self.relay_limiter
+            .check_cost("voice", limit, bytes as f64, now)
+    }
+
+    /// Record a presence ping from a client, returns false if the peer isn't a client of the session
+    pub fn presence(&mut self, peer: &PeerId, now: Instant) -> bool {
+        match self.clients.iter_mut().find(|client| client.peer == *peer) {
+            Some(client) => {
+                client.last_presence = Some(now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record the round trip time of a client, returns whether it changed enough to report it to the owner
+    pub fn set_latency(&mut self, peer: &PeerId, latency: Duration) -> bool {
+        let Some(client) = self.clients.iter_mut().find(|client| client.peer == *peer) else {
+            return false;
+        };
+        client
+            .latency
+            .replace(latency)
+            .is_none_or(|previous| previous.abs_diff(latency) >= LATENCY_REPORT_THRESHOLD)
+    }
+
+    pub fn idle_count(&self, now: Instant, timeout: Duration) -> usize {
+        self.clients
+            .iter()
+            .filter(|client| client.is_idle(now, timeout))
+            .count()
+    }
+
+    /// Presence information about every client, clients without a connection id are skipped
+    pub fn client_details<'a>(
+        &'a self,
+        now: Instant,
+        timeout: Duration,
+        connection_id: impl Fn(&PeerId) -> Option<String> + 'a,
+    ) -> impl Iterator<Item = ClientDetails> + 'a {
+        self.clients.iter().filter_map(move |client| {
+            Some(ClientDetails {
+                id: connection_id(&client.peer)?,
+                name: client.name.clone(),
+                joined_at: client.joined_at,
+                tick: client.progress,
+                idle: client.is_idle(now, timeout),
+                latency: client.latency.map(|latency| latency.as_millis() as u64),
+            })
+        })
+    }
+
+    /// Update the idle count reported to the owner, returns true if it changed since the last report
+    pub fn update_reported_idle(&mut self, idle: usize) -> bool {
+        let changed = self.reported_idle != idle;
+        self.reported_idle = idle;
+        changed
+    }
+
+    /// Count a reaction towards the current window, returns false if the reaction was dropped
+    pub fn react(&mut self, peer: PeerId, emote: &str, now: Instant) -> bool {
+        if emote.is_empty() || emote.len() > MAX_EMOTE_LENGTH || !self.is_member(&peer) {
+            return false;
+        }
+        if let Some(last) = self.last_reaction.get(&peer) {
+            if now.duration_since(*last) < REACTION_INTERVAL {
+                return false;
+            }
+        }
+        if !self.reactions.contains_key(emote) && self.reactions.len() >= MAX_REACTION_KINDS {
+            return false;
+        }
+        self.last_reaction.insert(peer, now);
+        self.total_reactions += 1;
+        *self.reactions.entry(emote.to_string()).or_default() += 1;
+        true
+    }
+
+    /// Record the tick a client is at, returns false if the peer isn't a client of the session
+    pub fn progress(&mut self, peer: &PeerId, tick: u64) -> bool {
+        match self.clients.iter_mut().find(|client| client.peer == *peer) {
+            Some(client) => {
+                self.progress_changed |= client.progress.replace(tick) != Some(tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Summarize the progress of the clients, if it changed since the last call
+    pub fn take_progress(&mut self) -> Option<ClientProgress> {
+        if !std::mem::take(&mut self.progress_changed) {
+            return None;
+        }
+        let mut ticks: Vec<u64> = self
+            .clients
+            .iter()
+            .filter_map(|client| client.progress)
+            .collect();
+        ticks.sort_unstable();
+        Some(ClientProgress {
+            clients: ticks.len(),
+            min: ticks.first().copied(),
+            max: ticks.last().copied(),
+            median: ticks.get(ticks.len() / 2).copied(),
+        })
+    }
+
+    /// The clients with their names, if any joined, left or changed their name since the last call
+    pub fn take_roster(&mut self) -> Option<impl Iterator<Item = (&PeerId, Option<&str>)>> {
+        std::mem::take(&mut self.roster_changed).then(|| {
+            self.clients
+                .iter()
+                .map(|client| (&client.peer, client.name.as_deref()))
+        })
+    }
+
+    /// Take the first demo from the queue
+    pub fn next_demo(&mut self) -> Option<DemoInfo> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        self.dirty = true;
+        Some(self.queue.remove(0))
+    }
+
+    /// Keep an extension message from the owner for clients joining later
+    pub fn record_ext(&mut self, kind: &str, payload: &serde_json::Value) {
+        self.push_history(HistoryEntry::Ext {
+            kind: kind.to_string(),
+            payload: payload.clone(),
+        });
+    }
+
+    fn push_history(&mut self, entry: HistoryEntry) {
+        if self.history.len() >= MAX_HISTORY {
+            self.history.remove(0);
+        }
+        self.history.push(entry);
+    }
+
+    /// Take the reactions aggregated since the last call
+    pub fn take_reactions(&mut self) -> BTreeMap<String, u32> {
+        std::mem::take(&mut self.reactions)
+    }
+
+    /// The last tick of the current demo, if the owner told us
+    fn max_tick(&self) -> Option<u64> {
+        self.demo.as_ref()?.max_tick
+    }
+
+    fn clamp_tick(&self, tick: u64) -> u64 {
+        self.max_tick().map_or(tick, |max_tick| tick.min(max_tick))
+    }
+
+    /// The command with its tick clamped to the end of the demo, `None` if it's already in range
+    pub fn clamp<'a>(&self, command: &SyncCommand<'a>) -> Option<SyncCommand<'a>> {
+        let max_tick = self.max_tick()?;
+        let mut clamped = command.clone();
+        match &mut clamped {
+            SyncCommand::Tick { tick, .. }
+            | SyncCommand::Seek { tick, .. }
+            | SyncCommand::PlayAt { tick, .. }
+                if *tick > max_tick =>
+            {
+                *tick = max_tick;
+                Some(clamped)
+            }
+            _ => None,
+        }
+    }
+
+    /// Take the end of the demo if playback reached it since the last call
+    pub fn take_ended(&mut self) -> Option<Ended> {
+        self.ended.take()
+    }
+
+    /// Whether the next demo of the queue should be played because the last command ended the current one
+    pub fn take_advance(&mut self) -> bool {
+        std::mem::take(&mut self.advance_pending)
+    }
+
+    /// Apply an owner command, returns whether the state of the session changed
+    pub fn handle_command(&mut self, command: &SyncCommand) -> bool {
+        self.advance_pending = false;
+        let changed = match command {
+            // going back takes an explicit seek in monotonic sessions
+            SyncCommand::Tick { tick, .. } if self.monotonic && *tick < self.tick => false,
+            SyncCommand::Tick {
+                tick,
+                timestamp,
+                tickrate,
+                ..
+            } => {
+                let tick = self.clamp_tick(*tick);
+                self.tick_at = *timestamp;
+                if tickrate.is_some() {
+                    self.tickrate = *tickrate;
+                }
+                if self.max_tick() == Some(tick) && self.tick < tick {
+                    self.reach_end(tick);
+                }
+                std::mem::replace(&mut self.tick, tick) != tick
+            }
+            SyncCommand::Seek { tick, .. } => {
+                let tick = self.clamp_tick(*tick);
+                // seeking to the current position still has to make the clients reload it
+                self.tick = tick;
+                self.tick_at = None;
+                self.last_seek = Some(tick);
+                if self.max_tick() == Some(tick) {
+                    self.reach_end(tick);
+                }
+                true
+            }
+            SyncCommand::Play { play, .. } => std::mem::replace(&mut self.playing, *play) != *play,
+            SyncCommand::PlayAt { tick, play, .. } => {
+                let tick = self.clamp_tick(*tick);
+                // like a seek, jumping to the current position still has to make the clients reload it
+                self.tick = tick;
+                self.tick_at = None;
+                self.last_seek = Some(tick);
+                self.playing = *play;
+                if self.max_tick() == Some(tick) {
+                    self.reach_end(tick);
+                }
+                true
+            }
+            SyncCommand::Demo { demo, .. } => {
+                std::mem::replace(&mut self.demo, demo.clone()) != *demo
+            }
+            SyncCommand::QueueAdd { demo, .. } => {
+                let added = self.queue.len() < MAX_QUEUE_LENGTH;
+                if added {
+                    self.queue.push(demo.clone());
+                }
+                added
+            }
+            SyncCommand::QueueRemove { index, .. } => {
+                let removed = *index < self.queue.len();
+                if removed {
+                    self.queue.remove(*index);
+                }
+                removed
+            }
+            SyncCommand::SetMeta {
+                title, description, ..
+            } => {
+                let changed = self.title != *title || self.description != *description;
+                self.title.clone_from(title);
+                self.description.clone_from(description);
+                changed
+            }
+            SyncCommand::Pov { entity_id, .. } => {
+                std::mem::replace(&mut self.pov, *entity_id) != *entity_id
+            }
+            SyncCommand::Loop {
+                from_tick,
+                to_tick,
+                enabled,
+                ..
+            } => {
+                let region = enabled.then_some((*from_tick, *to_tick));
+                std::mem::replace(&mut self.loop_region, region) != region
+            }
+            SyncCommand::Marker { tick, label, .. } => {
+                if self.markers.len() >= MAX_MARKERS {
+                    self.markers.remove(0);
+                }
+                self.markers.push(Marker {
+                    tick: *tick,
+                    label: label.clone(),
+                });
+                true
+            }
+            SyncCommand::Annotate {
+                payload, retain, ..
+            } => {
+                if *retain {
+                    self.annotation = (!payload.is_null()).then(|| payload.clone());
+                } else {
+                    self.push_history(HistoryEntry::Annotate(payload.clone()));
+                }
+                true
+            }
+            SyncCommand::Schedule { start_at, .. } | SyncCommand::Countdown { start_at, .. } => {
+                let changed = std::mem::replace(&mut self.scheduled_start, *start_at) != *start_at;
+                if changed {
+                    self.last_reminder = None;
+                }
+                changed
+            }
+            SyncCommand::Voice { enabled, .. } => {
+                std::mem::replace(&mut self.voice, *enabled) != *enabled
+            }
+            SyncCommand::Encryption { mode, .. } => {
+                std::mem::replace(&mut self.encryption, *mode) != *mode
+            }
+            _ => true,
+        };
+        self.dirty |= changed;
+        changed
+    }
+
+    /// Playback reached the last tick, pausing the session if the demo asks for it
+    fn reach_end(&mut self, tick: u64) {
+        let paused = self.playing && self.demo.as_ref().is_some_and(|demo| demo.pause_at_end);
+        if paused {
+            self.playing = false;
+        }
+        self.ended = Some(Ended { tick, paused });
+        self.advance_pending = self.auto_advance && !self.queue.is_empty();
+    }
+
+    /// Advance the scheduled start, `now` is the current unix time in milliseconds
+    pub fn poll_schedule(&mut self, now: u64) -> Option<ScheduleEvent> {
+        let start_at = self.scheduled_start?;
+        if now >= start_at {
+            self.scheduled_start = None;
+            self.last_reminder = None;
+            self.playing = true;
+            self.dirty = true;
+            return Some(ScheduleEvent::Start);
+        }
+
+        let in_seconds = (start_at - now).div_ceil(1000);
+        let reminder = SCHEDULE_REMINDERS
+            .iter()
+            .copied()
+            .find(|threshold| in_seconds <= *threshold)?;
+        if self.last_reminder.is_some_and(|last| last <= reminder) {
+            return None;
         }
+        self.last_reminder = Some(reminder);
+        Some(ScheduleEvent::Reminder {
+            start_at,
+            in_seconds,
+        })
     }
 }
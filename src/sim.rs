@@ -0,0 +1,538 @@
+//! In-memory peers for driving multi-peer scenarios against a server without sockets
+//!
+//! All timeouts and schedules use the tokio clock, with a paused runtime (`tokio::time::pause`) they
+//! can be reached by calling `tokio::time::advance` followed by [`Server::housekeeping`] instead of sleeping.
+//! Once a simulated peer connected, the unix timestamps of the server follow the tokio clock as well.
+
+use crate::auth::Identity;
+use crate::peer::{generate_connection_id, ConnectionInfo, PeerId};
+use crate::task::spawn_named;
+use crate::transport::{Frame, PeerTransport};
+use crate::{Server, SyncCommand};
+use futures_channel::mpsc::{unbounded, SendError, UnboundedReceiver, UnboundedSender};
+use futures_util::StreamExt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+static SIMULATED: AtomicBool = AtomicBool::new(false);
+
+/// Whether any simulated peer connected, from then on timestamps follow the tokio clock
+pub(crate) fn is_simulated() -> bool {
+    SIMULATED.load(Ordering::Relaxed)
+}
+
+/// The server side of an in-memory connection
+pub struct SimTransport {
+    incoming: UnboundedReceiver<Frame>,
+    outgoing: UnboundedSender<Frame>,
+}
+
+impl PeerTransport for SimTransport {
+    type Error = SendError;
+
+    async fn recv(&mut self) -> Option<Frame> {
+        self.incoming.next().await
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), Self::Error> {
+        self.outgoing
+            .unbounded_send(frame)
+            .map_err(|error| error.into_send_error())
+    }
+}
+
+/// The client side of an in-memory connection
+pub struct SimPeer {
+    id: PeerId,
+    tx: UnboundedSender<Frame>,
+    rx: UnboundedReceiver<Frame>,
+    task: JoinHandle<()>,
+}
+
+impl SimPeer {
+    /// Connect an anonymous peer
+    pub fn connect(server: &Arc<Server>) -> Self {
+        Self::connect_with(
+            server,
+            ConnectionInfo {
+                connection_id: generate_connection_id(),
+                request_id: None,
+                identity: Identity::Anonymous,
+                user_agent: None,
+                client_version: None,
+                rate_limits: None,
+            },
+        )
+    }
+
+    pub fn connect_with(server: &Arc<Server>, info: ConnectionInfo) -> Self {
+        SIMULATED.store(true, Ordering::Relaxed);
+        let id = PeerId::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.next_peer_id());
+        let (tx, incoming) = unbounded();
+        let (outgoing, rx) = unbounded();
+        let transport = SimTransport { incoming, outgoing };
+        let server = server.clone();
+        let task = spawn_named("sim peer", async move {
+            server.handle_peer(id, info, transport).await
+        });
+        SimPeer { id, tx, rx, task }
+    }
+
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    pub fn send(&self, command: &SyncCommand) {
+        self.send_frame(Frame::Text(serde_json::to_string(command).unwrap()));
+    }
+
+    pub fn send_frame(&self, frame: Frame) {
+        // the server closed the connection, the frame is dropped just like on a real socket
+        let _ = self.tx.unbounded_send(frame);
+    }
+
+    /// Wait for the next frame from the server, `None` once the server closed the connection
+    pub async fn recv(&mut self) -> Option<Frame> {
+        self.rx.next().await
+    }
+
+    /// Wait for the next text message from the server, skipping binary frames and pings
+    pub async fn recv_text(&mut self) -> Option<String> {
+        loop {
+            match self.recv().await? {
+                Frame::Text(text) => return Some(text),
+                Frame::Binary(_) | Frame::Ping(_) | Frame::Pong(_) => continue,
+                Frame::Close { .. } => return None,
+            }
+        }
+    }
+
+    /// Get the next frame if one has already been send
+    pub fn try_recv(&mut self) -> Option<Frame> {
+        self.rx.try_next().ok().flatten()
+    }
+
+    /// Close the connection and wait until the server handled the disconnect
+    pub async fn disconnect(self) {
+        drop(self.tx);
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SimPeer;
+    use crate::config::Config;
+    use crate::transport::{Frame, CLOSE_POLICY};
+    use crate::{Server, ServerBuilder};
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn server(config: Config) -> Arc<Server> {
+        Arc::new(ServerBuilder::new(config).build().unwrap())
+    }
+
+    fn send(peer: &SimPeer, message: Value) {
+        peer.send_frame(Frame::Text(message.to_string()));
+    }
+
+    /// All frames the server sent to the peer, once it handled everything that was sent so far
+    async fn frames(peer: &mut SimPeer) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        // the paused clock only advances once all tasks are idle
+        while let Ok(Some(frame)) =
+            tokio::time::timeout(Duration::from_millis(10), peer.recv()).await
+        {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    async fn messages(peer: &mut SimPeer) -> Vec<Value> {
+        frames(peer)
+            .await
+            .into_iter()
+            .filter_map(|frame| match frame {
+                Frame::Text(text) => Some(serde_json::from_str(&text).unwrap()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn of_type<'a>(messages: &'a [Value], kind: &str) -> Vec<&'a Value> {
+        messages
+            .iter()
+            .filter(|message| message["type"] == kind)
+            .collect()
+    }
+
+    /// Connect a peer, returning its connection id from the server info
+    async fn connect(server: &Arc<Server>) -> (SimPeer, String) {
+        let mut peer = SimPeer::connect(server);
+        let info = messages(&mut peer).await;
+        let id = of_type(&info, "serverinfo")[0]["connection_id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        (peer, id)
+    }
+
+    async fn create(server: &Arc<Server>, session: &str) -> SimPeer {
+        let (mut owner, _) = connect(server).await;
+        send(
+            &owner,
+            json!({"type": "create", "session": session, "token": "token"}),
+        );
+        let created = messages(&mut owner).await;
+        assert_eq!(of_type(&created, "created").len(), 1);
+        owner
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_expires_after_owner_timeout() {
+        let config = Config::default();
+        let timeout = config.session_timeout;
+        let server = server(config);
+        let owner = create(&server, "expiring").await;
+        let (mut client, _) = connect(&server).await;
+        send(&client, json!({"type": "join", "session": "expiring"}));
+        messages(&mut client).await;
+
+        owner.disconnect().await;
+        let away = messages(&mut client).await;
+        assert_eq!(of_type(&away, "owneraway").len(), 1);
+
+        tokio::time::advance(timeout - Duration::from_secs(1)).await;
+        server.housekeeping();
+        let before = messages(&mut client).await;
+        assert!(of_type(&before, "sessionclosed").is_empty());
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        server.housekeeping();
+        let after = messages(&mut client).await;
+        let closed = of_type(&after, "sessionclosed");
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0]["reason"], "expired");
+
+        send(&client, json!({"type": "join", "session": "expiring"}));
+        let join = messages(&mut client).await;
+        assert_eq!(of_type(&join, "error")[0]["code"], "session_not_found");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reclaimed_session_doesnt_expire() {
+        let config = Config::default();
+        let timeout = config.session_timeout;
+        let server = server(config);
+        let owner = create(&server, "reclaimed").await;
+        owner.disconnect().await;
+
+        tokio::time::advance(timeout / 2).await;
+        let mut owner = create(&server, "reclaimed").await;
+        tokio::time::advance(timeout).await;
+        server.housekeeping();
+        assert!(of_type(&messages(&mut owner).await, "sessionclosed").is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recreating_only_changes_sent_settings() {
+        let server = server(Config::default());
+        let (mut owner, _) = connect(&server).await;
+        send(
+            &owner,
+            json!({"type": "create", "session": "settings", "token": "token", "password": "secret"}),
+        );
+        messages(&mut owner).await;
+        send(
+            &owner,
+            json!({"type": "create", "session": "settings", "token": "token", "public": true}),
+        );
+        messages(&mut owner).await;
+
+        let (mut client, _) = connect(&server).await;
+        send(&client, json!({"type": "join", "session": "settings"}));
+        let refused = messages(&mut client).await;
+        assert_eq!(of_type(&refused, "error").len(), 1);
+
+        send(
+            &owner,
+            json!({"type": "create", "session": "settings", "token": "token", "password": null}),
+        );
+        messages(&mut owner).await;
+        send(&client, json!({"type": "join", "session": "settings"}));
+        assert!(of_type(&messages(&mut client).await, "error").is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ended_demos_advance_to_the_queue() {
+        let server = server(Config::default());
+        let (mut owner, _) = connect(&server).await;
+        send(
+            &owner,
+            json!({"type": "create", "session": "queue", "token": "token", "auto_advance": true}),
+        );
+        send(
+            &owner,
+            json!({"type": "demo", "session": "queue", "demo": {"id": 1, "max_tick": 100}}),
+        );
+        send(
+            &owner,
+            json!({"type": "queueadd", "session": "queue", "demo": {"id": 2}}),
+        );
+        let (mut client, _) = connect(&server).await;
+        send(&client, json!({"type": "join", "session": "queue"}));
+        messages(&mut owner).await;
+        messages(&mut client).await;
+
+        send(
+            &owner,
+            json!({"type": "tick", "session": "queue", "tick": 100}),
+        );
+        for peer in [&mut owner, &mut client] {
+            let advanced = messages(peer).await;
+            assert_eq!(of_type(&advanced, "ended").len(), 1);
+            assert_eq!(of_type(&advanced, "demo")[0]["demo"]["id"], 2);
+            assert_eq!(of_type(&advanced, "seek")[0]["tick"], 0);
+        }
+
+        // the queue is empty now
+        send(
+            &owner,
+            json!({"type": "tick", "session": "queue", "tick": 100}),
+        );
+        assert!(of_type(&messages(&mut client).await, "demo").is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_applied_commands_are_logged() {
+        let server = server(Config::default());
+        let (mut owner, _) = connect(&server).await;
+        send(
+            &owner,
+            json!({"type": "create", "session": "logged", "token": "token", "log": true, "monotonic": true}),
+        );
+        send(
+            &owner,
+            json!({"type": "tick", "session": "logged", "tick": 10}),
+        );
+        send(
+            &owner,
+            json!({"type": "tick", "session": "logged", "tick": 5}),
+        );
+        send(
+            &owner,
+            json!({"type": "play", "session": "logged", "play": false}),
+        );
+        send(&owner, json!({"type": "history", "session": "logged"}));
+        let history = messages(&mut owner).await;
+        let commands = of_type(&history, "history")[0]["commands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|logged| logged["command"]["tick"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(commands, [json!(10)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seeking_to_the_end_ends_the_demo() {
+        let server = server(Config::default());
+        let mut owner = create(&server, "seeking").await;
+        send(
+            &owner,
+            json!({"type": "demo", "session": "seeking", "demo": {"max_tick": 100, "pause_at_end": true}}),
+        );
+        let (mut client, _) = connect(&server).await;
+        send(&client, json!({"type": "join", "session": "seeking"}));
+        messages(&mut owner).await;
+        messages(&mut client).await;
+
+        send(
+            &owner,
+            json!({"type": "seek", "session": "seeking", "tick": 500}),
+        );
+        let ended = messages(&mut client).await;
+        assert_eq!(of_type(&ended, "seek")[0]["tick"], 100);
+        assert_eq!(of_type(&ended, "ended")[0]["tick"], 100);
+        messages(&mut owner).await;
+
+        send(
+            &owner,
+            json!({"type": "playat", "session": "seeking", "tick": 100, "play": true}),
+        );
+        let paused = messages(&mut client).await;
+        assert_eq!(of_type(&paused, "ended").len(), 1);
+        assert_eq!(of_type(&paused, "play").last().unwrap()["play"], false);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn encrypted_sessions_refuse_cleartext_annotations() {
+        let server = server(Config::default());
+        let mut owner = create(&server, "encrypted").await;
+        let (mut client, _) = connect(&server).await;
+        send(&client, json!({"type": "join", "session": "encrypted"}));
+        send(
+            &owner,
+            json!({"type": "encryption", "session": "encrypted", "mode": "payloads"}),
+        );
+        messages(&mut owner).await;
+        messages(&mut client).await;
+
+        send(
+            &owner,
+            json!({"type": "annotate", "session": "encrypted", "payload": {"text": "hi"}}),
+        );
+        let refused = messages(&mut owner).await;
+        assert_eq!(of_type(&refused, "error")[0]["code"], "invalid_request");
+        assert!(of_type(&messages(&mut client).await, "annotate").is_empty());
+
+        // the playback state is only refused in full mode
+        send(
+            &owner,
+            json!({"type": "tick", "session": "encrypted", "tick": 10}),
+        );
+        assert!(of_type(&messages(&mut owner).await, "error").is_empty());
+        assert_eq!(of_type(&messages(&mut client).await, "tick").len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn encrypted_mirrors_only_get_what_they_accept() {
+        let server = server(Config::default());
+        let mut source = create(&server, "source").await;
+        let mut encrypted = create(&server, "encrypted").await;
+        let _plain = create(&server, "plain").await;
+        send(
+            &encrypted,
+            json!({"type": "encryption", "session": "encrypted", "mode": "full"}),
+        );
+        messages(&mut encrypted).await;
+        let (mut encrypted_client, _) = connect(&server).await;
+        let (mut plain_client, _) = connect(&server).await;
+        send(
+            &encrypted_client,
+            json!({"type": "join", "session": "encrypted"}),
+        );
+        send(&plain_client, json!({"type": "join", "session": "plain"}));
+        for target in ["encrypted", "plain"] {
+            send(
+                &source,
+                json!({"type": "mirror", "session": "source", "target": target, "token": "token", "enabled": true}),
+            );
+        }
+        assert!(of_type(&messages(&mut source).await, "error").is_empty());
+        messages(&mut encrypted_client).await;
+        messages(&mut plain_client).await;
+
+        send(
+            &source,
+            json!({"type": "tick", "session": "source", "tick": 10}),
+        );
+        assert!(of_type(&messages(&mut encrypted_client).await, "tick").is_empty());
+        assert_eq!(of_type(&messages(&mut plain_client).await, "tick").len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn join_leave_and_kick() {
+        let server = server(Config::default());
+        let mut owner = create(&server, "members").await;
+        let (mut client, client_id) = connect(&server).await;
+
+        send(&client, json!({"type": "join", "session": "members"}));
+        messages(&mut client).await;
+        let joined = messages(&mut owner).await;
+        assert_eq!(of_type(&joined, "clients").last().unwrap()["count"], 1);
+
+        send(&client, json!({"type": "leave", "session": "members"}));
+        messages(&mut client).await;
+        let left = messages(&mut owner).await;
+        assert_eq!(of_type(&left, "clients").last().unwrap()["count"], 0);
+
+        // commands of left sessions are no longer delivered
+        send(
+            &owner,
+            json!({"type": "play", "session": "members", "play": true}),
+        );
+        assert!(of_type(&messages(&mut client).await, "play").is_empty());
+
+        send(&client, json!({"type": "join", "session": "members"}));
+        messages(&mut client).await;
+        messages(&mut owner).await;
+        send(
+            &owner,
+            json!({"type": "kick", "session": "members", "client": client_id}),
+        );
+        let kicked = messages(&mut client).await;
+        assert_eq!(of_type(&kicked, "kicked").len(), 1);
+        let removed = messages(&mut owner).await;
+        assert_eq!(of_type(&removed, "clients").last().unwrap()["count"], 0);
+
+        send(&client, json!({"type": "join", "session": "members"}));
+        let rejoin = messages(&mut client).await;
+        assert_eq!(of_type(&rejoin, "error")[0]["code"], "unauthorized");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_the_owner_can_kick() {
+        let server = server(Config::default());
+        let _owner = create(&server, "kicks").await;
+        let (mut client, _) = connect(&server).await;
+        let (other, other_id) = connect(&server).await;
+        send(&client, json!({"type": "join", "session": "kicks"}));
+        send(&other, json!({"type": "join", "session": "kicks"}));
+        messages(&mut client).await;
+
+        send(
+            &client,
+            json!({"type": "kick", "session": "kicks", "client": other_id}),
+        );
+        let refused = messages(&mut client).await;
+        assert_eq!(of_type(&refused, "error")[0]["code"], "not_owner");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commands_over_the_rate_limit_are_refused() {
+        let config = Config::default();
+        let burst = config.rate_limits.get("join").burst as usize;
+        let server = server(config);
+        let _owner = create(&server, "limited").await;
+        let (mut client, _) = connect(&server).await;
+
+        for _ in 0..burst + 1 {
+            send(&client, json!({"type": "join", "session": "limited"}));
+        }
+        let replies = messages(&mut client).await;
+        let errors = of_type(&replies, "error");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["code"], "rate_limited");
+
+        // the bucket refills over time
+        tokio::time::advance(Duration::from_secs(1)).await;
+        send(&client, json!({"type": "join", "session": "limited"}));
+        assert!(of_type(&messages(&mut client).await, "error").is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooding_peers_are_disconnected() {
+        let config = Config::default();
+        let max_errors = config.flood_policy.max_decode_errors;
+        let server = server(config);
+        let (mut peer, _) = connect(&server).await;
+
+        for _ in 0..max_errors {
+            peer.send_frame(Frame::Text("not json".into()));
+        }
+        assert!(!frames(&mut peer)
+            .await
+            .iter()
+            .any(|frame| matches!(frame, Frame::Close { .. })));
+
+        peer.send_frame(Frame::Text("not json".into()));
+        let frames = frames(&mut peer).await;
+        assert!(frames
+            .iter()
+            .any(|frame| matches!(frame, Frame::Close { code, .. } if *code == CLOSE_POLICY)));
+    }
+}
@@ -0,0 +1,701 @@
+use crate::{DemoInfo, EncryptionMode, Marker};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use tracing::error;
+
+/// The persistent part of a session
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SessionSnapshot {
+    pub name: String,
+    pub owner_token: String,
+    pub tick: u64,
+    pub playing: bool,
+    pub scheduled_start: Option<u64>,
+    /// Position of the last explicit seek
+    #[serde(default)]
+    pub last_seek: Option<u64>,
+    #[serde(default)]
+    pub public: bool,
+    /// Whether ticks that go backwards are ignored
+    #[serde(default)]
+    pub monotonic: bool,
+    /// Whether the next demo of the queue is played once the current demo ended
+    #[serde(default)]
+    pub auto_advance: bool,
+    /// Seconds the session is kept after the owner left, instead of the configured timeout
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    /// Maximum number of clients chosen by the owner
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    /// Whether the owner commands are logged
+    #[serde(default)]
+    pub log: bool,
+    /// The entity of the player the owner is spectating
+    #[serde(default)]
+    pub pov: Option<u32>,
+    /// The first and last tick of the region that is repeated
+    #[serde(default)]
+    pub loop_region: Option<(u64, u64)>,
+    /// Whether the owner enabled the voice relay
+    #[serde(default)]
+    pub voice: bool,
+    /// Which messages are encrypted, so clients can't send cleartext state after a restart
+    #[serde(default)]
+    pub encryption: EncryptionMode,
+    #[serde(default)]
+    pub demo: Option<DemoInfo>,
+    /// Password clients need to join the session
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub markers: Vec<Marker>,
+    /// Demos to play after the current one
+    #[serde(default)]
+    pub queue: Vec<DemoInfo>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Encoding(serde_json::Error),
+    /// A stored session couldn't be decrypted with any of the configured keys, or no keys are configured
+    Decryption,
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "redis")]
+    Redis(redis::RedisError),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "io error: {e}"),
+            StoreError::Encoding(e) => write!(f, "invalid session data: {e}"),
+            StoreError::Decryption => {
+                write!(
+                    f,
+                    "stored session can't be decrypted with the configured keys"
+                )
+            }
+            #[cfg(feature = "sqlite")]
+            StoreError::Sqlite(e) => write!(f, "sqlite error: {e}"),
+            #[cfg(feature = "redis")]
+            StoreError::Redis(e) => write!(f, "redis error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(value: std::io::Error) -> Self {
+        StoreError::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(value: serde_json::Error) -> Self {
+        StoreError::Encoding(value)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StoreError {
+    fn from(value: rusqlite::Error) -> Self {
+        StoreError::Sqlite(value)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl From<redis::RedisError> for StoreError {
+    fn from(value: redis::RedisError) -> Self {
+        StoreError::Redis(value)
+    }
+}
+
+/// Storage for session state that should survive restarts
+pub trait SessionStore: Send + Sync {
+    fn load(&self, name: &str) -> Result<Option<SessionSnapshot>, StoreError>;
+    fn save(&self, snapshot: &SessionSnapshot) -> Result<(), StoreError>;
+    fn delete(&self, name: &str) -> Result<(), StoreError>;
+    /// List the names of all stored sessions
+    fn list(&self) -> Result<Vec<String>, StoreError>;
+}
+
+/// Storage for serialized sessions, the stores that write to disk or another server implement this
+/// and are turned into a [`SessionStore`] by [`SerializedStore`]
+pub trait DataStore: Send + Sync {
+    fn load(&self, name: &str) -> Result<Option<String>, StoreError>;
+    fn save(&self, name: &str, data: &str) -> Result<(), StoreError>;
+    fn delete(&self, name: &str) -> Result<(), StoreError>;
+    /// List the names of all stored sessions
+    fn list(&self) -> Result<Vec<String>, StoreError>;
+}
+
+/// Store sessions as json in a [`DataStore`]
+pub struct SerializedStore {
+    inner: Box<dyn DataStore>,
+}
+
+impl SerializedStore {
+    pub fn new(inner: Box<dyn DataStore>) -> Self {
+        SerializedStore { inner }
+    }
+}
+
+impl SessionStore for SerializedStore {
+    fn load(&self, name: &str) -> Result<Option<SessionSnapshot>, StoreError> {
+        let Some(data) = self.inner.load(name)? else {
+            return Ok(None);
+        };
+        // stored while encryption was enabled
+        if data.starts_with(ENCRYPTED_PREFIX) {
+            return Err(StoreError::Decryption);
+        }
+        Ok(Some(serde_json::from_str(&data)?))
+    }
+
+    fn save(&self, snapshot: &SessionSnapshot) -> Result<(), StoreError> {
+        self.inner
+            .save(&snapshot.name, &serde_json::to_string(snapshot)?)
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StoreError> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>, StoreError> {
+        self.inner.list()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionSnapshot>>,
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, name: &str) -> Result<Option<SessionSnapshot>, StoreError> {
+        Ok(self.sessions.lock().unwrap().get(name).cloned())
+    }
+
+    fn save(&self, snapshot: &SessionSnapshot) -> Result<(), StoreError> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(snapshot.name.clone(), snapshot.clone());
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StoreError> {
+        self.sessions.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, StoreError> {
+        Ok(self.sessions.lock().unwrap().keys().cloned().collect())
+    }
+}
+
+/// Store every session as a json file in a directory
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn open(dir: PathBuf) -> Result<Self, StoreError> {
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    /// Session names are hex encoded to get safe file names
+    fn path(&self, name: &str) -> PathBuf {
+        let encoded = encode_hex(name.as_bytes());
+        self.dir.join(format!("{encoded}.json"))
+    }
+}
+
+fn decode_file_name(name: &str) -> Option<String> {
+    let encoded = name.strip_suffix(".json")?;
+    String::from_utf8(decode_hex(encoded)?).ok()
+}
+
+impl DataStore for FileStore {
+    fn load(&self, name: &str) -> Result<Option<String>, StoreError> {
+        match fs::read_to_string(self.path(name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, name: &str, data: &str) -> Result<(), StoreError> {
+        let path = self.path(name);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StoreError> {
+        match fs::remove_file(self.path(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, StoreError> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            if let Some(name) = entry?.file_name().to_str().and_then(decode_file_name) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    pub fn open(path: PathBuf) -> Result<Self, StoreError> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (name TEXT PRIMARY KEY, data TEXT NOT NULL)",
+            (),
+        )?;
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl DataStore for SqliteStore {
+    fn load(&self, name: &str) -> Result<Option<String>, StoreError> {
+        use rusqlite::OptionalExtension;
+
+        let connection = self.connection.lock().unwrap();
+        Ok(connection
+            .query_row("SELECT data FROM sessions WHERE name = ?1", [name], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+
+    fn save(&self, name: &str, data: &str) -> Result<(), StoreError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO sessions (name, data) VALUES (?1, ?2) ON CONFLICT(name) DO UPDATE SET data = excluded.data",
+            (name, data),
+        )?;
+        Ok(())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StoreError> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM sessions WHERE name = ?1", [name])?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT name FROM sessions")?;
+        let names = statement
+            .query_map((), |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(names)
+    }
+}
+
+/// Store all sessions as json in a single redis hash
+///
+/// Like the other stores it's only read when the server starts and written by a single server, every server
+/// needs its own database.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    client: redis::Client,
+    /// Opened on first use and after the connection failed
+    connection: Mutex<Option<redis::Connection>>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    /// Name of the hash the sessions are stored in
+    const KEY: &'static str = "sync:sessions";
+    /// Timeout for connecting and for every command, so an unreachable server doesn't hold up the queued writes
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    pub fn open(url: &str) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        let connection = Self::connect(&client)?;
+        Ok(RedisStore {
+            client,
+            connection: Mutex::new(Some(connection)),
+        })
+    }
+
+    fn query<T: redis::FromRedisValue>(&self, command: &redis::Cmd) -> Result<T, StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let active = match connection.as_mut() {
+            Some(active) => active,
+            None => connection.insert(Self::connect(&self.client)?),
+        };
+        let result = command.query(active);
+        if result
+            .as_ref()
+            .is_err_and(|e| e.is_connection_dropped() || e.is_io_error())
+        {
+            *connection = None;
+        }
+        Ok(result?)
+    }
+
+    fn connect(client: &redis::Client) -> Result<redis::Connection, StoreError> {
+        let connection = client.get_connection_with_timeout(Self::TIMEOUT)?;
+        connection.set_read_timeout(Some(Self::TIMEOUT))?;
+        connection.set_write_timeout(Some(Self::TIMEOUT))?;
+        Ok(connection)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl DataStore for RedisStore {
+    fn load(&self, name: &str) -> Result<Option<String>, StoreError> {
+        self.query(redis::cmd("HGET").arg(Self::KEY).arg(name))
+    }
+
+    fn save(&self, name: &str, data: &str) -> Result<(), StoreError> {
+        self.query(redis::cmd("HSET").arg(Self::KEY).arg(name).arg(data))
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StoreError> {
+        self.query(redis::cmd("HDEL").arg(Self::KEY).arg(name))
+    }
+
+    fn list(&self) -> Result<Vec<String>, StoreError> {
+        self.query(redis::cmd("HKEYS").arg(Self::KEY))
+    }
+}
+
+enum StoreWrite {
+    Save(Box<SessionSnapshot>),
+    Delete(String),
+}
+
+/// Write to a store from a dedicated thread, so slow storage never blocks the server while it holds a session
+///
+/// Writes are applied in the order they are queued.
+pub struct StoreWriter {
+    tx: mpsc::Sender<StoreWrite>,
+}
+
+impl StoreWriter {
+    pub fn spawn(store: Arc<dyn SessionStore>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("session-store".into())
+            .spawn(move || {
+                // stops once the server is dropped
+                for write in rx {
+                    match write {
+                        StoreWrite::Save(snapshot) => {
+                            if let Err(error) = store.save(&snapshot) {
+                                error!(session = snapshot.name, %error, "failed to persist session");
+                            }
+                        }
+                        StoreWrite::Delete(name) => {
+                            if let Err(error) = store.delete(&name) {
+                                error!(session = name, %error, "failed to delete stored session");
+                            }
+                        }
+                    }
+                }
+            })
+            .expect("failed to start the session store thread");
+        StoreWriter { tx }
+    }
+
+    pub fn save(&self, snapshot: SessionSnapshot) {
+        let _ = self.tx.send(StoreWrite::Save(Box::new(snapshot)));
+    }
+
+    pub fn delete(&self, name: &str) {
+        let _ = self.tx.send(StoreWrite::Delete(name.to_string()));
+    }
+}
+
+/// Keys for encrypting stored sessions
+///
+/// The first key encrypts, the other keys are only used to decrypt sessions stored before the key was rotated.
+#[derive(Clone)]
+pub struct StoreKeys(Vec<Key>);
+
+impl Debug for StoreKeys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StoreKeys({} keys)", self.0.len())
+    }
+}
+
+/// Comma separated list of hex encoded 32 byte keys, the first key is the current key
+impl FromStr for StoreKeys {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split(',')
+            .map(|key| {
+                let bytes = decode_hex(key.trim()).ok_or(())?;
+                let bytes: [u8; 32] = bytes.try_into().map_err(|_| ())?;
+                Ok(Key::from(bytes))
+            })
+            .collect::<Result<Vec<_>, ()>>()?;
+        Ok(StoreKeys(keys))
+    }
+}
+
+/// Prefix of encrypted sessions, followed by the hex encoded nonce and ciphertext
+const ENCRYPTED_PREFIX: &str = "enc:";
+
+impl StoreKeys {
+    fn encrypt(&self, data: &str) -> String {
+        let mut nonce = Nonce::default();
+        rand::thread_rng().fill(nonce.as_mut_slice());
+        let ciphertext = ChaCha20Poly1305::new(&self.0[0])
+            .encrypt(&nonce, data.as_bytes())
+            .expect("encrypting in memory can't fail");
+        format!(
+            "{ENCRYPTED_PREFIX}{}{}",
+            encode_hex(&nonce),
+            encode_hex(&ciphertext)
+        )
+    }
+
+    /// Decrypt a stored session, also returns whether it has to be stored again with the current key
+    fn decrypt(&self, stored: &str) -> Result<(String, bool), StoreError> {
+        // sessions stored before encryption was enabled
+        let Some(encoded) = stored.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok((stored.to_string(), true));
+        };
+        let data = decode_hex(encoded).ok_or(StoreError::Decryption)?;
+        if data.len() < 12 {
+            return Err(StoreError::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(12);
+        self.0
+            .iter()
+            .enumerate()
+            .find_map(|(index, key)| {
+                let plain = ChaCha20Poly1305::new(key)
+                    .decrypt(Nonce::from_slice(nonce), ciphertext)
+                    .ok()?;
+                Some((String::from_utf8(plain).ok()?, index > 0))
+            })
+            .ok_or(StoreError::Decryption)
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(encoded: &str) -> Option<Vec<u8>> {
+    (0..encoded.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(encoded.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Encrypt the serialized sessions saved in another store
+///
+/// Sessions stored without encryption or with a rotated key are encrypted with the current key when they are loaded.
+pub struct EncryptedStore {
+    inner: Box<dyn DataStore>,
+    keys: StoreKeys,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Box<dyn DataStore>, keys: StoreKeys) -> Self {
+        EncryptedStore { inner, keys }
+    }
+}
+
+impl DataStore for EncryptedStore {
+    fn load(&self, name: &str) -> Result<Option<String>, StoreError> {
+        let Some(stored) = self.inner.load(name)? else {
+            return Ok(None);
+        };
+        let (data, outdated) = self.keys.decrypt(&stored)?;
+        if outdated {
+            self.save(name, &data)?;
+        }
+        Ok(Some(data))
+    }
+
+    fn save(&self, name: &str, data: &str) -> Result<(), StoreError> {
+        self.inner.save(name, &self.keys.encrypt(data))
+    }
+
+    fn delete(&self, name: &str) -> Result<(), StoreError> {
+        self.inner.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>, StoreError> {
+        self.inner.list()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum StoreConfig {
+    #[default]
+    Memory,
+    File(PathBuf),
+    #[cfg(feature = "sqlite")]
+    Sqlite(PathBuf),
+    /// Connection url of the redis server
+    #[cfg(feature = "redis")]
+    Redis(String),
+}
+
+impl StoreConfig {
+    /// Open the store, encrypting the stored sessions if keys are given
+    pub fn open(&self, keys: Option<&StoreKeys>) -> Result<Box<dyn SessionStore>, StoreError> {
+        let store: Box<dyn DataStore> = match self {
+            StoreConfig::Memory => return Ok(Box::new(MemoryStore::default())),
+            StoreConfig::File(dir) => Box::new(FileStore::open(dir.clone())?),
+            #[cfg(feature = "sqlite")]
+            StoreConfig::Sqlite(path) => Box::new(SqliteStore::open(path.clone())?),
+            #[cfg(feature = "redis")]
+            StoreConfig::Redis(url) => Box::new(RedisStore::open(url)?),
+        };
+        let store = match keys {
+            Some(keys) => Box::new(EncryptedStore::new(store, keys.clone())),
+            None => store,
+        };
+        Ok(Box::new(SerializedStore::new(store)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Data(Mutex<HashMap<String, String>>);
+
+    impl DataStore for Arc<Data> {
+        fn load(&self, name: &str) -> Result<Option<String>, StoreError> {
+            Ok(self.0.lock().unwrap().get(name).cloned())
+        }
+
+        fn save(&self, name: &str, data: &str) -> Result<(), StoreError> {
+            self.0.lock().unwrap().insert(name.into(), data.into());
+            Ok(())
+        }
+
+        fn delete(&self, name: &str) -> Result<(), StoreError> {
+            self.0.lock().unwrap().remove(name);
+            Ok(())
+        }
+
+        fn list(&self) -> Result<Vec<String>, StoreError> {
+            Ok(self.0.lock().unwrap().keys().cloned().collect())
+        }
+    }
+
+    const KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const OTHER_KEY: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+    fn snapshot() -> SessionSnapshot {
+        serde_json::from_value(serde_json::json!({
+            "name": "session",
+            "owner_token": "owner-token",
+            "tick": 10,
+            "playing": false,
+            "scheduled_start": null,
+            "password": "secret",
+            "title": "grand final"
+        }))
+        .unwrap()
+    }
+
+    fn store(data: &Arc<Data>, keys: Option<&str>) -> SerializedStore {
+        let data: Box<dyn DataStore> = Box::new(data.clone());
+        SerializedStore::new(match keys {
+            Some(keys) => Box::new(EncryptedStore::new(data, keys.parse().unwrap())),
+            None => data,
+        })
+    }
+
+    fn stored(data: &Data) -> String {
+        data.0.lock().unwrap()["session"].clone()
+    }
+
+    #[test]
+    fn whole_sessions_are_encrypted() {
+        let data = Arc::default();
+        let store = store(&data, Some(KEY));
+        store.save(&snapshot()).unwrap();
+        let stored = stored(&data);
+        assert!(stored.starts_with(ENCRYPTED_PREFIX));
+        for secret in ["owner-token", "secret", "grand final"] {
+            assert!(!stored.contains(secret));
+        }
+        assert_eq!(store.load("session").unwrap(), Some(snapshot()));
+    }
+
+    #[test]
+    fn plaintext_and_rotated_sessions_are_encrypted_again() {
+        let data = Arc::default();
+        store(&data, None).save(&snapshot()).unwrap();
+        assert!(!stored(&data).starts_with(ENCRYPTED_PREFIX));
+
+        let loaded = store(&data, Some(OTHER_KEY)).load("session").unwrap();
+        assert_eq!(loaded, Some(snapshot()));
+        let encrypted = stored(&data);
+        assert!(encrypted.starts_with(ENCRYPTED_PREFIX));
+
+        let rotated = store(&data, Some(&format!("{KEY},{OTHER_KEY}")));
+        assert_eq!(rotated.load("session").unwrap(), Some(snapshot()));
+        assert_ne!(stored(&data), encrypted);
+        assert_eq!(
+            store(&data, Some(KEY)).load("session").unwrap(),
+            Some(snapshot())
+        );
+    }
+
+    #[test]
+    fn sessions_need_the_right_key() {
+        let data = Arc::default();
+        store(&data, Some(KEY)).save(&snapshot()).unwrap();
+        assert!(matches!(
+            store(&data, Some(OTHER_KEY)).load("session"),
+            Err(StoreError::Decryption)
+        ));
+        assert!(matches!(
+            store(&data, None).load("session"),
+            Err(StoreError::Decryption)
+        ));
+    }
+}
@@ -0,0 +1,24 @@
+//! Spawn tasks with a name, so they can be told apart in tokio-console
+
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+/// Spawn a task, named when built with the `console` feature and `--cfg tokio_unstable`
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("failed to spawn task")
+    }
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
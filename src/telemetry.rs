@@ -0,0 +1,121 @@
+//! Metrics are recorded with the `metrics` facade, the exporter is chosen at startup
+//!
+//! When embedding the server any `metrics` recorder can be installed instead.
+
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use std::fmt::{Display, Formatter};
+#[cfg(feature = "prometheus")]
+use std::net::SocketAddr;
+
+pub const CONNECTIONS: &str = "sync_connections_total";
+pub const REFUSED_CONNECTIONS: &str = "sync_refused_connections_total";
+pub const PEERS: &str = "sync_peers";
+pub const SESSIONS: &str = "sync_sessions";
+pub const COMMANDS: &str = "sync_commands_total";
+pub const COMMAND_DURATION: &str = "sync_command_duration_seconds";
+pub const RATE_LIMITED: &str = "sync_rate_limited_total";
+pub const DECODE_ERRORS: &str = "sync_decode_errors_total";
+pub const BROADCASTS: &str = "sync_broadcasts_total";
+pub const SUPPRESSED_BROADCASTS: &str = "sync_suppressed_broadcasts_total";
+pub const DROPPED_FRAMES: &str = "sync_dropped_frames_total";
+pub const QUEUED_FRAMES: &str = "sync_queued_frames";
+pub const QUEUE_HIGH_WATER: &str = "sync_queue_high_water";
+pub const PANICS: &str = "sync_panics_total";
+pub const LOCK_CONTENDED: &str = "sync_lock_contended_total";
+pub const LOCK_WAIT: &str = "sync_lock_wait_seconds";
+pub const EGRESS_SHED: &str = "sync_egress_shed_total";
+pub const EGRESS_SHED_BYTES: &str = "sync_egress_shed_bytes_total";
+
+#[derive(Debug, Clone, Default)]
+pub enum MetricsConfig {
+    #[default]
+    None,
+    /// Serve prometheus metrics over http on the address
+    #[cfg(feature = "prometheus")]
+    Prometheus(SocketAddr),
+}
+
+#[derive(Debug)]
+pub enum MetricsError {
+    #[cfg(feature = "prometheus")]
+    Prometheus(metrics_exporter_prometheus::BuildError),
+}
+
+impl Display for MetricsError {
+    fn fmt(&self, _f: &mut Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            #[cfg(feature = "prometheus")]
+            MetricsError::Prometheus(ref e) => {
+                write!(_f, "failed to start prometheus exporter: {e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetricsError {}
+
+impl MetricsConfig {
+    /// Install the global metrics recorder, has to be called from within the tokio runtime
+    pub fn install(&self) -> Result<(), MetricsError> {
+        match self {
+            MetricsConfig::None => {}
+            #[cfg(feature = "prometheus")]
+            MetricsConfig::Prometheus(address) => {
+                metrics_exporter_prometheus::PrometheusBuilder::new()
+                    .with_http_listener(*address)
+                    .install()
+                    .map_err(MetricsError::Prometheus)?;
+                describe();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Register descriptions for all metrics with the installed recorder
+pub fn describe() {
+    describe_counter!(CONNECTIONS, "Accepted websocket connections");
+    describe_counter!(
+        REFUSED_CONNECTIONS,
+        "Connections refused during the handshake"
+    );
+    describe_gauge!(PEERS, "Currently connected peers");
+    describe_gauge!(SESSIONS, "Currently active sessions");
+    describe_counter!(COMMANDS, "Received commands by type");
+    describe_histogram!(
+        COMMAND_DURATION,
+        Unit::Seconds,
+        "Time spent handling a command"
+    );
+    describe_counter!(RATE_LIMITED, "Rate limited commands by type");
+    describe_counter!(DECODE_ERRORS, "Messages that couldn't be decoded");
+    describe_counter!(BROADCASTS, "Messages send to all clients of a session");
+    describe_counter!(
+        SUPPRESSED_BROADCASTS,
+        "Owner commands that weren't send to the clients because they didn't change the session"
+    );
+    describe_counter!(
+        DROPPED_FRAMES,
+        "Frames that couldn't be send to a peer by reason"
+    );
+    describe_gauge!(QUEUED_FRAMES, "Frames waiting to be send to peers");
+    describe_gauge!(
+        QUEUE_HIGH_WATER,
+        "Highest number of frames queued for a single peer"
+    );
+    describe_counter!(LOCK_CONTENDED, "Lookups that had to wait for a lock by map");
+    describe_histogram!(
+        LOCK_WAIT,
+        Unit::Seconds,
+        "Time spend waiting for a contended lock by map"
+    );
+    describe_counter!(
+        EGRESS_SHED,
+        "Frames that weren't send because an outbound budget was exhausted, by budget and priority"
+    );
+    describe_counter!(
+        EGRESS_SHED_BYTES,
+        Unit::Bytes,
+        "Bytes that weren't send because an outbound budget was exhausted, by budget"
+    );
+}
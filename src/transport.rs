@@ -0,0 +1,158 @@
+use crate::msgpack;
+use crate::relay::RELAY_TAG;
+use futures_util::{SinkExt, StreamExt};
+use std::future::Future;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Close code for peers that violated the protocol
+pub const CLOSE_POLICY: u16 = 1008;
+/// Close code for peers speaking an unsupported protocol version
+pub const CLOSE_PROTOCOL: u16 = 1002;
+/// Close code for peers that neither send messages nor answered pings for too long
+pub const CLOSE_IDLE: u16 = 4000;
+
+/// Websocket subprotocol for clients that exchange messages as MessagePack instead of json
+pub const MSGPACK_PROTOCOL: &str = "sync-msgpack";
+
+/// How messages are encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// Messages are send as binary MessagePack frames, relay frames are left unchanged
+    MsgPack,
+}
+
+impl Encoding {
+    /// Pick the encoding from the comma separated subprotocols requested by the client
+    pub fn negotiate(protocols: Option<&str>) -> Self {
+        let requested = protocols
+            .into_iter()
+            .flat_map(|protocols| protocols.split(','))
+            .any(|protocol| protocol.trim() == MSGPACK_PROTOCOL);
+        if requested {
+            Encoding::MsgPack
+        } else {
+            Encoding::Json
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MsgPack => "msgpack",
+        }
+    }
+}
+
+/// A message exchanged with a peer, independent of the transport used
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Websocket ping send by the server to measure the round trip time
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close {
+        code: u16,
+        reason: String,
+    },
+}
+
+impl Frame {
+    /// Size of the frame payload in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Frame::Text(text) => text.len(),
+            Frame::Binary(data) | Frame::Ping(data) | Frame::Pong(data) => data.len(),
+            Frame::Close { reason, .. } => reason.len() + 2,
+        }
+    }
+}
+
+/// A connection to a peer that can send and receive frames
+pub trait PeerTransport: Send {
+    type Error: std::error::Error;
+
+    /// Receive the next frame from the peer, `None` if the connection is closed
+    ///
+    /// This has to be cancel safe, it is raced against outgoing frames
+    fn recv(&mut self) -> impl Future<Output = Option<Frame>> + Send;
+
+    fn send(&mut self, frame: Frame) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+pub struct WebSocketTransport<S> {
+    stream: WebSocketStream<S>,
+    encoding: Encoding,
+}
+
+impl<S> WebSocketTransport<S> {
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        WebSocketTransport {
+            stream,
+            encoding: Encoding::Json,
+        }
+    }
+
+    pub fn with_encoding(self, encoding: Encoding) -> Self {
+        WebSocketTransport { encoding, ..self }
+    }
+
+    /// Turn MessagePack frames into the json text frames the server handles
+    ///
+    /// Frames that can't be decoded are passed on as binary frames, which the server rejects as invalid relay frames
+    fn decode(&self, data: Vec<u8>) -> Frame {
+        if self.encoding != Encoding::MsgPack || data.first() == Some(&RELAY_TAG) {
+            return Frame::Binary(data);
+        }
+        match msgpack::decode(&data) {
+            Some(value) => Frame::Text(value.to_string()),
+            None => Frame::Binary(data),
+        }
+    }
+
+    fn encode(&self, text: String) -> Message {
+        if self.encoding == Encoding::MsgPack {
+            if let Ok(value) = serde_json::from_str(&text) {
+                return Message::Binary(msgpack::encode(&value));
+            }
+        }
+        Message::Text(text)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> PeerTransport for WebSocketTransport<S> {
+    type Error = WsError;
+
+    async fn recv(&mut self) -> Option<Frame> {
+        loop {
+            match self.stream.next().await? {
+                Ok(Message::Text(text)) => return Some(Frame::Text(text)),
+                Ok(Message::Binary(data)) => return Some(self.decode(data)),
+                Ok(Message::Pong(data)) => return Some(Frame::Pong(data)),
+                Ok(Message::Close(_)) | Err(_) => return None,
+                // pings are answered by tungstenite
+                Ok(Message::Ping(_) | Message::Frame(_)) => continue,
+            }
+        }
+    }
+
+    async fn send(&mut self, frame: Frame) -> Result<(), Self::Error> {
+        let message = match frame {
+            Frame::Text(text) => self.encode(text),
+            Frame::Binary(data) => Message::Binary(data),
+            Frame::Ping(data) => Message::Ping(data),
+            Frame::Pong(data) => Message::Pong(data),
+            Frame::Close { code, reason } => Message::Close(Some(CloseFrame {
+                code: CloseCode::from(code),
+                reason: reason.into(),
+            })),
+        };
+        self.stream.send(message).await
+    }
+}